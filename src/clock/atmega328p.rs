@@ -0,0 +1,73 @@
+use super::{Clock, Clocks};
+use crate::global::global;
+use crate::reg::{Field, Reg};
+use crate::{HalError, Result};
+
+const CLKPR: Reg<u8> = unsafe { Reg::new(0x61) }; // Clock Prescale Register
+
+// CLKPR bits
+const CLKPCE: u8 = 1 << 7;             // Clock Prescaler Change Enable
+const CLKPS: Field = Field::new(0, 4); // Clock Prescaler Select: division factor 2^CLKPS
+
+// The oscillator is selected by the fuses, software only tells the HAL which one was programmed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClockSource {
+    ExternalCrystal(u32), // Crystal or resonator of the given frequency (16 MHz on an Arduino Uno)
+    ExternalClock(u32),   // Clock signal fed to XTAL1
+    InternalRc,           // Calibrated 8 MHz RC oscillator
+    InternalRc128k,       // 128 kHz RC oscillator
+}
+
+impl ClockSource {
+    fn frequency(self) -> u32 {
+        match self {
+            ClockSource::ExternalCrystal(hz) | ClockSource::ExternalClock(hz) => hz,
+            ClockSource::InternalRc => 8_000_000,
+            ClockSource::InternalRc128k => 128_000,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClockConfig {
+    pub source: ClockSource,
+    pub prescaler: u16, // System clock prescaler: 1, 2, 4, ... 256
+}
+
+impl Default for ClockConfig {
+    // Arduino Uno: 16 MHz crystal, no division
+    fn default() -> Self {
+        ClockConfig { source: ClockSource::ExternalCrystal(16_000_000), prescaler: 1 }
+    }
+}
+
+const RESET_CLOCKS: Clocks = Clocks::single(16_000_000);
+
+global! { static CLOCKS: Clocks = RESET_CLOCKS; }
+
+pub struct Atmega328p;
+
+impl Clock for Atmega328p {
+    type Config = ClockConfig;
+
+    // Programs the system clock prescaler, the source frequency must be 20 MHz at most
+    fn clock_init(config: ClockConfig) -> Result<Clocks> {
+        let source = config.source.frequency();
+        if source == 0 || source > 20_000_000 || !config.prescaler.is_power_of_two() || config.prescaler > 256 {
+            return Err(HalError::InvalidClock);
+        }
+
+        // CLKPS can only be written within 4 cycles after setting CLKPCE alone,
+        // interrupts must therefore be disabled by the caller around this function
+        CLKPR.write(CLKPCE);
+        CLKPR.write(CLKPS.val(config.prescaler.trailing_zeros()));
+
+        let clocks = Clocks::single(source / config.prescaler as u32);
+        CLOCKS.with(|current| current.set(clocks));
+        Ok(clocks)
+    }
+
+    fn clocks() -> Clocks {
+        CLOCKS.with(|current| current.get())
+    }
+}
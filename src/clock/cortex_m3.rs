@@ -0,0 +1,180 @@
+use super::{Clock, Clocks};
+use crate::global::global;
+use crate::rcc::RCC_BASE;
+use crate::reg::{Field, Reg};
+use crate::timeout::wait_until;
+use crate::{HalError, Result};
+
+const RCC_CR: Reg<u32> = unsafe { Reg::new(RCC_BASE) };          // Clock Control Register
+const RCC_CFGR: Reg<u32> = unsafe { Reg::new(RCC_BASE + 0x04) }; // Clock Configuration Register
+const FLASH_ACR: Reg<u32> = unsafe { Reg::new(0x4002_2000) };    // Flash Access Control Register
+
+// CR bits
+const HSEON: u32 = 1 << 16;  // HSE oscillator enable
+const HSERDY: u32 = 1 << 17; // HSE oscillator ready
+const PLLON: u32 = 1 << 24;  // PLL enable
+const PLLRDY: u32 = 1 << 25; // PLL ready
+
+// CFGR fields
+const SW: Field = Field::new(0, 2);      // System clock switch
+const SWS: Field = Field::new(2, 2);     // System clock switch status
+const HPRE: Field = Field::new(4, 4);    // AHB prescaler
+const PPRE1: Field = Field::new(8, 3);   // APB1 prescaler
+const PPRE2: Field = Field::new(11, 3);  // APB2 prescaler
+const PLLSRC: u32 = 1 << 16;             // PLL fed by HSE (cleared for HSI/2)
+const PLLXTPRE: u32 = 1 << 17;           // HSE divided by 2 before the PLL
+const PLLMUL: Field = Field::new(18, 4); // PLL multiplication factor minus 2
+
+// SW/SWS values
+const SW_HSI: u32 = 0b00;
+const SW_HSE: u32 = 0b01;
+const SW_PLL: u32 = 0b10;
+
+// ACR bits
+const LATENCY: Field = Field::new(0, 3); // Flash wait states
+const PRFTBE: u32 = 1 << 4;              // Prefetch buffer enable
+
+const HSI_HZ: u32 = 8_000_000;
+const SYSCLK_MAX_HZ: u32 = 72_000_000;
+const PCLK1_MAX_HZ: u32 = 36_000_000;
+
+// Source of the system clock
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SysClkSource {
+    Hsi,                                // Internal 8 MHz RC oscillator
+    Hse(u32),                           // External crystal or clock of the given frequency (4 to 16 MHz)
+    Pll { source: PllSource, mul: u8 }, // PLL output, multiplication factor 2 to 16
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PllSource {
+    HsiDiv2,      // 4 MHz
+    Hse(u32),     // External crystal or clock of the given frequency
+    HseDiv2(u32), // Same, divided by 2
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClockConfig {
+    pub source: SysClkSource,
+    pub ahb_prescaler: u16, // 1, 2, 4, 8, 16, 64, 128, 256 or 512
+    pub apb1_prescaler: u8, // 1, 2, 4, 8 or 16, APB1 must not exceed 36 MHz
+    pub apb2_prescaler: u8, // 1, 2, 4, 8 or 16
+}
+
+impl Default for ClockConfig {
+    // Reset configuration: HSI, no bus division
+    fn default() -> Self {
+        ClockConfig { source: SysClkSource::Hsi, ahb_prescaler: 1, apb1_prescaler: 1, apb2_prescaler: 1 }
+    }
+}
+
+const RESET_CLOCKS: Clocks = Clocks::single(HSI_HZ);
+
+global! { static CLOCKS: Clocks = RESET_CLOCKS; }
+
+// HPRE encoding of an AHB division factor
+fn hpre_bits(prescaler: u16) -> Result<u32> {
+    match prescaler {
+        1 => Ok(0b0000),
+        2 | 4 | 8 | 16 => Ok(0b1000 | (prescaler.trailing_zeros() - 1)),
+        64 | 128 | 256 | 512 => Ok(0b1100 | (prescaler.trailing_zeros() - 6)),
+        _ => Err(HalError::InvalidClock),
+    }
+}
+
+// PPRE1/PPRE2 encoding of an APB division factor
+fn ppre_bits(prescaler: u8) -> Result<u32> {
+    match prescaler {
+        1 => Ok(0b000),
+        2 | 4 | 8 | 16 => Ok(0b100 | (prescaler.trailing_zeros() - 1)),
+        _ => Err(HalError::InvalidClock),
+    }
+}
+
+fn check_hse(hz: u32) -> Result<u32> {
+    if (4_000_000..=16_000_000).contains(&hz) {
+        Ok(hz)
+    } else {
+        Err(HalError::InvalidClock)
+    }
+}
+
+pub struct CortexM3;
+
+impl Clock for CortexM3 {
+    type Config = ClockConfig;
+
+    // Starts the oscillators, programs the prescalers and the flash wait states, then switches the system clock
+    // Meant to be called once at startup, while the core still runs from HSI
+    fn clock_init(config: ClockConfig) -> Result<Clocks> {
+        let hpre = hpre_bits(config.ahb_prescaler)?;
+        let ppre1 = ppre_bits(config.apb1_prescaler)?;
+        let ppre2 = ppre_bits(config.apb2_prescaler)?;
+
+        // Frequency of the selected source, PLL configuration bits and whether HSE must be started
+        let (sysclk, pll_bits, use_hse) = match config.source {
+            SysClkSource::Hsi => (HSI_HZ, None, false),
+            SysClkSource::Hse(hz) => (check_hse(hz)?, None, true),
+            SysClkSource::Pll { source, mul } => {
+                if !(2..=16).contains(&mul) {
+                    return Err(HalError::InvalidClock);
+                }
+                let (input, src_bits, use_hse) = match source {
+                    PllSource::HsiDiv2 => (HSI_HZ / 2, 0, false),
+                    PllSource::Hse(hz) => (check_hse(hz)?, PLLSRC, true),
+                    PllSource::HseDiv2(hz) => (check_hse(hz)? / 2, PLLSRC | PLLXTPRE, true),
+                };
+                (input * mul as u32, Some(src_bits | PLLMUL.val::<u32>(mul as u32 - 2)), use_hse)
+            }
+        };
+
+        let hclk = sysclk / config.ahb_prescaler as u32;
+        let clocks = Clocks {
+            sysclk,
+            hclk,
+            pclk1: hclk / config.apb1_prescaler as u32,
+            pclk2: hclk / config.apb2_prescaler as u32,
+        };
+        if sysclk > SYSCLK_MAX_HZ || clocks.pclk1 > PCLK1_MAX_HZ {
+            return Err(HalError::InvalidClock);
+        }
+
+        if use_hse {
+            RCC_CR.set_bits(HSEON);
+            wait_until(|| RCC_CR.is_set(HSERDY))?;
+        }
+
+        // The flash needs one wait state above 24 MHz and two above 48 MHz, set before speeding up
+        let wait_states = match sysclk {
+            0..=24_000_000 => 0,
+            24_000_001..=48_000_000 => 1,
+            _ => 2,
+        };
+        FLASH_ACR.write(PRFTBE | LATENCY.val::<u32>(wait_states));
+
+        RCC_CFGR.write_field(HPRE, hpre);
+        RCC_CFGR.write_field(PPRE1, ppre1);
+        RCC_CFGR.write_field(PPRE2, ppre2);
+
+        let sw = match pll_bits {
+            Some(bits) => {
+                RCC_CFGR.modify(|cfgr| (cfgr & !(PLLSRC | PLLXTPRE | PLLMUL.mask())) | bits);
+                RCC_CR.set_bits(PLLON);
+                wait_until(|| RCC_CR.is_set(PLLRDY))?;
+                SW_PLL
+            }
+            None if use_hse => SW_HSE,
+            None => SW_HSI,
+        };
+
+        RCC_CFGR.write_field(SW, sw);
+        wait_until(|| RCC_CFGR.read_field(SWS) == sw)?; // The switch is effective once SWS reports the new source
+
+        CLOCKS.with(|current| current.set(clocks));
+        Ok(clocks)
+    }
+
+    fn clocks() -> Clocks {
+        CLOCKS.with(|current| current.get())
+    }
+}
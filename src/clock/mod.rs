@@ -0,0 +1,50 @@
+pub mod atmega328p;
+pub mod cortex_m3;
+
+use crate::Result;
+
+// Frequencies (in Hz) of the clock tree once configured, consumed by the USART, SPI and I2C init functions
+// The ATmega328p has a single clock domain: all four frequencies are the same
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Clocks {
+    pub sysclk: u32, // Core clock
+    pub hclk: u32,   // AHB bus clock
+    pub pclk1: u32,  // APB1 peripheral clock (USART2, I2C1 on the Cortex-M3)
+    pub pclk2: u32,  // APB2 peripheral clock (SPI1 on the Cortex-M3)
+}
+
+impl Clocks {
+    // Clock tree where every bus runs at the core frequency
+    pub const fn single(hz: u32) -> Self {
+        Clocks { sysclk: hz, hclk: hz, pclk1: hz, pclk2: hz }
+    }
+}
+
+// Clock configuration trait, `Config` describes the oscillators and prescalers of the chip
+pub trait Clock {
+    type Config;
+    fn clock_init(config: Self::Config) -> Result<Clocks>;
+    fn clocks() -> Clocks; // Frequencies set by the last `clock_init`, or the reset values
+}
+
+#[cfg(feature = "atmega328p")]
+pub type ActiveClock = atmega328p::Atmega328p;
+
+#[cfg(feature = "cortex_m3")]
+pub type ActiveClock = cortex_m3::CortexM3;
+
+#[cfg(feature = "atmega328p")]
+pub use atmega328p::ClockConfig;
+
+#[cfg(feature = "cortex_m3")]
+pub use cortex_m3::ClockConfig;
+
+#[cfg(any(feature = "atmega328p", feature = "cortex_m3"))]
+pub fn clock_init(config: ClockConfig) -> Result<Clocks> {
+    ActiveClock::clock_init(config)
+}
+
+#[cfg(any(feature = "atmega328p", feature = "cortex_m3"))]
+pub fn clocks() -> Clocks {
+    ActiveClock::clocks()
+}
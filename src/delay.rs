@@ -0,0 +1,115 @@
+// Busy-wait delays derived from the system clock
+// `Delay` counts CPU cycles, so it is only as accurate as the `Clocks` it was created with and is stretched
+// by interrupts. It implements the embedded-hal delay traits for the drivers that need one.
+
+use crate::clock::Clocks;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Delay {
+    sysclk: u32,
+}
+
+impl Delay {
+    pub fn new(clocks: &Clocks) -> Self {
+        Delay { sysclk: clocks.sysclk }
+    }
+
+    pub fn delay_ns(&mut self, ns: u32) {
+        self.delay_cycles(ns as u64, 1_000_000_000);
+    }
+
+    pub fn delay_us(&mut self, us: u32) {
+        self.delay_cycles(us as u64, 1_000_000);
+    }
+
+    pub fn delay_ms(&mut self, ms: u32) {
+        self.delay_cycles(ms as u64, 1_000);
+    }
+
+    // Waits `amount` units of 1/`per_second` s, rounded up to the next cycle
+    fn delay_cycles(&mut self, amount: u64, per_second: u64) {
+        let mut cycles = (amount * self.sysclk as u64).div_ceil(per_second);
+        while cycles > 0 {
+            let chunk = cycles.min(u32::MAX as u64);
+            spin(chunk as u32);
+            cycles -= chunk;
+        }
+    }
+}
+
+#[cfg(all(feature = "cortex_m3", not(feature = "host-sim")))]
+fn spin(cycles: u32) {
+    cortex_m::asm::delay(cycles);
+}
+
+// A `nop` and the loop around it take about 4 cycles
+#[cfg(all(feature = "atmega328p", not(feature = "host-sim")))]
+fn spin(cycles: u32) {
+    for _ in 0..cycles / 4 {
+        avr_device::asm::nop();
+    }
+}
+
+// Simulated time does not pass, tests are not slowed down
+#[cfg(feature = "host-sim")]
+fn spin(_cycles: u32) {}
+
+#[cfg(not(any(feature = "atmega328p", feature = "cortex_m3", feature = "host-sim")))]
+fn spin(cycles: u32) {
+    for _ in 0..cycles {
+        core::hint::spin_loop();
+    }
+}
+
+// embedded-hal 0.2 delay traits
+impl embedded_hal::blocking::delay::DelayUs<u32> for Delay {
+    fn delay_us(&mut self, us: u32) {
+        Delay::delay_us(self, us);
+    }
+}
+
+impl embedded_hal::blocking::delay::DelayUs<u16> for Delay {
+    fn delay_us(&mut self, us: u16) {
+        Delay::delay_us(self, us as u32);
+    }
+}
+
+impl embedded_hal::blocking::delay::DelayUs<u8> for Delay {
+    fn delay_us(&mut self, us: u8) {
+        Delay::delay_us(self, us as u32);
+    }
+}
+
+impl embedded_hal::blocking::delay::DelayMs<u32> for Delay {
+    fn delay_ms(&mut self, ms: u32) {
+        Delay::delay_ms(self, ms);
+    }
+}
+
+impl embedded_hal::blocking::delay::DelayMs<u16> for Delay {
+    fn delay_ms(&mut self, ms: u16) {
+        Delay::delay_ms(self, ms as u32);
+    }
+}
+
+impl embedded_hal::blocking::delay::DelayMs<u8> for Delay {
+    fn delay_ms(&mut self, ms: u8) {
+        Delay::delay_ms(self, ms as u32);
+    }
+}
+
+// embedded-hal 1.0 delay trait
+#[cfg(feature = "embedded-hal-1")]
+impl embedded_hal_1::delay::DelayNs for Delay {
+    fn delay_ns(&mut self, ns: u32) {
+        Delay::delay_ns(self, ns);
+    }
+
+    fn delay_us(&mut self, us: u32) {
+        Delay::delay_us(self, us);
+    }
+
+    fn delay_ms(&mut self, ms: u32) {
+        Delay::delay_ms(self, ms);
+    }
+}
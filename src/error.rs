@@ -0,0 +1,91 @@
+use core::fmt;
+
+// Errors reported by every peripheral of the HAL
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HalError {
+    InvalidPin,      // The pin does not exist on the selected chip
+    InvalidBaud,     // The baud rate cannot be generated from the peripheral clock
+    InvalidClock,    // The requested bus clock is out of the range supported by the peripheral
+    InvalidConfig,   // The requested combination of settings is not supported by the peripheral
+    Nack,            // The I2C slave did not acknowledge its address or a data byte
+    ArbitrationLost, // Another I2C master took the bus
+    BusError,        // Misplaced START or STOP condition on the I2C bus
+    Overrun,         // A received byte was lost because the previous one was not read in time
+    Framing,         // The stop bit of a received frame was not found
+    Parity,          // The parity of a received frame is wrong
+    Noise,           // The samples of a received bit disagreed, the byte may be corrupted
+    Timeout,         // The peripheral did not answer in time
+    Busy,            // The shared bus is already in use, e.g. by an interrupt handler that preempted a transaction
+}
+
+pub type Result<T> = core::result::Result<T, HalError>;
+
+impl fmt::Display for HalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            HalError::InvalidPin => "invalid pin",
+            HalError::InvalidBaud => "invalid baud rate",
+            HalError::InvalidClock => "invalid clock speed",
+            HalError::InvalidConfig => "unsupported configuration",
+            HalError::Nack => "no acknowledge from the slave",
+            HalError::ArbitrationLost => "arbitration lost",
+            HalError::BusError => "bus error",
+            HalError::Overrun => "overrun",
+            HalError::Framing => "framing error",
+            HalError::Parity => "parity error",
+            HalError::Noise => "noise error",
+            HalError::Timeout => "timeout",
+            HalError::Busy => "bus busy",
+        };
+        f.write_str(message)
+    }
+}
+
+// embedded-hal 1.0 and embedded-io error kinds, so that generic drivers can react to the cause of a failure
+#[cfg(feature = "embedded-hal-1")]
+impl embedded_hal_1::digital::Error for HalError {
+    fn kind(&self) -> embedded_hal_1::digital::ErrorKind {
+        embedded_hal_1::digital::ErrorKind::Other
+    }
+}
+
+#[cfg(feature = "embedded-hal-1")]
+impl embedded_hal_1::spi::Error for HalError {
+    fn kind(&self) -> embedded_hal_1::spi::ErrorKind {
+        use embedded_hal_1::spi::ErrorKind;
+        match self {
+            HalError::Overrun => ErrorKind::Overrun,
+            HalError::Framing => ErrorKind::FrameFormat,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+#[cfg(feature = "embedded-hal-1")]
+impl embedded_hal_1::i2c::Error for HalError {
+    fn kind(&self) -> embedded_hal_1::i2c::ErrorKind {
+        use embedded_hal_1::i2c::{ErrorKind, NoAcknowledgeSource};
+        match self {
+            HalError::Nack => ErrorKind::NoAcknowledge(NoAcknowledgeSource::Unknown), // Address and data NACKs are not told apart
+            HalError::ArbitrationLost => ErrorKind::ArbitrationLoss,
+            HalError::BusError => ErrorKind::Bus,
+            HalError::Overrun => ErrorKind::Overrun,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+#[cfg(feature = "embedded-hal-1")]
+impl embedded_io::Error for HalError {
+    fn kind(&self) -> embedded_io::ErrorKind {
+        use embedded_io::ErrorKind;
+        match self {
+            HalError::InvalidPin | HalError::InvalidBaud | HalError::InvalidClock | HalError::InvalidConfig => {
+                ErrorKind::InvalidInput
+            }
+            HalError::Overrun | HalError::Framing | HalError::Parity | HalError::Noise => ErrorKind::InvalidData,
+            HalError::Timeout => ErrorKind::TimedOut,
+            _ => ErrorKind::Other,
+        }
+    }
+}
@@ -0,0 +1,46 @@
+// Driver-wide state kept outside of the peripheral registers (timeout policy, counters, ...)
+// On target a `global!` is a plain static cell: the chips are single core and the value is only used from
+// the main program. With `host-sim` it becomes thread-local, so that every test thread gets its own copy
+// just like the simulated register file.
+
+#[cfg(not(feature = "host-sim"))]
+use core::cell::Cell;
+
+#[cfg(not(feature = "host-sim"))]
+pub struct StaticCell<T>(Cell<T>);
+
+// Safety: single-core targets, a cell shared with an interrupt handler is only touched inside `interrupt::free`
+#[cfg(not(feature = "host-sim"))]
+unsafe impl<T> Sync for StaticCell<T> {}
+
+#[cfg(not(feature = "host-sim"))]
+impl<T> StaticCell<T> {
+    pub const fn new(value: T) -> Self {
+        StaticCell(Cell::new(value))
+    }
+
+    // Same signature as `LocalKey::with`, so that both flavours of `global!` are used the same way
+    pub fn with<R, F: FnOnce(&Cell<T>) -> R>(&'static self, f: F) -> R {
+        f(&self.0)
+    }
+}
+
+// Type of a `global!`, for the helpers that take one by reference
+#[cfg(feature = "host-sim")]
+pub type Global<T> = std::thread::LocalKey<core::cell::Cell<T>>;
+#[cfg(not(feature = "host-sim"))]
+pub type Global<T> = StaticCell<T>;
+
+// Declares a driver-wide value, read with `NAME.with(|cell| cell.get())` and written with `cell.set(..)`
+macro_rules! global {
+    ($vis:vis static $name:ident: $ty:ty = $init:expr;) => {
+        #[cfg(feature = "host-sim")]
+        std::thread_local! {
+            $vis static $name: core::cell::Cell<$ty> = const { core::cell::Cell::new($init) };
+        }
+        #[cfg(not(feature = "host-sim"))]
+        $vis static $name: $crate::global::StaticCell<$ty> = $crate::global::StaticCell::new($init);
+    };
+}
+
+pub(crate) use global;
@@ -1,45 +1,116 @@
-use super::{PinMode, PinValue, GPIO};
+use super::pin::PortId;
+use super::{PinMode, PinValue, Port, GPIO};
+use crate::reg::Reg;
+use crate::{HalError, Result};
 
-// Memory addresses for registers controlling the Data Direction (DDRB), Output (PORTB), and Input (PINB) of PORTB
-const DDRB: *mut u8 = 0x24 as *mut u8;
-const PORTB: *mut u8 = 0x25 as *mut u8;
-const PINB: *mut u8 = 0x23 as *mut u8;
+// Registers of an I/O port: Input (PINx), Data Direction (DDRx) and Output (PORTx), located at consecutive addresses
+struct PortRegs {
+    pin: Reg<u8>,
+    ddr: Reg<u8>,
+    port: Reg<u8>,
+    pin_count: u8,
+}
+
+impl PortRegs {
+    const fn new(base: usize, pin_count: u8) -> Self {
+        unsafe { PortRegs { pin: Reg::new(base), ddr: Reg::new(base + 1), port: Reg::new(base + 2), pin_count } }
+    }
+}
+
+const PORTB: PortRegs = PortRegs::new(0x23, 8); // PB6/PB7 are shared with the crystal
+const PORTC: PortRegs = PortRegs::new(0x26, 7); // PC6 is the RESET pin, there is no PC7
+const PORTD: PortRegs = PortRegs::new(0x29, 8);
 
 pub struct Atmega328p;
 
-// We will indicate volatile access for memory-mapped registers to ensure they are accessed in a way that prevents 
-// compiler optimizations from reordering or omitting them
+// Ports of the typestate `Pin` API, e.g. `Pin<PortB, 5, Unconfigured>`
+pub struct PortB;
+pub struct PortC;
+pub struct PortD;
+
+impl PortId for PortB {
+    type Gpio = Atmega328p;
+    const PORT: Port = Port::B;
+    const PIN_COUNT: u8 = PORTB.pin_count;
+}
+
+impl PortId for PortC {
+    type Gpio = Atmega328p;
+    const PORT: Port = Port::C;
+    const PIN_COUNT: u8 = PORTC.pin_count;
+}
+
+impl PortId for PortD {
+    type Gpio = Atmega328p;
+    const PORT: Port = Port::D;
+    const PIN_COUNT: u8 = PORTD.pin_count;
+}
+
+// Every register access goes through `Reg`, which is volatile to ensure the compiler
+// does not reorder or omit accesses to memory-mapped registers
 
 impl GPIO for Atmega328p{
 
-    // Sets the pin as input or output by respectively clearing or setting the corresponding bit in DDRB
-    fn configure_pin(pin: u8, mode: PinMode) {
-        unsafe {
-            match mode {
-                PinMode::Input => core::ptr::write_volatile(DDRB, core::ptr::read_volatile(DDRB) & !(1 << pin)),
-                PinMode::Output => core::ptr::write_volatile(DDRB, core::ptr::read_volatile(DDRB) | (1 << pin)),
+    // Sets the pin as input or output by respectively clearing or setting the corresponding bit in DDRx
+    // For an input, the PORTx bit enables (1) or disables (0) the internal pull-up
+    fn configure_pin(port: Port, pin: u8, mode: PinMode) -> Result<()> {
+        let (regs, mask) = locate(port, pin)?;
+        match mode {
+            PinMode::Input => {
+                regs.ddr.clear_bits(mask);
+                regs.port.clear_bits(mask);
+            }
+            PinMode::InputPullUp => {
+                regs.ddr.clear_bits(mask);
+                regs.port.set_bits(mask);
             }
+            PinMode::Output => regs.ddr.set_bits(mask),
         }
+        Ok(())
     }
 
-    // Controls the output state (HIGH/LOW) of a pin by setting or clearing the corresponding bit in PORTB
-    fn write_pin(pin: u8, value: PinValue) {
-        unsafe {
-            match value {
-                PinValue::High => core::ptr::write_volatile(PORTB, core::ptr::read_volatile(PORTB) | (1 << pin)),
-                PinValue::Low => core::ptr::write_volatile(PORTB, core::ptr::read_volatile(PORTB) & !(1 << pin)),
-            }
+    // Controls the output state (HIGH/LOW) of a pin by setting or clearing the corresponding bit in PORTx
+    fn write_pin(port: Port, pin: u8, value: PinValue) -> Result<()> {
+        let (regs, mask) = locate(port, pin)?;
+        match value {
+            PinValue::High => regs.port.set_bits(mask),
+            PinValue::Low => regs.port.clear_bits(mask),
         }
+        Ok(())
     }
 
-    // Reads the state (HIGH/LOW) of a pin by checking its bit in PINB
-    fn read_pin(pin: u8) -> PinValue {
-        unsafe {
-            if core::ptr::read_volatile(PINB) & (1 << pin) != 0 {
-                PinValue::High
-            } else {
-                PinValue::Low
-            }
+    // Reads the state (HIGH/LOW) of a pin by checking its bit in PINx
+    fn read_pin(port: Port, pin: u8) -> Result<PinValue> {
+        let (regs, mask) = locate(port, pin)?;
+        if regs.pin.is_set(mask) {
+            Ok(PinValue::High)
+        } else {
+            Ok(PinValue::Low)
+        }
+    }
+
+    // Reads the level an output pin is driven to from its bit in PORTx
+    fn read_output(port: Port, pin: u8) -> Result<PinValue> {
+        let (regs, mask) = locate(port, pin)?;
+        if regs.port.is_set(mask) {
+            Ok(PinValue::High)
+        } else {
+            Ok(PinValue::Low)
         }
     }
 }
+
+// Register block of the port and bit of the pin, rejecting ports and pins the chip does not have
+fn locate(port: Port, pin: u8) -> Result<(PortRegs, u8)> {
+    let regs = match port {
+        Port::B => PORTB,
+        Port::C => PORTC,
+        Port::D => PORTD,
+        Port::A | Port::E => return Err(HalError::InvalidPin),
+    };
+    if pin < regs.pin_count {
+        Ok((regs, 1 << pin))
+    } else {
+        Err(HalError::InvalidPin)
+    }
+}
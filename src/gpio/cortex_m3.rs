@@ -1,69 +1,155 @@
-use super::{PinMode, PinValue, GPIO};
+use super::pin::PortId;
+use super::{PinMode, PinValue, Port, GPIO};
+use crate::rcc::{self, Peripheral};
+use crate::reg::{Field, Reg};
+use crate::{HalError, Result};
 
-const GPIOA_MODER: *mut u32 = 0x48000000u32 as *mut u32; // Mode register
-const GPIOA_ODR: *mut u32 = 0x48000014u32 as *mut u32;   // Output data register
-const GPIOA_IDR: *mut u32 = 0x48000010u32 as *mut u32;   // Input data register
+const GPIO_BASE: usize = 0x4800_0000; // Base address of GPIOA, the next ports follow every 0x400 bytes
+
+// Registers of a GPIO port
+struct PortRegs {
+    moder: Reg<u32>, // Mode register
+    idr: Reg<u32>,   // Input data register
+    odr: Reg<u32>,   // Output data register
+    pupdr: Reg<u32>, // Pull-up/pull-down register
+    afr: [Reg<u32>; 2], // Alternate function registers, low (pins 0-7) and high (pins 8-15)
+    clock: Peripheral,
+}
+
+impl PortRegs {
+    const fn new(index: usize, clock: Peripheral) -> Self {
+        let base = GPIO_BASE + index * 0x400;
+        unsafe {
+            PortRegs {
+                moder: Reg::new(base),
+                idr: Reg::new(base + 0x10),
+                odr: Reg::new(base + 0x14),
+                pupdr: Reg::new(base + 0x0C),
+                afr: [Reg::new(base + 0x20), Reg::new(base + 0x24)],
+                clock,
+            }
+        }
+    }
+}
+
+const GPIOA: PortRegs = PortRegs::new(0, Peripheral::GpioA);
+const GPIOB: PortRegs = PortRegs::new(1, Peripheral::GpioB);
+const GPIOC: PortRegs = PortRegs::new(2, Peripheral::GpioC);
+const GPIOD: PortRegs = PortRegs::new(3, Peripheral::GpioD);
+const GPIOE: PortRegs = PortRegs::new(4, Peripheral::GpioE);
+
+// MODER field values
+const MODE_INPUT: u32 = 0b00;
+const MODE_OUTPUT: u32 = 0b01;
+const MODE_ALTERNATE: u32 = 0b10;
+
+// PUPDR field values
+const PULL_NONE: u32 = 0b00;
+const PULL_UP: u32 = 0b01;
+
+const PIN_COUNT: u8 = 16; // Every port has 16 pins
 
 pub struct CortexM3;
 
-// We will indicate volatile access for memory-mapped registers to ensure they are accessed in a way that prevents 
-// compiler optimizations from reordering or omitting them
+// Ports of the typestate `Pin` API, e.g. `Pin<GpioA, 5, Unconfigured>`
+pub struct GpioA;
+pub struct GpioB;
+pub struct GpioC;
+pub struct GpioD;
+pub struct GpioE;
+
+macro_rules! port_id {
+    ($($name:ident => $port:expr),*) => {
+        $(
+            impl PortId for $name {
+                type Gpio = CortexM3;
+                const PORT: Port = $port;
+                const PIN_COUNT: u8 = PIN_COUNT;
+            }
+        )*
+    };
+}
+
+port_id!(GpioA => Port::A, GpioB => Port::B, GpioC => Port::C, GpioD => Port::D, GpioE => Port::E);
+
+// Every register access goes through `Reg`, which is volatile to ensure the compiler
+// does not reorder or omit accesses to memory-mapped registers
+
+// Each pin uses 2 bits in the MODER and PUPDR registers
+fn pin_field(pin: u8) -> Field {
+    Field::new(pin * 2, 2)
+}
+
+// Register block of the port, rejecting pins past the port width
+fn locate(port: Port, pin: u8) -> Result<PortRegs> {
+    if pin >= PIN_COUNT {
+        return Err(HalError::InvalidPin);
+    }
+    Ok(match port {
+        Port::A => GPIOA,
+        Port::B => GPIOB,
+        Port::C => GPIOC,
+        Port::D => GPIOD,
+        Port::E => GPIOE,
+    })
+}
+
+// Hands the pin over to a peripheral (e.g. USART TX/RX), `function` is the AFx number of the chip's pin table
+pub(crate) fn configure_alternate(port: Port, pin: u8, function: u8) -> Result<()> {
+    let regs = locate(port, pin)?;
+    rcc::enable(regs.clock);
+    regs.afr[pin as usize / 8].write_field(Field::new((pin % 8) * 4, 4), function as u32);
+    regs.moder.write_field(pin_field(pin), MODE_ALTERNATE);
+    Ok(())
+}
 
 impl GPIO for CortexM3 {
 
-    // Sets the pin as input or output by respectively clearing or setting the corresponding bit in MODER
-    fn configure_pin(pin: u8, mode: PinMode) {
-        unsafe {
-            let shift = pin * 2; // Each pin uses 2 bits in the MODER register
-            match mode {
-                PinMode::Input => {
-                    // Clears the 2 bits for the pin to set it as input (00)
-                    core::ptr::write_volatile(
-                        GPIOA_MODER,
-                        core::ptr::read_volatile(GPIOA_MODER) & !(0b11 << shift),
-                    );
-                }
-                PinMode::Output => {
-                    // Sets the 2 bits for the pin to configure it as output (01)
-                    core::ptr::write_volatile(
-                        GPIOA_MODER,
-                        (core::ptr::read_volatile(GPIOA_MODER) & !(0b11 << shift)) | (0b01 << shift),
-                    );
-                }
+    // Sets the pin as input (00) or output (01) by writing its 2 bits in MODER, inputs also get their pull-up in PUPDR
+    fn configure_pin(port: Port, pin: u8, mode: PinMode) -> Result<()> {
+        let regs = locate(port, pin)?;
+        rcc::enable(regs.clock); // The port ignores every access until its clock runs
+        match mode {
+            PinMode::Input => {
+                regs.pupdr.write_field(pin_field(pin), PULL_NONE);
+                regs.moder.write_field(pin_field(pin), MODE_INPUT);
             }
+            PinMode::InputPullUp => {
+                regs.pupdr.write_field(pin_field(pin), PULL_UP);
+                regs.moder.write_field(pin_field(pin), MODE_INPUT);
+            }
+            PinMode::Output => regs.moder.write_field(pin_field(pin), MODE_OUTPUT),
         }
+        Ok(())
     }
 
-    // Writes a HIGH or LOW value to the specified pin by modifying the GPIOA_ODR register
-    fn write_pin(pin: u8, value: PinValue) {
-        unsafe {
-            match value {
-                PinValue::High => {
-                    // Sets the corresponding bit in the ODR register to set the pin to HIGH
-                    core::ptr::write_volatile(
-                        GPIOA_ODR,
-                        core::ptr::read_volatile(GPIOA_ODR) | (1 << pin),
-                    );
-                }
-                PinValue::Low => {
-                    // Clears the corresponding bit in the ODR register to set the pin to LOW
-                    core::ptr::write_volatile(
-                        GPIOA_ODR,
-                        core::ptr::read_volatile(GPIOA_ODR) & !(1 << pin),
-                    );
-                }
-            }
+    // Writes a HIGH or LOW value to the specified pin by setting or clearing its bit in ODR
+    fn write_pin(port: Port, pin: u8, value: PinValue) -> Result<()> {
+        let regs = locate(port, pin)?;
+        match value {
+            PinValue::High => regs.odr.set_bits(1 << pin),
+            PinValue::Low => regs.odr.clear_bits(1 << pin),
         }
+        Ok(())
     }
 
-    // Reads the state (HIGH or LOW) of the specified pin from the GPIOA_IDR register
-    fn read_pin(pin: u8) -> PinValue {
-        unsafe {
-            if core::ptr::read_volatile(GPIOA_IDR) & (1 << pin) != 0 {
-                PinValue::High
-            } else {
-                PinValue::Low
-            }
+    // Reads the state (HIGH or LOW) of the specified pin from the IDR register
+    fn read_pin(port: Port, pin: u8) -> Result<PinValue> {
+        let regs = locate(port, pin)?;
+        if regs.idr.is_set(1 << pin) {
+            Ok(PinValue::High)
+        } else {
+            Ok(PinValue::Low)
+        }
+    }
+
+    // Reads the level an output pin is driven to from the ODR register
+    fn read_output(port: Port, pin: u8) -> Result<PinValue> {
+        let regs = locate(port, pin)?;
+        if regs.odr.is_set(1 << pin) {
+            Ok(PinValue::High)
+        } else {
+            Ok(PinValue::Low)
         }
     }
 }
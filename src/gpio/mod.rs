@@ -1,8 +1,12 @@
 pub mod atmega328p;
 pub mod cortex_m3;
+pub mod pin;
+
+use crate::Result;
 
 pub enum PinMode {
-    Input,
+    Input,       // Floating input, the internal pull-up is disabled
+    InputPullUp, // Input with the internal pull-up resistor enabled
     Output,
 }
 
@@ -11,10 +15,23 @@ pub enum PinValue {
     Low,
 }
 
+// GPIO ports: PORTB, PORTC and PORTD on the Atmega328p, GPIOA to GPIOE on the Cortex-M3
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Port {
+    A,
+    B,
+    C,
+    D,
+    E,
+}
+
+// Pins are addressed by port and bit number (e.g. `Port::B, 5` for PB5)
+// Ports and pins that do not exist on the chip are rejected with `HalError::InvalidPin`
 pub trait GPIO {
-    fn configure_pin(pin: u8, mode: PinMode);
-    fn read_pin(pin: u8) -> PinValue;
-    fn write_pin(pin: u8, value: PinValue);
+    fn configure_pin(port: Port, pin: u8, mode: PinMode) -> Result<()>;
+    fn read_pin(port: Port, pin: u8) -> Result<PinValue>;
+    fn write_pin(port: Port, pin: u8, value: PinValue) -> Result<()>;
+    fn read_output(port: Port, pin: u8) -> Result<PinValue>; // Level the pin is driven to, read back from the output latch
 }
 
 #[cfg(feature = "atmega328p")]
@@ -23,15 +40,22 @@ pub type ActiveGPIO = atmega328p::Atmega328p;
 #[cfg(feature = "cortex_m3")]
 pub type ActiveGPIO = cortex_m3::CortexM3;
 
-pub fn configure_pin(pin: u8, mode: PinMode) {
-    ActiveGPIO::configure_pin(pin, mode);
+#[cfg(any(feature = "atmega328p", feature = "cortex_m3"))]
+pub fn configure_pin(port: Port, pin: u8, mode: PinMode) -> Result<()> {
+    ActiveGPIO::configure_pin(port, pin, mode)
 }
 
-pub fn read_pin(pin: u8) -> PinValue {
-    ActiveGPIO::read_pin(pin)
+#[cfg(any(feature = "atmega328p", feature = "cortex_m3"))]
+pub fn read_pin(port: Port, pin: u8) -> Result<PinValue> {
+    ActiveGPIO::read_pin(port, pin)
 }
 
-pub fn write_pin(pin: u8, value: PinValue) {
-    ActiveGPIO::write_pin(pin, value);
+#[cfg(any(feature = "atmega328p", feature = "cortex_m3"))]
+pub fn write_pin(port: Port, pin: u8, value: PinValue) -> Result<()> {
+    ActiveGPIO::write_pin(port, pin, value)
 }
 
+#[cfg(any(feature = "atmega328p", feature = "cortex_m3"))]
+pub fn read_output(port: Port, pin: u8) -> Result<PinValue> {
+    ActiveGPIO::read_output(port, pin)
+}
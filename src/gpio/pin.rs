@@ -0,0 +1,207 @@
+// Typestate pin API on top of the `GPIO` trait implementations
+// A `Pin<PORT, N, MODE>` carries its port, its number and its mode in its type: the pin number is checked
+// against the port width at compile time, only inputs can be read and only outputs can be written.
+// Changing the mode consumes the pin and returns it with its new type:
+//     let led = Pin::<PortB, 5, Unconfigured>::new().into_push_pull_output()?;
+
+use core::marker::PhantomData;
+
+use super::{PinMode, PinValue, Port, GPIO};
+use crate::{HalError, Result};
+
+// Port of a chip, implemented by the marker types of each backend (`PortB`, `GpioA`, ...)
+pub trait PortId {
+    type Gpio: GPIO;
+    const PORT: Port;
+    const PIN_COUNT: u8;
+}
+
+// Modes
+pub struct Unconfigured;           // Reset state, neither readable nor writable
+pub struct Input<PULL>(PhantomData<PULL>);
+pub struct Output<OTYPE>(PhantomData<OTYPE>);
+
+// Input pull configurations
+pub struct Floating;
+pub struct PullUp;
+
+// Output types
+pub struct PushPull;
+
+pub struct Pin<P: PortId, const N: u8, MODE> {
+    _marker: PhantomData<(P, MODE)>,
+}
+
+impl<P: PortId, const N: u8, MODE> Pin<P, N, MODE> {
+    // Evaluated when a pin type is first used, a pin past the port width fails to compile
+    const VALID: () = assert!(N < P::PIN_COUNT, "pin number past the port width");
+
+    fn with_mode<NEW>() -> Pin<P, N, NEW> {
+        Pin { _marker: PhantomData }
+    }
+
+    pub fn into_floating_input(self) -> Result<Pin<P, N, Input<Floating>>> {
+        P::Gpio::configure_pin(P::PORT, N, PinMode::Input)?;
+        Ok(Self::with_mode())
+    }
+
+    pub fn into_pull_up_input(self) -> Result<Pin<P, N, Input<PullUp>>> {
+        P::Gpio::configure_pin(P::PORT, N, PinMode::InputPullUp)?;
+        Ok(Self::with_mode())
+    }
+
+    pub fn into_push_pull_output(self) -> Result<Pin<P, N, Output<PushPull>>> {
+        P::Gpio::configure_pin(P::PORT, N, PinMode::Output)?;
+        Ok(Self::with_mode())
+    }
+
+    pub fn port(&self) -> Port {
+        P::PORT
+    }
+
+    pub fn number(&self) -> u8 {
+        N
+    }
+}
+
+impl<P: PortId, const N: u8> Pin<P, N, Unconfigured> {
+    // Handle on a pin left in its reset state, the typestate only tracks this handle:
+    // two handles on the same pin can still change its mode behind each other's back
+    pub fn new() -> Self {
+        let () = Self::VALID;
+        Self::with_mode()
+    }
+}
+
+impl<P: PortId, const N: u8> Default for Pin<P, N, Unconfigured> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<P: PortId, const N: u8, PULL> Pin<P, N, Input<PULL>> {
+    pub fn is_high(&self) -> Result<bool> {
+        Ok(matches!(P::Gpio::read_pin(P::PORT, N)?, PinValue::High))
+    }
+
+    pub fn is_low(&self) -> Result<bool> {
+        Ok(!self.is_high()?)
+    }
+}
+
+impl<P: PortId, const N: u8, OTYPE> Pin<P, N, Output<OTYPE>> {
+    pub fn set_high(&mut self) -> Result<()> {
+        P::Gpio::write_pin(P::PORT, N, PinValue::High)
+    }
+
+    pub fn set_low(&mut self) -> Result<()> {
+        P::Gpio::write_pin(P::PORT, N, PinValue::Low)
+    }
+
+    pub fn set_value(&mut self, value: PinValue) -> Result<()> {
+        P::Gpio::write_pin(P::PORT, N, value)
+    }
+
+    // Level the pin is driven to (output latch), not the level sampled on the pin
+    pub fn is_set_high(&self) -> Result<bool> {
+        Ok(matches!(P::Gpio::read_output(P::PORT, N)?, PinValue::High))
+    }
+
+    pub fn is_set_low(&self) -> Result<bool> {
+        Ok(!self.is_set_high()?)
+    }
+
+    pub fn toggle(&mut self) -> Result<()> {
+        if self.is_set_high()? {
+            self.set_low()
+        } else {
+            self.set_high()
+        }
+    }
+}
+
+// embedded-hal 0.2 digital traits
+impl<P: PortId, const N: u8, OTYPE> embedded_hal::digital::v2::OutputPin for Pin<P, N, Output<OTYPE>> {
+    type Error = HalError;
+
+    fn set_low(&mut self) -> Result<()> {
+        Pin::set_low(self)
+    }
+
+    fn set_high(&mut self) -> Result<()> {
+        Pin::set_high(self)
+    }
+}
+
+impl<P: PortId, const N: u8, OTYPE> embedded_hal::digital::v2::StatefulOutputPin for Pin<P, N, Output<OTYPE>> {
+    fn is_set_high(&self) -> Result<bool> {
+        Pin::is_set_high(self)
+    }
+
+    fn is_set_low(&self) -> Result<bool> {
+        Pin::is_set_low(self)
+    }
+}
+
+impl<P: PortId, const N: u8, OTYPE> embedded_hal::digital::v2::ToggleableOutputPin for Pin<P, N, Output<OTYPE>> {
+    type Error = HalError;
+
+    fn toggle(&mut self) -> Result<()> {
+        Pin::toggle(self)
+    }
+}
+
+impl<P: PortId, const N: u8, PULL> embedded_hal::digital::v2::InputPin for Pin<P, N, Input<PULL>> {
+    type Error = HalError;
+
+    fn is_high(&self) -> Result<bool> {
+        Pin::is_high(self)
+    }
+
+    fn is_low(&self) -> Result<bool> {
+        Pin::is_low(self)
+    }
+}
+
+// embedded-hal 1.0 digital traits
+#[cfg(feature = "embedded-hal-1")]
+impl<P: PortId, const N: u8, MODE> embedded_hal_1::digital::ErrorType for Pin<P, N, MODE> {
+    type Error = HalError;
+}
+
+#[cfg(feature = "embedded-hal-1")]
+impl<P: PortId, const N: u8, OTYPE> embedded_hal_1::digital::OutputPin for Pin<P, N, Output<OTYPE>> {
+    fn set_low(&mut self) -> Result<()> {
+        Pin::set_low(self)
+    }
+
+    fn set_high(&mut self) -> Result<()> {
+        Pin::set_high(self)
+    }
+}
+
+#[cfg(feature = "embedded-hal-1")]
+impl<P: PortId, const N: u8, OTYPE> embedded_hal_1::digital::StatefulOutputPin for Pin<P, N, Output<OTYPE>> {
+    fn is_set_high(&mut self) -> Result<bool> {
+        Pin::is_set_high(self)
+    }
+
+    fn is_set_low(&mut self) -> Result<bool> {
+        Pin::is_set_low(self)
+    }
+
+    fn toggle(&mut self) -> Result<()> {
+        Pin::toggle(self)
+    }
+}
+
+#[cfg(feature = "embedded-hal-1")]
+impl<P: PortId, const N: u8, PULL> embedded_hal_1::digital::InputPin for Pin<P, N, Input<PULL>> {
+    fn is_high(&mut self) -> Result<bool> {
+        Pin::is_high(self)
+    }
+
+    fn is_low(&mut self) -> Result<bool> {
+        Pin::is_low(self)
+    }
+}
@@ -1,10 +1,19 @@
-use super::I2C;
+use core::task::Waker;
 
-const TWBR: *mut u8 = 0xB8 as *mut u8;  // TWI Bit Rate Register
-const TWSR: *mut u8 = 0xB9 as *mut u8;  // TWI Status Register
-const TWAR: *mut u8 = 0xBA as *mut u8;  // TWI (Slave) Address Register
-const TWDR: *mut u8 = 0xBB as *mut u8;  // TWI Data Register
-const TWCR: *mut u8 = 0xBC as *mut u8;  // TWI Control Register
+use super::{read_run_len, AsOperation, AsyncI2C, Operation, I2C};
+use crate::clock::Clocks;
+use crate::global::global;
+use crate::interrupt;
+use crate::reg::{Field, Reg};
+use crate::timeout::wait_until;
+use crate::{HalError, Result};
+
+const TWBR: Reg<u8> = unsafe { Reg::new(0xB8) };  // TWI Bit Rate Register
+const TWSR: Reg<u8> = unsafe { Reg::new(0xB9) };  // TWI Status Register
+#[allow(dead_code)] // Only needed once slave mode is supported
+const TWAR: Reg<u8> = unsafe { Reg::new(0xBA) };  // TWI (Slave) Address Register
+const TWDR: Reg<u8> = unsafe { Reg::new(0xBB) };  // TWI Data Register
+const TWCR: Reg<u8> = unsafe { Reg::new(0xBC) };  // TWI Control Register
 
 // Control Register Bits
 const TWINT: u8 = 1 << 7; // TWI Interrupt Flag
@@ -12,88 +21,242 @@ const TWSTA: u8 = 1 << 5; // TWI Start Condition Bit
 const TWSTO: u8 = 1 << 4; // TWI Stop Condition Bit
 const TWEN: u8 = 1 << 2; // TWI Enable Bit
 const TWEA: u8 = 1 << 6; // TWI Enable Acknowledge Bit
+const TWIE: u8 = 1 << 0; // TWI Interrupt Enable
+
+// Status Register Fields
+const TWPS: Field = Field::new(0, 2); // TWI Prescaler Bits
+const TWS: Field = Field::new(3, 5);  // TWI Status Bits
+
+// Status codes, as read from TWSR with the prescaler bits masked out
+const TW_START: u8 = 0x08;         // START transmitted
+const TW_REP_START: u8 = 0x10;     // Repeated START transmitted
+const TW_MT_SLA_ACK: u8 = 0x18;    // SLA+W transmitted, ACK received
+const TW_MT_SLA_NACK: u8 = 0x20;   // SLA+W transmitted, NACK received
+const TW_MT_DATA_ACK: u8 = 0x28;   // Data transmitted, ACK received
+const TW_MT_DATA_NACK: u8 = 0x30;  // Data transmitted, NACK received
+const TW_ARB_LOST: u8 = 0x38;      // Arbitration lost
+const TW_MR_SLA_ACK: u8 = 0x40;    // SLA+R transmitted, ACK received
+const TW_MR_SLA_NACK: u8 = 0x48;   // SLA+R transmitted, NACK received
+const TW_MR_DATA_ACK: u8 = 0x50;   // Data received, ACK returned
+const TW_MR_DATA_NACK: u8 = 0x58;  // Data received, NACK returned
+
+global! { static WAKER: Option<Waker> = None; }
 
 pub struct Atmega328p;
 
-impl I2C for Atmega328p {
-    fn i2c_init(clock_speed: u32) {
-        const CPU_CLOCK: u32 = 16_000_000; // CPU clock frequency
-        let prescaler: u8 = 1;             // Prescaler value (can be modified if needed)
-        // Calculate the TWPS bits based on the prescaler value
-        let twps_bits = match prescaler {
-            1 => 0b00, // TWPS bits for prescaler = 1
-            4 => 0b01, // TWPS bits for prescaler = 4
-            16 => 0b10, // TWPS bits for prescaler = 16
-            64 => 0b11, // TWPS bits for prescaler = 64
-            _ => 0b00, // Default to prescaler = 1
-        };
-
-        unsafe {
-            // Set the prescaler in TWSR
-            *TWSR = (*TWSR & !0b11) | twps_bits;
-    
-            // Calculate and set bit rate
-            let bit_rate = ((CPU_CLOCK / clock_speed) - 16) / (2 * prescaler) as u32;
-            if bit_rate < 10 {
-                panic!("Invalid clock_speed: Bit rate too low!");
+// Waits for the TWI to finish the current operation, then checks that it ended with the `expected` status
+fn wait_status(expected: u8) -> Result<()> {
+    wait_until(|| TWCR.is_set(TWINT))?;
+    check_status(expected)
+}
+
+fn check_status(expected: u8) -> Result<()> {
+    match TWSR.read() & TWS.val::<u8>(0x1F) {
+        status if status == expected => Ok(()),
+        TW_MT_SLA_NACK | TW_MT_DATA_NACK | TW_MR_SLA_NACK => Err(HalError::Nack),
+        TW_ARB_LOST => Err(HalError::ArbitrationLost),
+        _ => Err(HalError::BusError), // 0x00 (illegal START or STOP condition) or an unexpected state
+    }
+}
+
+// Sends the stop condition, unless the bus was lost to another master (the TWI has already released it)
+fn stop(result: Result<()>) -> Result<()> {
+    if result != Err(HalError::ArbitrationLost) {
+        TWCR.write(TWINT | TWSTO | TWEN);
+    }
+    result
+}
+
+// Sends the start condition followed by the slave address and the read/write bit
+// `condition` is TW_START, or TW_REP_START when the bus is still held by a previous transfer
+fn start(condition: u8, address_rw: u8, expected: u8) -> Result<()> {
+    TWCR.write(TWINT | TWSTA | TWEN);
+    wait_status(condition)?;
+
+    TWDR.write(address_rw);
+    TWCR.write(TWINT | TWEN); // Clears TWINT to start transmission
+    wait_status(expected)
+}
+
+// Sends data bytes, each of them must be ACKed by the slave
+fn send_bytes(data: &[u8]) -> Result<()> {
+    for &byte in data {
+        TWDR.write(byte);
+        TWCR.write(TWINT | TWEN);
+        wait_status(TW_MT_DATA_ACK)?;
+    }
+    Ok(())
+}
+
+// Receives bytes, all ACKed except the last one when `nack_last` (the end of the read)
+fn receive_bytes(buffer: &mut [u8], nack_last: bool) -> Result<()> {
+    let buffer_len = buffer.len();
+    for (i, byte) in buffer.iter_mut().enumerate() {
+        if nack_last && i == buffer_len - 1 {
+            TWCR.write(TWINT | TWEN); // NACK for the last byte
+            wait_status(TW_MR_DATA_NACK)?;
+        } else {
+            TWCR.write(TWINT | TWEN | TWEA); // ACK for all other bytes
+            wait_status(TW_MR_DATA_ACK)?;
+        }
+        *byte = TWDR.read();
+    }
+    Ok(())
+}
+
+// Master transmitter: start condition, address with the write bit and data bytes
+fn transmit(address: u8, data: &[u8]) -> Result<()> {
+    start(TW_START, (address << 1) & 0xFE, TW_MT_SLA_ACK)?;
+    send_bytes(data)
+}
+
+// Master receiver: start condition, address with the read bit, then every byte is ACKed except the last one
+fn receive(condition: u8, address: u8, buffer: &mut [u8]) -> Result<()> {
+    start(condition, (address << 1) | 1, TW_MR_SLA_ACK)?;
+    receive_bytes(buffer, true)
+}
+
+// Runs the operations of a transaction, the caller sends the final stop condition
+fn run_transaction<O: AsOperation>(address: u8, operations: &mut [O]) -> Result<()> {
+    let mut condition = TW_START;
+    let mut reading = None; // Direction of the previous operation, a change needs a new (repeated) START
+    for i in 0..operations.len() {
+        let (current, rest) = operations[i..].split_first_mut().unwrap();
+        match current.as_operation() {
+            Operation::Write(data) => {
+                if reading != Some(false) {
+                    start(condition, (address << 1) & 0xFE, TW_MT_SLA_ACK)?;
+                    condition = TW_REP_START;
+                }
+                send_bytes(data)?;
+                reading = Some(false);
+            }
+            Operation::Read(buffer) => {
+                if reading != Some(true) {
+                    start(condition, (address << 1) | 1, TW_MR_SLA_ACK)?;
+                    condition = TW_REP_START;
+                }
+                receive_bytes(buffer, read_run_len(rest) == 0)?;
+                reading = Some(true);
             }
-            *TWBR = bit_rate as u8;
-    
-            // Enable TWI
-            *TWCR = TWEN; // TWI Enable
         }
     }
+    Ok(())
+}
 
-    fn i2c_write(address: u8, data: &[u8]) {
-        unsafe {
-            // Sends start condition
-            *TWCR = TWINT | TWSTA | TWEN;  
-            while *TWCR & TWINT == 0 {}
-
-            // Sends address
-            *TWDR = (address << 1) & 0xFE; // Address and write bit
-            *TWCR = TWINT | TWEN; // Clears TWINT to start transmission
-            while *TWCR & TWINT == 0 {} 
-
-            // Writes data
-            for &byte in data {
-                *TWDR = byte;
-                *TWCR = TWINT | TWEN;
-                while *TWCR & TWINT == 0 {}
+// Writes a TWCR command, then sleeps until the TWI interrupt reports that it ended with the `expected` status
+// TWINT is kept at 0 when TWIE is set, writing a one there would start the next operation
+async fn command_async(command: u8, expected: u8) -> Result<()> {
+    TWCR.write(command);
+    interrupt::wait_until(&WAKER, || TWCR.modify(|value| (value & !TWINT) | TWIE), || TWCR.is_set(TWINT)).await;
+    check_status(expected)
+}
+
+async fn start_async(condition: u8, address_rw: u8, expected: u8) -> Result<()> {
+    command_async(TWINT | TWSTA | TWEN, condition).await?;
+    TWDR.write(address_rw);
+    command_async(TWINT | TWEN, expected).await
+}
+
+// Same sequence as `run_transaction`, sleeping between the TWI operations
+async fn run_transaction_async<O: AsOperation>(address: u8, operations: &mut [O]) -> Result<()> {
+    let mut condition = TW_START;
+    let mut reading = None;
+    for i in 0..operations.len() {
+        let (current, rest) = operations[i..].split_first_mut().unwrap();
+        match current.as_operation() {
+            Operation::Write(data) => {
+                if reading != Some(false) {
+                    start_async(condition, (address << 1) & 0xFE, TW_MT_SLA_ACK).await?;
+                    condition = TW_REP_START;
+                }
+                for &byte in data {
+                    TWDR.write(byte);
+                    command_async(TWINT | TWEN, TW_MT_DATA_ACK).await?;
+                }
+                reading = Some(false);
+            }
+            Operation::Read(buffer) => {
+                if reading != Some(true) {
+                    start_async(condition, (address << 1) | 1, TW_MR_SLA_ACK).await?;
+                    condition = TW_REP_START;
+                }
+                let last = if read_run_len(rest) == 0 { buffer.len().checked_sub(1) } else { None };
+                for (i, byte) in buffer.iter_mut().enumerate() {
+                    if Some(i) == last {
+                        command_async(TWINT | TWEN, TW_MR_DATA_NACK).await?; // NACK for the last byte
+                    } else {
+                        command_async(TWINT | TWEN | TWEA, TW_MR_DATA_ACK).await?;
+                    }
+                    *byte = TWDR.read();
+                }
+                reading = Some(true);
             }
+        }
+    }
+    Ok(())
+}
+
+impl I2C for Atmega328p {
+    fn i2c_init(clock_speed: u32, clocks: &Clocks) -> Result<()> {
+        let cpu_clock = clocks.sysclk; // CPU clock frequency
+
+        // SCL = cpu_clock / (16 + 2 * TWBR * prescaler), TWBR is kept above 10 for a stable bus
+        if clock_speed == 0 || cpu_clock / clock_speed < 16 {
+            return Err(HalError::InvalidClock);
+        }
+        let cycles = (cpu_clock / clock_speed - 16) / 2;
 
-            // Sends stop condition
-            *TWCR = TWINT | TWSTO | TWEN;
+        // Smallest prescaler (1, 4, 16 or 64, selected by TWPS = 0..3) for which TWBR fits in 8 bits
+        let twps_bits = (0..4).find(|&twps| cycles >> (2 * twps) <= 0xFF).unwrap_or(3);
+        let bit_rate = cycles >> (2 * twps_bits);
+        if !(10..=0xFF).contains(&bit_rate) {
+            return Err(HalError::InvalidClock);
         }
+
+        // Set the prescaler in TWSR
+        TWSR.write_field(TWPS, twps_bits);
+
+        // Set bit rate
+        TWBR.write(bit_rate as u8);
+
+        // Enable TWI
+        TWCR.write(TWEN); // TWI Enable
+        Ok(())
+    }
+
+    fn i2c_write(address: u8, data: &[u8]) -> Result<()> {
+        stop(transmit(address, data)) // Sends stop condition
     }
     
-    fn i2c_read(address: u8, buffer: &mut [u8]) -> u8{
-        let mut last_byte = 0;
-        let buffer_len = buffer.len();
-        unsafe {
-            // Sends start condition
-            *TWCR = TWINT | TWSTA | TWEN;
-            while *TWCR & TWINT == 0 {}
-
-            // Sends address
-            *TWDR = (address << 1) | 1; // Adress and read bit
-            *TWCR = TWINT | TWEN; // Clears TWINT to start transmission
-            while *TWCR & TWINT == 0 {}
-
-            // Reads data
-            for (i, byte) in buffer.iter_mut().enumerate() {
-                if i == buffer_len - 1{
-                    *TWCR = TWINT | TWEN; // NACK for the last byte
-                } else {
-                    *TWCR = TWINT | TWEN | TWEA; // ACK for all other bytes
-                }
-                while *TWCR & TWINT == 0 {}
-                *byte = *TWDR;
-                last_byte = *byte; // Save the last byte read
-            }
-            // Sends stop condition
-            *TWCR = TWINT | TWSTO | TWEN;
+    fn i2c_read(address: u8, buffer: &mut [u8]) -> Result<()> {
+        stop(receive(TW_START, address, buffer)) // Sends stop condition
+    }
+
+    // The read starts with a repeated START, the bus is only released after the last byte
+    fn i2c_write_read(address: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<()> {
+        stop(transmit(address, bytes).and_then(|()| receive(TW_REP_START, address, buffer)))
+    }
+
+    fn i2c_transaction<O: AsOperation>(address: u8, operations: &mut [O]) -> Result<()> {
+        if operations.is_empty() {
+            return Ok(()); // Nothing to send, the bus is not even claimed
+        }
+        stop(run_transaction(address, operations))
+    }
+}
+
+impl AsyncI2C for Atmega328p {
+    async fn i2c_transaction_async<O: AsOperation>(address: u8, operations: &mut [O]) -> Result<()> {
+        if operations.is_empty() {
+            return Ok(());
         }
-        last_byte // returns the last byte (or zero if the buffer is empty)
+        stop(run_transaction_async(address, operations).await)
+    }
+
+    // TWI vector: TWINT stays set until the next command, so TWIE is cleared (without writing TWINT back)
+    fn i2c_on_interrupt() {
+        TWCR.modify(|value| value & !(TWINT | TWIE));
+        interrupt::wake(&WAKER);
     }
 }
@@ -0,0 +1,78 @@
+// Handle on an initialised I2C peripheral, implementing the embedded-hal blocking I2C traits on top of an `I2C` implementation
+
+use core::marker::PhantomData;
+
+use embedded_hal::blocking::i2c;
+
+use super::I2C;
+#[cfg(feature = "async")]
+use super::AsyncI2C;
+use crate::clock::Clocks;
+use crate::{HalError, Result};
+
+pub struct I2c<I: I2C> {
+    _i2c: PhantomData<I>,
+}
+
+impl<I: I2C> I2c<I> {
+    pub fn init(clock_speed: u32, clocks: &Clocks) -> Result<Self> {
+        I::i2c_init(clock_speed, clocks)?;
+        Ok(Self::new())
+    }
+
+    // Wraps an I2C peripheral already initialised with `i2c_init`
+    pub fn new() -> Self {
+        I2c { _i2c: PhantomData }
+    }
+}
+
+impl<I: I2C> Default for I2c<I> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<I: I2C> i2c::Read for I2c<I> {
+    type Error = HalError;
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<()> {
+        I::i2c_read(address, buffer)
+    }
+}
+
+impl<I: I2C> i2c::Write for I2c<I> {
+    type Error = HalError;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<()> {
+        I::i2c_write(address, bytes)
+    }
+}
+
+impl<I: I2C> i2c::WriteRead for I2c<I> {
+    type Error = HalError;
+
+    fn write_read(&mut self, address: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<()> {
+        I::i2c_write_read(address, bytes, buffer)
+    }
+}
+
+// embedded-hal 1.0 I2C, every operation goes through `i2c_transaction`
+#[cfg(feature = "embedded-hal-1")]
+impl<I: I2C> embedded_hal_1::i2c::ErrorType for I2c<I> {
+    type Error = HalError;
+}
+
+#[cfg(feature = "embedded-hal-1")]
+impl<I: I2C> embedded_hal_1::i2c::I2c for I2c<I> {
+    fn transaction(&mut self, address: u8, operations: &mut [embedded_hal_1::i2c::Operation<'_>]) -> Result<()> {
+        I::i2c_transaction(address, operations)
+    }
+}
+
+// embedded-hal-async I2C, sleeping in the I2C interrupt between bus events
+#[cfg(feature = "async")]
+impl<I: AsyncI2C> embedded_hal_async::i2c::I2c for I2c<I> {
+    async fn transaction(&mut self, address: u8, operations: &mut [embedded_hal_1::i2c::Operation<'_>]) -> Result<()> {
+        I::i2c_transaction_async(address, operations).await
+    }
+}
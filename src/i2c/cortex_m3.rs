@@ -1,95 +1,302 @@
-use super::I2C;
+use core::task::Waker;
 
-const I2C_CR1: *mut u32 = 0x40005400u32 as *mut u32;
-const I2C_CR2: *mut u32 = 0x40005404u32 as *mut u32;
-const I2C_DR: *mut u32 = 0x40005410u32 as *mut u32;
-const I2C_SR1: *mut u32 = 0x40005414u32 as *mut u32;
-const I2C_SR2: *mut u32 = 0x40005418u32 as *mut u32;
+use super::{read_run_len, AsOperation, AsyncI2C, Operation, I2C};
+use crate::clock::Clocks;
+use crate::global::global;
+use crate::interrupt;
+use crate::rcc::{self, Peripheral};
+use crate::reg::{Field, Reg};
+use crate::timeout::wait_for;
+use crate::{HalError, Result};
+
+const I2C_CR1: Reg<u32> = unsafe { Reg::new(0x4000_5400) };
+const I2C_CR2: Reg<u32> = unsafe { Reg::new(0x4000_5404) };
+const I2C_DR: Reg<u32> = unsafe { Reg::new(0x4000_5410) };
+const I2C_SR1: Reg<u32> = unsafe { Reg::new(0x4000_5414) };
+const I2C_SR2: Reg<u32> = unsafe { Reg::new(0x4000_5418) };
+const I2C_CCR: Reg<u32> = unsafe { Reg::new(0x4000_541C) };
+const I2C_TRISE: Reg<u32> = unsafe { Reg::new(0x4000_5420) };
 
 // Control Register Bits
 const I2C_CR1_PE: u32 = 1 << 0;    // Peripheral Enable
 const I2C_CR1_START: u32 = 1 << 8; // Start Generation
 const I2C_CR1_STOP: u32 = 1 << 9;  // Stop Generation
 const I2C_CR1_ACK: u32 = 1 << 10; // Acknowledge Enable Bit
+const I2C_CR2_FREQ: Field = Field::new(0, 6); // Peripheral clock frequency in MHz
+const I2C_CR2_ITERREN: u32 = 1 << 8;  // Error Interrupt Enable
+const I2C_CR2_ITEVTEN: u32 = 1 << 9;  // Event Interrupt Enable (SB, ADDR, BTF)
+const I2C_CR2_ITBUFEN: u32 = 1 << 10; // Buffer Interrupt Enable (TXE, RXNE)
+const I2C_CR2_IT: u32 = I2C_CR2_ITERREN | I2C_CR2_ITEVTEN | I2C_CR2_ITBUFEN;
+
+// Clock Control Register Bits
+const I2C_CCR_FS: u32 = 1 << 15;           // Fast mode (Sm mode when cleared)
+const I2C_CCR_CCR: Field = Field::new(0, 12); // SCL half period in APB1 cycles
 
 // Status Register Bits
 const I2C_SR1_SB: u32 = 1 << 0;    // Start Bit
 const I2C_SR1_ADDR: u32 = 1 << 1;  // Address Sent/Matched
 const I2C_SR1_TXE: u32 = 1 << 7;   // Transmit Data Register Empty
 const I2C_SR1_RXNE: u32 = 1 << 6;  // Receive Data Register Not Empty
+const I2C_SR1_BERR: u32 = 1 << 8;  // Bus Error
+const I2C_SR1_ARLO: u32 = 1 << 9;  // Arbitration Lost
+const I2C_SR1_AF: u32 = 1 << 10;   // Acknowledge Failure
+
+global! { static WAKER: Option<Waker> = None; }
 
 pub struct CortexM3;
 
+// Result of a wait for `flag` in SR1, available as soon as the flag or an error flag shows up
+// Error flags are cleared by writing 0 to them
+fn check_sr1(flag: u32) -> Option<Result<()>> {
+    let status = I2C_SR1.read();
+    if status & I2C_SR1_AF != 0 {
+        I2C_SR1.clear_bits(I2C_SR1_AF);
+        return Some(Err(HalError::Nack));
+    }
+    if status & I2C_SR1_ARLO != 0 {
+        I2C_SR1.clear_bits(I2C_SR1_ARLO);
+        return Some(Err(HalError::ArbitrationLost));
+    }
+    if status & I2C_SR1_BERR != 0 {
+        I2C_SR1.clear_bits(I2C_SR1_BERR);
+        return Some(Err(HalError::BusError));
+    }
+    if status & flag != 0 { Some(Ok(())) } else { None }
+}
+
+// A NACK or a timeout releases the bus with a stop condition
+fn release_on_error(result: Result<()>) -> Result<()> {
+    if let Err(HalError::Nack | HalError::Timeout) = result {
+        I2C_CR1.set_bits(I2C_CR1_STOP);
+    }
+    result
+}
+
+// Waits until `flag` is set in SR1, giving up as soon as an error flag shows up
+fn wait_sr1(flag: u32) -> Result<()> {
+    release_on_error(wait_for(|| check_sr1(flag)).and_then(|status| status))
+}
+
+// Sleeps until `flag` is set in SR1 or an error flag shows up, both raise the I2C1 interrupts
+async fn wait_sr1_async(flag: u32) -> Result<()> {
+    release_on_error(interrupt::wait_for(&WAKER, || I2C_CR2.set_bits(I2C_CR2_IT), || check_sr1(flag)).await)
+}
+
+async fn address_phase_async(address_rw: u8) -> Result<()> {
+    I2C_CR1.set_bits(I2C_CR1_START);
+    wait_sr1_async(I2C_SR1_SB).await?;
+    I2C_DR.write(address_rw as u32);
+    wait_sr1_async(I2C_SR1_ADDR).await?;
+    let _ = I2C_SR2.read(); // Clear ADDR bit by reading SR2
+    Ok(())
+}
+
+// Same sequence as `run_transaction`, sleeping until each flag is set
+async fn run_transaction_async<O: AsOperation>(address: u8, operations: &mut [O]) -> Result<()> {
+    let mut reading = None;
+    for i in 0..operations.len() {
+        let (current, rest) = operations[i..].split_first_mut().unwrap();
+        match current.as_operation() {
+            Operation::Write(data) => {
+                if reading != Some(false) {
+                    address_phase_async(address << 1).await?;
+                }
+                for &byte in data {
+                    I2C_DR.write(byte as u32);
+                    wait_sr1_async(I2C_SR1_TXE).await?;
+                }
+                reading = Some(false);
+            }
+            Operation::Read(buffer) => {
+                let mut remaining = buffer.len() + read_run_len(rest);
+                if reading != Some(true) {
+                    if remaining > 1 {
+                        I2C_CR1.set_bits(I2C_CR1_ACK);
+                    } else {
+                        I2C_CR1.clear_bits(I2C_CR1_ACK);
+                    }
+                    address_phase_async((address << 1) | 1).await?;
+                }
+                for byte in buffer.iter_mut() {
+                    if remaining == 1 {
+                        I2C_CR1.clear_bits(I2C_CR1_ACK); // NACK for the last byte of the read
+                    }
+                    wait_sr1_async(I2C_SR1_RXNE).await?;
+                    *byte = I2C_DR.read() as u8;
+                    remaining -= 1;
+                }
+                reading = Some(true);
+            }
+        }
+    }
+    Ok(())
+}
+
+// (Repeated) start condition followed by the slave address and the read/write bit
+fn address_phase(address_rw: u8) -> Result<()> {
+    // Genreates start condition
+    I2C_CR1.set_bits(I2C_CR1_START);
+    wait_sr1(I2C_SR1_SB)?;
+
+    // Sends Slave Address with the Read/Write Bit
+    I2C_DR.write(address_rw as u32);
+    wait_sr1(I2C_SR1_ADDR)?;
+    let _ = I2C_SR2.read(); // Clear ADDR bit by reading SR2
+    Ok(())
+}
+
+// Writes data bytes
+fn send_bytes(data: &[u8]) -> Result<()> {
+    for &byte in data {
+        I2C_DR.write(byte as u32);
+        wait_sr1(I2C_SR1_TXE)?;
+    }
+    Ok(())
+}
+
+// Start condition, address with the write bit and data bytes, the bus is left held
+fn transmit(address: u8, data: &[u8]) -> Result<()> {
+    address_phase(address << 1)?;
+    send_bytes(data)
+}
+
+// Runs the operations of a transaction, consecutive reads share their address phase and their ACK sequence
+fn run_transaction<O: AsOperation>(address: u8, operations: &mut [O]) -> Result<()> {
+    let mut reading = None; // Direction of the previous operation, a change needs a new (repeated) START
+    for i in 0..operations.len() {
+        let (current, rest) = operations[i..].split_first_mut().unwrap();
+        match current.as_operation() {
+            Operation::Write(data) => {
+                if reading != Some(false) {
+                    address_phase(address << 1)?;
+                }
+                send_bytes(data)?;
+                reading = Some(false);
+            }
+            Operation::Read(buffer) => {
+                let mut remaining = buffer.len() + read_run_len(rest);
+                if reading != Some(true) {
+                    // The ACK bit must be set before the slave is addressed, a single byte is NACKed right away
+                    if remaining > 1 {
+                        I2C_CR1.set_bits(I2C_CR1_ACK);
+                    } else {
+                        I2C_CR1.clear_bits(I2C_CR1_ACK);
+                    }
+                    address_phase((address << 1) | 1)?;
+                }
+                for byte in buffer.iter_mut() {
+                    if remaining == 1 {
+                        I2C_CR1.clear_bits(I2C_CR1_ACK); // NACK for the last byte of the read
+                    }
+                    wait_sr1(I2C_SR1_RXNE)?;
+                    *byte = I2C_DR.read() as u8;
+                    remaining -= 1;
+                }
+                reading = Some(true);
+            }
+        }
+    }
+    Ok(())
+}
+
 impl I2C for CortexM3 {
-    fn i2c_init(clock_speed: u32) {
-        const MAX_FREQ_MHZ: u32 = 36; // Maximum clock frequency in MHz for I2C
-        let freq = clock_speed / 1_000_000;
-    
-        if freq > MAX_FREQ_MHZ {
-            panic!("Clock speed too high for I2C peripheral!");
+    // Programs the SCL timing for `clock_speed` (up to 100 kHz in standard mode, 400 kHz in fast mode)
+    fn i2c_init(clock_speed: u32, clocks: &Clocks) -> Result<()> {
+        // FREQ holds the APB1 frequency in MHz, the peripheral needs 2 MHz at least and 4 MHz for fast mode
+        let freq = clocks.pclk1 / 1_000_000;
+        if !(2..=36).contains(&freq) || clock_speed == 0 || clock_speed > 400_000 {
+            return Err(HalError::InvalidClock);
         }
-    
-        unsafe {
-            *I2C_CR2 = freq & 0x3F; // Set frequency
-            *I2C_CR1 |= I2C_CR1_PE; // Enable I2C
+
+        // Standard mode: SCL high and low times are both CCR cycles, rise time up to 1000 ns
+        // Fast mode (duty 2:1): SCL low is 2 * CCR and high is CCR cycles, rise time up to 300 ns
+        let (mode, ccr, trise) = if clock_speed <= 100_000 {
+            (0, (clocks.pclk1 / (2 * clock_speed)).max(4), freq + 1)
+        } else if freq >= 4 {
+            (I2C_CCR_FS, (clocks.pclk1 / (3 * clock_speed)).max(1), freq * 300 / 1000 + 1)
+        } else {
+            return Err(HalError::InvalidClock);
+        };
+        if ccr > I2C_CCR_CCR.mask() {
+            return Err(HalError::InvalidClock); // SCL too slow for the 12-bit CCR field
         }
+
+        rcc::enable(Peripheral::I2c1);
+        I2C_CR1.clear_bits(I2C_CR1_PE); // The timing registers can only be written while the peripheral is disabled
+        I2C_CR2.write_field(I2C_CR2_FREQ, freq); // Set frequency
+        I2C_CCR.write(mode | I2C_CCR_CCR.val::<u32>(ccr));
+        I2C_TRISE.write(trise);
+        I2C_CR1.set_bits(I2C_CR1_PE); // Enable I2C
+        Ok(())
     }
     
 
-    fn i2c_write(address: u8, data: &[u8]) {
-        unsafe {
-            // Genreates start condition
-            *I2C_CR1 |= I2C_CR1_START;
-            while *I2C_SR1 & I2C_SR1_SB == 0 {}
-
-            // Sends Slave Address with Write Bit
-            *I2C_DR = (address << 1) as u32;
-            while *I2C_SR1 & I2C_SR1_ADDR == 0 {}
-            let _ = *I2C_SR2; // Clear ADDR bit by reading SR2
-
-            // Writes data
-            for &byte in data {
-                *I2C_DR = byte as u32;
-                while *I2C_SR1 & I2C_SR1_TXE == 0 {}
-            }
+    fn i2c_write(address: u8, data: &[u8]) -> Result<()> {
+        transmit(address, data)?;
 
-            // Stop condition
-            *I2C_CR1 |= I2C_CR1_STOP;
-        }
+        // Stop condition
+        I2C_CR1.set_bits(I2C_CR1_STOP);
+        Ok(())
     }
     
 
-    fn i2c_read(address: u8, buffer: &mut [u8]) -> u8 {
-        let mut last_byte = 0;
+    fn i2c_read(address: u8, buffer: &mut [u8]) -> Result<()> {
         let buffer_len = buffer.len();
-        unsafe {
-            // Start condition
-            *I2C_CR1 |= I2C_CR1_START;
-            while *I2C_SR1 & I2C_SR1_SB == 0 {}
-
-            // Sends Slave Address with Read Bit
-            *I2C_DR = ((address << 1) | 1) as u32;
-            while *I2C_SR1 & I2C_SR1_ADDR == 0 {}
-            let _ = *I2C_SR2;
-
-            // Reads data
-            for (i, byte) in buffer.iter_mut().enumerate() {
-                if i == buffer_len - 1 {
-                    *I2C_CR1 &= !I2C_CR1_ACK; // NACK for the last byte
-                } else {
-                    *I2C_CR1 |= I2C_CR1_ACK; // ACK for other bytes
-                }
-                while *I2C_SR1 & I2C_SR1_RXNE == 0 {}
-                *byte = *I2C_DR as u8;
-                last_byte = *byte; // Save the last byte read
+
+        // The ACK bit must be set before the slave is addressed, otherwise the first byte is already NACKed
+        if buffer_len > 1 {
+            I2C_CR1.set_bits(I2C_CR1_ACK);
+        } else {
+            I2C_CR1.clear_bits(I2C_CR1_ACK); // A single byte is NACKed right away
+        }
+
+        // Start condition, Slave Address with Read Bit
+        address_phase((address << 1) | 1)?;
+
+        // Reads data
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            if i == buffer_len - 1 && buffer_len > 1 {
+                I2C_CR1.clear_bits(I2C_CR1_ACK); // NACK for the last byte
             }
-            // Stop condition
-            *I2C_CR1 |= I2C_CR1_STOP;
+            wait_sr1(I2C_SR1_RXNE)?;
+            *byte = I2C_DR.read() as u8;
         }
-        last_byte // returns the last byte (or zero if the buffer is empty)
+        // Stop condition
+        I2C_CR1.set_bits(I2C_CR1_STOP);
+        Ok(())
+    }
+
+    // The START of the read is a repeated START since no STOP was sent after the write
+    fn i2c_write_read(address: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<()> {
+        transmit(address, bytes)?;
+        Self::i2c_read(address, buffer)
+    }
+
+    fn i2c_transaction<O: AsOperation>(address: u8, operations: &mut [O]) -> Result<()> {
+        if operations.is_empty() {
+            return Ok(()); // Nothing to send, the bus is not even claimed
+        }
+        run_transaction(address, operations)?;
+
+        // Stop condition
+        I2C_CR1.set_bits(I2C_CR1_STOP);
+        Ok(())
+    }
+}
+
+impl AsyncI2C for CortexM3 {
+    async fn i2c_transaction_async<O: AsOperation>(address: u8, operations: &mut [O]) -> Result<()> {
+        if operations.is_empty() {
+            return Ok(());
+        }
+        run_transaction_async(address, operations).await?;
+        I2C_CR1.set_bits(I2C_CR1_STOP);
+        Ok(())
+    }
+
+    // I2C1_EV and I2C1_ER vectors: masks the I2C interrupts and wakes the waiting task, which checks SR1 again
+    fn i2c_on_interrupt() {
+        I2C_CR2.clear_bits(I2C_CR2_IT);
+        interrupt::wake(&WAKER);
     }
- 
-    
-            
-    
 }
@@ -1,28 +1,110 @@
 pub mod atmega328p;
+pub mod bus;
 pub mod cortex_m3;
 
+use core::future::Future;
+
+use crate::clock::Clocks;
+use crate::Result;
+
+// One step of an `i2c_transaction`
+pub enum Operation<'a> {
+    Read(&'a mut [u8]),
+    Write(&'a [u8]),
+}
+
+// Operation types accepted by `i2c_transaction`, so that other crates' operations can be used without copying them
+pub trait AsOperation {
+    fn as_operation(&mut self) -> Operation<'_>;
+}
+
+impl AsOperation for Operation<'_> {
+    fn as_operation(&mut self) -> Operation<'_> {
+        match self {
+            Operation::Read(buffer) => Operation::Read(buffer),
+            Operation::Write(bytes) => Operation::Write(bytes),
+        }
+    }
+}
+
+// embedded-hal 1.0 operations are run as they are
+#[cfg(feature = "embedded-hal-1")]
+impl AsOperation for embedded_hal_1::i2c::Operation<'_> {
+    fn as_operation(&mut self) -> Operation<'_> {
+        match self {
+            embedded_hal_1::i2c::Operation::Read(buffer) => Operation::Read(buffer),
+            embedded_hal_1::i2c::Operation::Write(bytes) => Operation::Write(bytes),
+        }
+    }
+}
+
+// Number of bytes read by the consecutive read operations at the start of `operations`
+pub(crate) fn read_run_len<O: AsOperation>(operations: &mut [O]) -> usize {
+    operations
+        .iter_mut()
+        .map_while(|operation| match operation.as_operation() {
+            Operation::Read(buffer) => Some(buffer.len()),
+            Operation::Write(_) => None,
+        })
+        .sum()
+}
+
+// A slave that does not acknowledge its address or a byte is reported as `HalError::Nack`
+// `i2c_init` derives the SCL timing from the frequencies reported by `clocks`
+// `i2c_transaction` merges consecutive operations of the same kind, separates a write from a read with a
+// repeated START and ends with a STOP
 pub trait I2C {
-    fn i2c_init(clock_speed: u32);
-    fn i2c_write(address: u8, data: &[u8]);
-    fn i2c_read(address: u8, buffer: &mut [u8]) -> u8;
+    fn i2c_init(clock_speed: u32, clocks: &Clocks) -> Result<()>;
+    fn i2c_write(address: u8, data: &[u8]) -> Result<()>;
+    fn i2c_read(address: u8, buffer: &mut [u8]) -> Result<()>;
+    fn i2c_write_read(address: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<()>; // Write, repeated START, read
+    fn i2c_transaction<O: AsOperation>(address: u8, operations: &mut [O]) -> Result<()>;
+}
+
+// Interrupt-driven variant of `i2c_transaction`, the task sleeps until the peripheral interrupt wakes it
+// `i2c_on_interrupt` must be called from the I2C interrupt vector(s) of the chip
+pub trait AsyncI2C: I2C {
+    fn i2c_transaction_async<O: AsOperation>(address: u8, operations: &mut [O]) -> impl Future<Output = Result<()>>;
+    fn i2c_on_interrupt();
 }
 
 #[cfg(feature = "atmega328p")]
-pub type ActiveSPI = atmega328p::Atmega328p;
+pub type ActiveI2C = atmega328p::Atmega328p;
 
 #[cfg(feature = "cortex_m3")]
-pub type ActiveSPI = cortex_m3::CortexM3;
+pub type ActiveI2C = cortex_m3::CortexM3;
+
+#[cfg(any(feature = "atmega328p", feature = "cortex_m3"))]
+pub fn i2c_init(clock_speed: u32, clocks: &Clocks) -> Result<()> {
+    ActiveI2C::i2c_init(clock_speed, clocks)
+}
 
-pub fn i2c_init(clock_speed: u32) {
-    ActiveSPI::i2c_init(clock_speed);
+#[cfg(any(feature = "atmega328p", feature = "cortex_m3"))]
+pub fn i2c_write(address: u8, data: &[u8]) -> Result<()> {
+    ActiveI2C::i2c_write(address, data)
 }
 
-pub fn i2c_write(address: u8, data: &[u8]) {
-    ActiveSPI::i2c_write(address, data);
+#[cfg(any(feature = "atmega328p", feature = "cortex_m3"))]
+pub fn i2c_read(address: u8, buffer: &mut [u8]) -> Result<()> {
+    ActiveI2C::i2c_read(address, buffer)
 }
 
-pub fn i2c_read(address: u8, buffer: &mut [u8]) -> u8 {
-    ActiveSPI::i2c_read(address, buffer)
+#[cfg(any(feature = "atmega328p", feature = "cortex_m3"))]
+pub fn i2c_write_read(address: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<()> {
+    ActiveI2C::i2c_write_read(address, bytes, buffer)
 }
 
+#[cfg(any(feature = "atmega328p", feature = "cortex_m3"))]
+pub fn i2c_transaction<O: AsOperation>(address: u8, operations: &mut [O]) -> Result<()> {
+    ActiveI2C::i2c_transaction(address, operations)
+}
 
+#[cfg(any(feature = "atmega328p", feature = "cortex_m3"))]
+pub async fn i2c_transaction_async<O: AsOperation>(address: u8, operations: &mut [O]) -> Result<()> {
+    ActiveI2C::i2c_transaction_async(address, operations).await
+}
+
+#[cfg(any(feature = "atmega328p", feature = "cortex_m3"))]
+pub fn i2c_on_interrupt() {
+    ActiveI2C::i2c_on_interrupt()
+}
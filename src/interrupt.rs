@@ -0,0 +1,60 @@
+// Interrupt-driven waits of the async drivers
+// An async operation that finds its status flag cleared stores the waker of its task, enables the interrupt of
+// the flag and returns `Pending`. The interrupt handler of the peripheral (`usart_on_interrupt`, `spi_on_interrupt`,
+// `i2c_on_interrupt`, called from the chip's vector) masks the interrupt again and wakes the task, which then finds
+// the flag set. The interrupts are level-triggered: a flag raised before its interrupt was enabled fires right away.
+
+use core::future::{poll_fn, Future};
+use core::task::{Poll, Waker};
+
+use crate::global::Global;
+
+// Runs `f` with interrupts disabled, for the state shared between the drivers and the interrupt handlers
+#[cfg(all(feature = "cortex_m3", not(feature = "host-sim")))]
+pub fn free<R, F: FnOnce() -> R>(f: F) -> R {
+    cortex_m::interrupt::free(|_| f())
+}
+
+#[cfg(all(feature = "atmega328p", not(feature = "host-sim")))]
+pub fn free<R, F: FnOnce() -> R>(f: F) -> R {
+    avr_device::interrupt::free(|_| f())
+}
+
+// Simulated interrupt handlers are called by the test itself, between two polls
+#[cfg(any(feature = "host-sim", not(any(feature = "atmega328p", feature = "cortex_m3"))))]
+pub fn free<R, F: FnOnce() -> R>(f: F) -> R {
+    f()
+}
+
+// Wakes the task waiting on `slot`, if any
+pub(crate) fn wake(slot: &'static Global<Option<Waker>>) {
+    if let Some(waker) = free(|| slot.with(|waker| waker.take())) {
+        waker.wake();
+    }
+}
+
+// Polls `ready` until it returns a value, `listen` enables the interrupt that signals a change
+pub(crate) fn wait_for<T, L: FnMut(), F: FnMut() -> Option<T>>(
+    slot: &'static Global<Option<Waker>>,
+    mut listen: L,
+    mut ready: F,
+) -> impl Future<Output = T> {
+    poll_fn(move |cx| match ready() {
+        Some(value) => Poll::Ready(value),
+        None => {
+            free(|| {
+                slot.with(|waker| waker.set(Some(cx.waker().clone())));
+                listen();
+            });
+            Poll::Pending
+        }
+    })
+}
+
+pub(crate) fn wait_until<L: FnMut(), F: FnMut() -> bool>(
+    slot: &'static Global<Option<Waker>>,
+    listen: L,
+    mut condition: F,
+) -> impl Future<Output = ()> {
+    wait_for(slot, listen, move || if condition() { Some(()) } else { None })
+}
@@ -1,13 +1,22 @@
-#![no_std]
-use core::panic::PanicInfo;
+#![cfg_attr(not(feature = "host-sim"), no_std)]
 
-#[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
-    loop {}
-}
+pub mod error;
+mod global;
+pub mod mmio;
+pub mod reg;
+#[cfg(feature = "host-sim")]
+pub mod sim;
 
+pub mod clock;
+pub mod delay;
+pub mod rcc;
 pub mod gpio;
 pub mod usart;
 pub mod spi;
 pub mod i2c;
+pub mod interrupt;
+pub mod panic;
+pub mod shell;
+pub mod timeout;
 
+pub use error::{HalError, Result};
@@ -1,75 +1,58 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(feature = "host-sim"), no_std)]
+#![cfg_attr(not(feature = "host-sim"), no_main)]
 
-#[cfg(feature = "cortex_m3")]
+#[cfg(all(feature = "cortex_m3", not(feature = "host-sim")))]
 use cortex_m_rt::entry;
-#[cfg(feature = "cortex_m3")]
+#[cfg(all(feature = "cortex_m3", not(feature = "host-sim")))]
 use cortex_m::asm;
 
-#[cfg(feature = "atmega328p")]
+#[cfg(all(feature = "atmega328p", not(feature = "host-sim")))]
 use avr_device::asm::nop;
 
-use hal_project::gpio::{configure_pin, read_pin, write_pin, PinMode, PinValue};
-use hal_project::usart::{usart_init, usart_write, usart_read};
-use hal_project::spi::{spi_init_master, spi_init_slave, spi_write, spi_read, spi_transfer};
+#[cfg(not(feature = "host-sim"))]
+use hal_project::clock::{clock_init, clocks, ClockConfig, Clocks};
+#[cfg(not(feature = "host-sim"))]
+use hal_project::gpio::{configure_pin, read_pin, write_pin, PinMode, PinValue, Port};
+#[cfg(not(feature = "host-sim"))]
+use hal_project::usart::{usart_init, usart_write, usart_read, UsartConfig};
+#[cfg(not(feature = "host-sim"))]
+use hal_project::usart_println;
+#[cfg(not(feature = "host-sim"))]
+use hal_project::spi::{spi_init_master, spi_init_slave, spi_write, spi_read, spi_transfer, SpiConfig};
+#[cfg(not(feature = "host-sim"))]
 use hal_project::i2c::{i2c_init, i2c_write, i2c_read};
+#[cfg(not(feature = "host-sim"))]
+use hal_project::Result;
+
+// With the host simulator there is no firmware to run: the drivers are exercised through `cargo test`
+#[cfg(feature = "host-sim")]
+fn main() {}
 
 // Entry point is conditional
-#[cfg(feature = "cortex_m3")]
+#[cfg(all(feature = "cortex_m3", not(feature = "host-sim")))]
 #[entry]
 fn main() -> ! {
     unified_main()
 }
 
-#[cfg(feature = "atmega328p")]
+#[cfg(all(feature = "atmega328p", not(feature = "host-sim")))]
 #[no_mangle]
 pub extern "C" fn main() -> ! {
     unified_main()
 }
 
 // Shared main logic
+#[cfg(not(feature = "host-sim"))]
 fn unified_main() -> ! {
 
-     // GPIO Example
-     if let Ok(pin) = GpioPin::new(2) {
-        configure_pin(pin.number(), PinMode::Output); // Configure pin 2 as output
-        write_pin(pin.number(), PinValue::High);      // Set pin 2 to HIGH
-        let gpio_state = read_pin(pin.number());      // Read the state of pin 2
-        if let PinValue::High = gpio_state {
-            write_pin(pin.number(), PinValue::Low);   // Turns pin 2 state to Low if it is High
-        }
-    }
-
-    // USART Example
-    usart_init(9600); // Initialize USART with 9600 baud
-    usart_write(0x31); // Write '1' (ASCII 0x31)
-    let received = usart_read(); // Read received data
-    usart_write(received); // Echo back received data
-
-    // SPI Example (Master Mode)
-    spi_init_master(); // Initialize SPI in master mode
-    spi_write(0x55);   // Send data
-    let spi_data = spi_read(); // Read a byte
-    let spi_response = spi_transfer(0x42); // Simultaneously write and read
-    if spi_response != 0x00 {
-        let _ = spi_response; // Could be replaced with logic to add consequences to the response
-    }
-
-    // SPI Example (Slave Mode)
-    spi_init_slave(); // Initialize SPI in slave mode
-    let slave_response = spi_transfer(0x00); // Send and receive data
-    if slave_response != 0x00 {
-        let _ = slave_response; // Could be replaced with logic to add consequences to the response
-    }
+    // Reset clock configuration of the board, the reported frequencies drive the baud and bit-rate math
+    let clocks = clock_init(ClockConfig::default()).unwrap_or_else(|_| clocks());
 
-    // I2C Example
-    i2c_init(100_000); // Initialize I2C at 100 kHz
-    i2c_write(0x42, &[0x01, 0x02, 0x03]); // Write data to slave
-    let mut i2c_data = [0u8; 3];
-    let i2c_result = i2c_read(0x42, &mut i2c_data); // Read data from slave
-    if i2c_result == 0 {
-        let _ = i2c_data; // Could be replaced with logic to add consequences to twhat was read
-    }
+    // Every driver call returns a `Result`: an example that fails is skipped and the next one still runs
+    let _ = gpio_example(Port::B, 5); // Pins missing on the chip are rejected with HalError::InvalidPin
+    let _ = usart_example(&clocks);
+    let _ = spi_example(&clocks);
+    let _ = i2c_example(&clocks);
 
     // Infinite loop to keep the program active
     loop {
@@ -81,19 +64,56 @@ fn unified_main() -> ! {
     }
 }
 
-// Safe wrapper for GPIO pin numbers
-struct GpioPin(u8);
+// GPIO Example
+#[cfg(not(feature = "host-sim"))]
+fn gpio_example(port: Port, pin: u8) -> Result<()> {
+    configure_pin(port, pin, PinMode::Output)?; // Configure the pin as output
+    write_pin(port, pin, PinValue::High)?;      // Set the pin to HIGH
+    let gpio_state = read_pin(port, pin)?;      // Read the state of the pin
+    if let PinValue::High = gpio_state {
+        write_pin(port, pin, PinValue::Low)?;   // Turns the pin state to Low if it is High
+    }
+    Ok(())
+}
+
+// USART Example
+#[cfg(not(feature = "host-sim"))]
+fn usart_example(clocks: &Clocks) -> Result<()> {
+    usart_init(UsartConfig::new(9600), clocks)?; // Initialize USART with 9600 baud, 8N1 frames
+    usart_write(0x31)?; // Write '1' (ASCII 0x31)
+    let received = usart_read()?; // Read received data
+    usart_write(received)?; // Echo back received data
+    usart_println!("\r\nreceived {:#04x}", received) // Readable diagnostics, formatted straight into usart_write
+}
 
-impl GpioPin {
-    fn new(pin: u8) -> Result<Self, &'static str> {
-        if pin < 32 {
-            Ok(Self(pin))
-        } else {
-            Err("Invalid GPIO pin: must be between 0 and 31")
-        }
+// SPI Example
+#[cfg(not(feature = "host-sim"))]
+fn spi_example(clocks: &Clocks) -> Result<()> {
+    // Master Mode
+    spi_init_master(SpiConfig::default(), clocks)?; // Initialize SPI in master mode, mode 0 MSB first at up to 1 MHz
+    spi_write(0x55)?;   // Send data
+    let _spi_data = spi_read()?; // Read a byte while clocking out the fill byte (0x00)
+    let spi_response = spi_transfer(0x42)?; // Simultaneously write and read
+    if spi_response != 0x00 {
+        let _ = spi_response; // Could be replaced with logic to add consequences to the response
     }
 
-    fn number(&self) -> u8 {
-        self.0
+    // Slave Mode
+    spi_init_slave(SpiConfig::default())?; // Initialize SPI in slave mode
+    let slave_response = spi_transfer(0x00)?; // Send and receive data
+    if slave_response != 0x00 {
+        let _ = slave_response; // Could be replaced with logic to add consequences to the response
     }
+    Ok(())
+}
+
+// I2C Example
+#[cfg(not(feature = "host-sim"))]
+fn i2c_example(clocks: &Clocks) -> Result<()> {
+    i2c_init(100_000, clocks)?; // Initialize I2C at 100 kHz
+    i2c_write(0x42, &[0x01, 0x02, 0x03])?; // Write data to slave
+    let mut i2c_data = [0u8; 3];
+    i2c_read(0x42, &mut i2c_data)?; // Read data from slave, a missing slave is reported as HalError::Nack
+    let _ = i2c_data; // Could be replaced with logic to add consequences to what was read
+    Ok(())
 }
@@ -0,0 +1,77 @@
+// Volatile access to memory-mapped peripheral registers.
+// Every driver goes through these two functions instead of dereferencing register pointers itself.
+// On target they compile down to a single volatile load or store, with the `host-sim` feature they are
+// routed to the simulated register file so the drivers can run under `cargo test` on a regular PC.
+
+use core::ops::{BitAnd, BitOr, Not};
+
+// Register widths supported by the memory map (8-bit on the AVR, 32-bit on the Cortex-M3)
+pub trait RegisterWidth:
+    Copy + PartialEq + BitOr<Output = Self> + BitAnd<Output = Self> + Not<Output = Self>
+{
+    const BITS: u8;
+    fn to_u32(self) -> u32;
+    fn from_u32(value: u32) -> Self;
+}
+
+impl RegisterWidth for u8 {
+    const BITS: u8 = 8;
+    fn to_u32(self) -> u32 {
+        self as u32
+    }
+    fn from_u32(value: u32) -> Self {
+        value as u8
+    }
+}
+
+impl RegisterWidth for u16 {
+    const BITS: u8 = 16;
+    fn to_u32(self) -> u32 {
+        self as u32
+    }
+    fn from_u32(value: u32) -> Self {
+        value as u16
+    }
+}
+
+impl RegisterWidth for u32 {
+    const BITS: u8 = 32;
+    fn to_u32(self) -> u32 {
+        self
+    }
+    fn from_u32(value: u32) -> Self {
+        value
+    }
+}
+
+/// Reads the register located at `reg`
+///
+/// # Safety
+/// `reg` must be the address of a readable register of the running chip
+#[inline(always)]
+pub unsafe fn read<T: RegisterWidth>(reg: *mut T) -> T {
+    #[cfg(feature = "host-sim")]
+    {
+        T::from_u32(crate::sim::read(reg as usize, T::BITS))
+    }
+    #[cfg(not(feature = "host-sim"))]
+    {
+        core::ptr::read_volatile(reg)
+    }
+}
+
+/// Writes `value` into the register located at `reg`
+///
+/// # Safety
+/// `reg` must be the address of a writable register of the running chip
+#[inline(always)]
+pub unsafe fn write<T: RegisterWidth>(reg: *mut T, value: T) {
+    #[cfg(feature = "host-sim")]
+    {
+        crate::sim::write(reg as usize, T::BITS, value.to_u32())
+    }
+    #[cfg(not(feature = "host-sim"))]
+    {
+        core::ptr::write_volatile(reg, value)
+    }
+}
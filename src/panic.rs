@@ -0,0 +1,102 @@
+// Panic handlers of the firmware, one of them is picked with the features:
+// - `panic-loop` (default): spins silently, nothing is reported
+// - `panic-usart`: re-initialises the console (`ActiveUSART`, `CONSOLE` frames), prints the location and the message
+//   of the panic, then halts with interrupts disabled
+// - `panic-reset`: prints like `panic-usart`, then lets the watchdog reset the chip (implies `panic-usart`)
+// `panic-usart` and `panic-reset` take precedence over `panic-loop`, so they can be enabled on top of the defaults.
+
+use core::fmt::Display;
+use core::panic::Location;
+
+use crate::usart::{UsartConfig, USART};
+use crate::usart::print::print;
+use crate::Result;
+
+// Frame format of the console the panic report is printed on
+pub const CONSOLE: UsartConfig = UsartConfig::new(115_200);
+
+// Prints "panicked at <file>:<line>:<column>:" and the message on its own line, then waits for the last byte to
+// leave the USART. Each write is bounded by the timeout policy, a dead console cannot hold the handler forever.
+pub fn report<U: USART>(location: Option<&Location<'_>>, message: &dyn Display) -> Result<()> {
+    match location {
+        Some(location) => print::<U>(format_args!(
+            "\r\npanicked at {}:{}:{}:\r\n",
+            location.file(),
+            location.line(),
+            location.column()
+        ))?,
+        None => print::<U>(format_args!("\r\npanicked:\r\n"))?,
+    }
+    print::<U>(format_args!("{}\r\n", message))?;
+    U::usart_flush()
+}
+
+#[cfg(all(not(feature = "host-sim"), feature = "panic-usart", not(any(feature = "atmega328p", feature = "cortex_m3"))))]
+compile_error!("the `panic-usart` and `panic-reset` features need the `atmega328p` or the `cortex_m3` feature");
+
+#[cfg(all(not(feature = "host-sim"), feature = "panic-loop", not(feature = "panic-usart")))]
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
+
+#[cfg(all(not(feature = "host-sim"), feature = "panic-usart", any(feature = "atmega328p", feature = "cortex_m3")))]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    use crate::clock::clocks;
+    use crate::usart::ActiveUSART;
+
+    disable_interrupts(); // The console is the only thing left running, an interrupt handler could touch it
+    // A console that fails to initialise is still written to, with whatever it was configured with before
+    let _ = ActiveUSART::usart_init(CONSOLE, &clocks());
+    let _ = report::<ActiveUSART>(info.location(), &info.message());
+    halt()
+}
+
+#[cfg(all(not(feature = "host-sim"), feature = "panic-usart", feature = "cortex_m3"))]
+fn disable_interrupts() {
+    cortex_m::interrupt::disable();
+}
+
+#[cfg(all(not(feature = "host-sim"), feature = "panic-usart", feature = "atmega328p"))]
+fn disable_interrupts() {
+    avr_device::interrupt::disable();
+}
+
+#[cfg(all(not(feature = "host-sim"), feature = "panic-usart", not(feature = "panic-reset")))]
+fn halt() -> ! {
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+// Starts the independent watchdog with its reset values (LSI / 4, reload 0xFFF): the chip resets within ~400 ms
+#[cfg(all(not(feature = "host-sim"), feature = "cortex_m3", feature = "panic-reset"))]
+fn halt() -> ! {
+    use crate::reg::Reg;
+
+    const IWDG_KR: Reg<u32> = unsafe { Reg::new(0x4000_3000) }; // Key register
+    const KEY_START: u32 = 0xCCCC;
+
+    IWDG_KR.write(KEY_START);
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+// Enables the watchdog in system reset mode with its shortest timeout (16 ms)
+// WDE can only be set within 4 cycles of setting WDCE, the two writes are consecutive with interrupts disabled
+#[cfg(all(not(feature = "host-sim"), feature = "atmega328p", feature = "panic-reset"))]
+fn halt() -> ! {
+    use crate::reg::Reg;
+
+    const WDTCSR: Reg<u8> = unsafe { Reg::new(0x60) }; // Watchdog Timer Control Register
+    const WDCE: u8 = 1 << 4; // Watchdog Change Enable
+    const WDE: u8 = 1 << 3;  // Watchdog System Reset Enable
+
+    WDTCSR.write(WDCE | WDE);
+    WDTCSR.write(WDE); // Prescaler bits left at 0: 2K cycles of the 128 kHz oscillator
+    loop {
+        core::hint::spin_loop();
+    }
+}
@@ -0,0 +1,79 @@
+// Peripheral clock gating of the Cortex-M3 (Reset and Clock Control)
+// Every peripheral starts with its clock disabled: until its enable bit is set, writes to its registers are
+// ignored and reads return 0. The `CortexM3` drivers call `enable` at the start of their init functions.
+
+use crate::reg::Reg;
+
+pub(crate) const RCC_BASE: usize = 0x4002_1000; // Base address of the Reset and Clock Control
+
+const RCC_APB2RSTR: Reg<u32> = unsafe { Reg::new(RCC_BASE + 0x0C) }; // APB2 peripheral reset register
+const RCC_APB1RSTR: Reg<u32> = unsafe { Reg::new(RCC_BASE + 0x10) }; // APB1 peripheral reset register
+const RCC_AHBENR: Reg<u32> = unsafe { Reg::new(RCC_BASE + 0x14) };   // AHB peripheral clock enable register
+const RCC_APB2ENR: Reg<u32> = unsafe { Reg::new(RCC_BASE + 0x18) };  // APB2 peripheral clock enable register
+const RCC_APB1ENR: Reg<u32> = unsafe { Reg::new(RCC_BASE + 0x1C) };  // APB1 peripheral clock enable register
+const RCC_AHBRSTR: Reg<u32> = unsafe { Reg::new(RCC_BASE + 0x28) };  // AHB peripheral reset register
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Peripheral {
+    GpioA,
+    GpioB,
+    GpioC,
+    GpioD,
+    GpioE,
+    Usart1,
+    Usart2,
+    Usart3,
+    Uart4,
+    Uart5,
+    Spi1,
+    Spi2,
+    I2c1,
+    I2c2,
+}
+
+impl Peripheral {
+    // Enable register, reset register and bit of the peripheral
+    // The GPIO ports (MODER-style, mapped from 0x4800_0000) sit on the AHB bus
+    fn gate(self) -> (Reg<u32>, Reg<u32>, u32) {
+        match self {
+            Peripheral::GpioA => (RCC_AHBENR, RCC_AHBRSTR, 1 << 17),
+            Peripheral::GpioB => (RCC_AHBENR, RCC_AHBRSTR, 1 << 18),
+            Peripheral::GpioC => (RCC_AHBENR, RCC_AHBRSTR, 1 << 19),
+            Peripheral::GpioD => (RCC_AHBENR, RCC_AHBRSTR, 1 << 20),
+            Peripheral::GpioE => (RCC_AHBENR, RCC_AHBRSTR, 1 << 21),
+            Peripheral::Spi1 => (RCC_APB2ENR, RCC_APB2RSTR, 1 << 12),
+            Peripheral::Usart1 => (RCC_APB2ENR, RCC_APB2RSTR, 1 << 14),
+            Peripheral::Spi2 => (RCC_APB1ENR, RCC_APB1RSTR, 1 << 14),
+            Peripheral::Usart2 => (RCC_APB1ENR, RCC_APB1RSTR, 1 << 17),
+            Peripheral::Usart3 => (RCC_APB1ENR, RCC_APB1RSTR, 1 << 18),
+            Peripheral::Uart4 => (RCC_APB1ENR, RCC_APB1RSTR, 1 << 19),
+            Peripheral::Uart5 => (RCC_APB1ENR, RCC_APB1RSTR, 1 << 20),
+            Peripheral::I2c1 => (RCC_APB1ENR, RCC_APB1RSTR, 1 << 21),
+            Peripheral::I2c2 => (RCC_APB1ENR, RCC_APB1RSTR, 1 << 22),
+        }
+    }
+}
+
+// Turns the peripheral clock on, enabling an already running peripheral has no effect
+pub fn enable(peripheral: Peripheral) {
+    let (enr, _, bit) = peripheral.gate();
+    enr.set_bits(bit);
+}
+
+// Turns the peripheral clock off to save power, its registers keep their value
+pub fn disable(peripheral: Peripheral) {
+    let (enr, _, bit) = peripheral.gate();
+    enr.clear_bits(bit);
+}
+
+// Pulses the reset line of the peripheral, bringing all of its registers back to their reset value
+pub fn reset(peripheral: Peripheral) {
+    let (_, rstr, bit) = peripheral.gate();
+    rstr.set_bits(bit);
+    rstr.clear_bits(bit);
+}
+
+pub fn is_enabled(peripheral: Peripheral) -> bool {
+    let (enr, _, bit) = peripheral.gate();
+    enr.is_set(bit)
+}
@@ -0,0 +1,96 @@
+// Typed handles on memory-mapped registers
+// A `Reg<T>` knows the address and the width of a register, every access it makes is volatile (through
+// `crate::mmio`), and `Field` names the bits of a register so that drivers never shift raw numbers around.
+
+use core::marker::PhantomData;
+
+use crate::mmio::{self, RegisterWidth};
+
+#[derive(Clone, Copy)]
+pub struct Reg<T> {
+    addr: usize,
+    width: PhantomData<T>,
+}
+
+impl<T: RegisterWidth> Reg<T> {
+    /// Creates a handle on the register located at `addr`
+    ///
+    /// # Safety
+    /// `addr` must be the address of a `T`-wide register of the running chip, all the accessors are safe afterwards
+    pub const unsafe fn new(addr: usize) -> Self {
+        Reg { addr, width: PhantomData }
+    }
+
+    pub const fn addr(self) -> usize {
+        self.addr
+    }
+
+    pub fn read(self) -> T {
+        unsafe { mmio::read(self.addr as *mut T) }
+    }
+
+    pub fn write(self, value: T) {
+        unsafe { mmio::write(self.addr as *mut T, value) }
+    }
+
+    // Read-modify-write of the whole register
+    pub fn modify<F: FnOnce(T) -> T>(self, f: F) {
+        self.write(f(self.read()));
+    }
+
+    pub fn set_bits(self, mask: T) {
+        self.modify(|value| value | mask);
+    }
+
+    pub fn clear_bits(self, mask: T) {
+        self.modify(|value| value & !mask);
+    }
+
+    // True if any bit of `mask` is set, e.g. `TWCR.is_set(TWINT)`
+    pub fn is_set(self, mask: T) -> bool {
+        self.read() & mask != T::from_u32(0)
+    }
+
+    // Value of a multi-bit field, shifted down to bit 0
+    pub fn read_field(self, field: Field) -> T {
+        T::from_u32((self.read().to_u32() & field.mask()) >> field.offset)
+    }
+
+    // Replaces a multi-bit field, leaving the other bits of the register untouched
+    pub fn write_field(self, field: Field, value: T) {
+        let mask = T::from_u32(field.mask());
+        self.modify(|current| (current & !mask) | field.val::<T>(value.to_u32()));
+    }
+}
+
+// Group of `width` contiguous bits starting at bit `offset` of a register (e.g. BR[2:0] in SPI_CR1)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Field {
+    offset: u8,
+    width: u8,
+}
+
+impl Field {
+    pub const fn new(offset: u8, width: u8) -> Self {
+        Field { offset, width }
+    }
+
+    pub const fn offset(self) -> u8 {
+        self.offset
+    }
+
+    pub const fn width(self) -> u8 {
+        self.width
+    }
+
+    // Bits covered by the field, in register position
+    pub const fn mask(self) -> u32 {
+        let bits = if self.width >= 32 { u32::MAX } else { (1 << self.width) - 1 };
+        bits << self.offset
+    }
+
+    // `value` moved to the field position, ready to be or-ed with other fields and written
+    pub fn val<T: RegisterWidth>(self, value: u32) -> T {
+        T::from_u32((value << self.offset) & self.mask())
+    }
+}
@@ -0,0 +1,109 @@
+// Built-in commands of the shell, each one generic over the driver it exercises:
+//     commands::gpio::<ActiveGPIO>()
+
+use core::fmt::Write;
+
+use super::{parse_number, Args, Command, CommandError, CommandResult};
+use crate::gpio::{PinMode, PinValue, Port, GPIO};
+use crate::i2c::I2C;
+use crate::spi::SPI;
+use crate::HalError;
+
+pub const fn gpio<G: GPIO>() -> Command {
+    Command {
+        name: "gpio",
+        usage: "<pin> [high|low|input|pullup|output]",
+        help: "read, drive or configure a pin, e.g. gpio b5 high",
+        run: run_gpio::<G>,
+    }
+}
+
+pub const fn i2c_scan<I: I2C>() -> Command {
+    Command {
+        name: "i2cscan",
+        usage: "",
+        help: "list the I2C addresses that acknowledge",
+        run: run_i2c_scan::<I>,
+    }
+}
+
+pub const fn spi<S: SPI>() -> Command {
+    Command {
+        name: "spi",
+        usage: "<byte>...",
+        help: "send the bytes and print the bytes received, e.g. spi 0x9f 0 0",
+        run: run_spi::<S>,
+    }
+}
+
+// Pin written as port letter and number, e.g. "b5" or "A10"
+fn parse_pin(word: &str) -> Option<(Port, u8)> {
+    let (letter, number) = word.split_at_checked(1)?;
+    let port = match letter {
+        "a" | "A" => Port::A,
+        "b" | "B" => Port::B,
+        "c" | "C" => Port::C,
+        "d" | "D" => Port::D,
+        "e" | "E" => Port::E,
+        _ => return None,
+    };
+    Some((port, number.parse().ok()?))
+}
+
+fn run_gpio<G: GPIO>(args: &mut Args<'_>, out: &mut dyn Write) -> CommandResult {
+    let name = args.word()?;
+    let (port, pin) = parse_pin(name).ok_or(CommandError::Usage)?;
+    let action = args.optional();
+    args.end()?;
+    match action {
+        None => {
+            let level = match G::read_pin(port, pin)? {
+                PinValue::High => "high",
+                PinValue::Low => "low",
+            };
+            write!(out, "{}: {}\r\n", name, level)?;
+        }
+        Some("high") => G::write_pin(port, pin, PinValue::High)?,
+        Some("low") => G::write_pin(port, pin, PinValue::Low)?,
+        Some("input") => G::configure_pin(port, pin, PinMode::Input)?,
+        Some("pullup") => G::configure_pin(port, pin, PinMode::InputPullUp)?,
+        Some("output") => G::configure_pin(port, pin, PinMode::Output)?,
+        Some(_) => return Err(CommandError::Usage),
+    }
+    Ok(())
+}
+
+// Addresses an empty write to every 7-bit address but the reserved ones (0x00-0x07 and 0x78-0x7F)
+fn run_i2c_scan<I: I2C>(args: &mut Args<'_>, out: &mut dyn Write) -> CommandResult {
+    args.end()?;
+    let mut found = 0;
+    for address in 0x08..=0x77 {
+        match I::i2c_write(address, &[]) {
+            Ok(()) => {
+                write!(out, "0x{:02x}\r\n", address)?;
+                found += 1;
+            }
+            Err(HalError::Nack) => {}
+            Err(error) => return Err(error.into()),
+        }
+    }
+    write!(out, "{} device(s)\r\n", found)?;
+    Ok(())
+}
+
+// Every byte is checked before the first one is sent. The chip select is left to the `gpio` command.
+fn run_spi<S: SPI>(args: &mut Args<'_>, out: &mut dyn Write) -> CommandResult {
+    let mut bytes = args.clone();
+    bytes.word()?;
+    while let Some(word) = bytes.optional() {
+        parse_number::<u8>(word).ok_or(CommandError::Usage)?;
+    }
+    let mut separator = "";
+    while let Some(word) = args.optional() {
+        let received = S::spi_transfer(parse_number(word).ok_or(CommandError::Usage)?)?;
+        write!(out, "{}{:02x}", separator, received)?;
+        separator = " ";
+    }
+    write!(out, "\r\n")?;
+    Ok(())
+}
@@ -0,0 +1,186 @@
+// Command shell on a serial console
+// `Shell` reads characters from a USART and echoes them with line editing: backspace (or DEL) erases the last
+// character, Ctrl-C drops the line, and a line longer than the buffer is refused with a bell. On Enter the first word
+// selects a command of a static table, the following words are its arguments:
+//     static COMMANDS: [Command; 3] =
+//         [commands::gpio::<ActiveGPIO>(), commands::i2c_scan::<ActiveI2C>(), commands::spi::<ActiveSPI>()];
+//     Shell::<ActiveUSART, 64>::new(&COMMANDS, "> ").run()
+// `help` is always available and lists the table.
+
+pub mod commands;
+
+use core::fmt::{self, Write};
+use core::marker::PhantomData;
+use core::str::SplitAsciiWhitespace;
+
+use crate::usart::print::print;
+use crate::usart::serial::Serial;
+use crate::usart::USART;
+use crate::{HalError, Result};
+
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7F;
+const CTRL_C: u8 = 0x03;
+const BELL: u8 = 0x07;
+
+// Why a command failed, reported by the shell before the next prompt
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CommandError {
+    Usage,         // Missing, extra or malformed argument: the usage line of the command is printed
+    Hal(HalError), // A driver call failed
+    Output,        // The console did not take the output
+}
+
+impl From<HalError> for CommandError {
+    fn from(error: HalError) -> Self {
+        CommandError::Hal(error)
+    }
+}
+
+impl From<fmt::Error> for CommandError {
+    fn from(_: fmt::Error) -> Self {
+        CommandError::Output
+    }
+}
+
+pub type CommandResult<T = ()> = core::result::Result<T, CommandError>;
+
+// Entry of the command table, `run` writes its output to the console through `out`
+pub struct Command {
+    pub name: &'static str,
+    pub usage: &'static str, // Arguments, e.g. "<pin> [high|low]"
+    pub help: &'static str,  // One-line description shown by `help`
+    pub run: fn(&mut Args<'_>, &mut dyn Write) -> CommandResult,
+}
+
+// Arguments of a command line, separated by spaces
+#[derive(Clone)]
+pub struct Args<'a> {
+    words: SplitAsciiWhitespace<'a>,
+}
+
+impl<'a> Args<'a> {
+    pub fn new(line: &'a str) -> Self {
+        Args { words: line.split_ascii_whitespace() }
+    }
+
+    // Next argument, `CommandError::Usage` if there is none
+    pub fn word(&mut self) -> CommandResult<&'a str> {
+        self.optional().ok_or(CommandError::Usage)
+    }
+
+    pub fn optional(&mut self) -> Option<&'a str> {
+        self.words.next()
+    }
+
+    // Next argument as a number, see `parse_number`
+    pub fn number<T: TryFrom<u32>>(&mut self) -> CommandResult<T> {
+        parse_number(self.word()?).ok_or(CommandError::Usage)
+    }
+
+    // `CommandError::Usage` if arguments are left
+    pub fn end(&mut self) -> CommandResult {
+        match self.optional() {
+            Some(_) => Err(CommandError::Usage),
+            None => Ok(()),
+        }
+    }
+}
+
+// Decimal, or hexadecimal with a 0x prefix, None if the value does not fit in `T`
+pub fn parse_number<T: TryFrom<u32>>(word: &str) -> Option<T> {
+    let value = match word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => word.parse(),
+    };
+    T::try_from(value.ok()?).ok()
+}
+
+// Shell reading from the USART `U`, with a line buffer of `N` characters
+pub struct Shell<U: USART, const N: usize> {
+    line: [u8; N],
+    len: usize,
+    last: u8, // Previous character, a "\r\n" sent by the terminal ends a single line
+    commands: &'static [Command],
+    prompt: &'static str,
+    _usart: PhantomData<U>,
+}
+
+impl<U: USART, const N: usize> Shell<U, N> {
+    pub const fn new(commands: &'static [Command], prompt: &'static str) -> Self {
+        Shell { line: [0; N], len: 0, last: 0, commands, prompt, _usart: PhantomData }
+    }
+
+    // Prints the first prompt
+    pub fn start(&mut self) -> Result<()> {
+        self.len = 0;
+        print::<U>(format_args!("{}", self.prompt))
+    }
+
+    // Waits (bounded by the timeout policy) for the next character and handles it
+    pub fn poll(&mut self) -> Result<()> {
+        let byte = U::usart_read()?;
+        self.feed(byte)
+    }
+
+    // Serves the console forever, a timeout only means that no key was pressed and receive errors are counted by
+    // the USART (`usart_errors`)
+    pub fn run(&mut self) -> ! {
+        let _ = self.start();
+        loop {
+            let _ = self.poll();
+        }
+    }
+
+    // Handles one received character
+    pub fn feed(&mut self, byte: u8) -> Result<()> {
+        let last = core::mem::replace(&mut self.last, byte);
+        match byte {
+            b'\n' if last == b'\r' => Ok(()),
+            b'\r' | b'\n' => {
+                print::<U>(format_args!("\r\n"))?;
+                self.execute()?;
+                self.start()
+            }
+            BACKSPACE | DELETE if self.len > 0 => {
+                self.len -= 1;
+                print::<U>(format_args!("\x08 \x08")) // Back, blank out the character, back again
+            }
+            CTRL_C => {
+                print::<U>(format_args!("^C\r\n"))?;
+                self.start()
+            }
+            0x20..=0x7E if self.len < N => {
+                self.line[self.len] = byte;
+                self.len += 1;
+                U::usart_write(byte)
+            }
+            0x20..=0x7E => U::usart_write(BELL),
+            _ => Ok(()), // Other control characters (arrow key sequences, ...) are ignored
+        }
+    }
+
+    fn execute(&self) -> Result<()> {
+        let line = core::str::from_utf8(&self.line[..self.len]).unwrap_or(""); // Only printable ASCII is stored
+        let mut args = Args::new(line);
+        let Some(name) = args.optional() else {
+            return Ok(());
+        };
+        if name == "help" {
+            print::<U>(format_args!("help - list the commands\r\n"))?;
+            for command in self.commands {
+                print::<U>(format_args!("{} {} - {}\r\n", command.name, command.usage, command.help))?;
+            }
+            return Ok(());
+        }
+        let Some(command) = self.commands.iter().find(|command| command.name == name) else {
+            return print::<U>(format_args!("unknown command: {}, try help\r\n", name));
+        };
+        match (command.run)(&mut args, &mut Serial::<U>::new()) {
+            Ok(()) => Ok(()),
+            Err(CommandError::Usage) => print::<U>(format_args!("usage: {} {}\r\n", command.name, command.usage)),
+            Err(CommandError::Hal(error)) => print::<U>(format_args!("error: {}\r\n", error)),
+            Err(CommandError::Output) => print::<U>(format_args!("\r\nerror: output lost\r\n")),
+        }
+    }
+}
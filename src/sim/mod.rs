@@ -0,0 +1,113 @@
+// Host-side simulation of the microcontrollers' memory map (enabled with the `host-sim` feature)
+// Register accesses made through `crate::mmio` land in a per-thread register file instead of real hardware,
+// so the `Atmega328p` and `CortexM3` implementations can be exercised by ordinary unit tests.
+// Each test thread owns its own register file: tests running in parallel never see each other's registers.
+
+pub mod models;
+pub mod trace;
+
+use std::boxed::Box;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::vec::Vec;
+
+// Storage of the simulated memory map, indexed by register address. Unwritten registers read as 0
+#[derive(Default)]
+pub struct RegisterFile {
+    cells: HashMap<usize, u32>,
+}
+
+impl RegisterFile {
+    pub fn get(&self, addr: usize) -> u32 {
+        self.cells.get(&addr).copied().unwrap_or(0)
+    }
+
+    pub fn set(&mut self, addr: usize, value: u32) {
+        self.cells.insert(addr, value);
+    }
+
+    pub fn set_bits(&mut self, addr: usize, mask: u32) {
+        let value = self.get(addr) | mask;
+        self.set(addr, value);
+    }
+
+    pub fn clear_bits(&mut self, addr: usize, mask: u32) {
+        let value = self.get(addr) & !mask;
+        self.set(addr, value);
+    }
+}
+
+// Behaviour of a simulated peripheral, plugged into the register file with `attach`
+// Models react to the driver's accesses, e.g. to raise TWINT, TXE or RXNE so that busy-wait loops terminate
+pub trait Peripheral {
+    // Called before the driver reads `addr`, the model may update the value the driver is about to see
+    fn before_read(&mut self, _regs: &mut RegisterFile, _addr: usize) {}
+
+    // Called after the driver stored `value` at `addr`
+    fn after_write(&mut self, _regs: &mut RegisterFile, _addr: usize, _value: u32) {}
+}
+
+#[derive(Default)]
+struct Simulator {
+    regs: RegisterFile,
+    peripherals: Vec<Box<dyn Peripheral>>,
+    trace: Vec<trace::Access>,
+}
+
+std::thread_local! {
+    static SIM: RefCell<Simulator> = RefCell::new(Simulator::default());
+}
+
+fn width_mask(bits: u8) -> u32 {
+    if bits >= 32 {
+        u32::MAX
+    } else {
+        (1 << bits) - 1
+    }
+}
+
+// Clears every register, detaches every peripheral model and empties the trace of the current thread
+pub fn reset() {
+    SIM.with(|sim| *sim.borrow_mut() = Simulator::default());
+}
+
+// Plugs a peripheral model into the register file of the current thread
+pub fn attach<P: Peripheral + 'static>(peripheral: P) {
+    SIM.with(|sim| sim.borrow_mut().peripherals.push(Box::new(peripheral)));
+}
+
+// Returns the raw content of a register without notifying the models
+pub fn peek(addr: usize) -> u32 {
+    SIM.with(|sim| sim.borrow().regs.get(addr))
+}
+
+// Sets the raw content of a register without notifying the models (e.g. to preload a received byte)
+pub fn poke(addr: usize, value: u32) {
+    SIM.with(|sim| sim.borrow_mut().regs.set(addr, value));
+}
+
+// Read access coming from `crate::mmio`
+pub(crate) fn read(addr: usize, bits: u8) -> u32 {
+    SIM.with(|sim| {
+        let sim = &mut *sim.borrow_mut();
+        for peripheral in sim.peripherals.iter_mut() {
+            peripheral.before_read(&mut sim.regs, addr);
+        }
+        let value = sim.regs.get(addr) & width_mask(bits);
+        sim.trace.push(trace::Access { kind: trace::Kind::Read, addr, bits, value });
+        value
+    })
+}
+
+// Write access coming from `crate::mmio`
+pub(crate) fn write(addr: usize, bits: u8, value: u32) {
+    SIM.with(|sim| {
+        let sim = &mut *sim.borrow_mut();
+        let value = value & width_mask(bits);
+        sim.regs.set(addr, value);
+        sim.trace.push(trace::Access { kind: trace::Kind::Write, addr, bits, value });
+        for peripheral in sim.peripherals.iter_mut() {
+            peripheral.after_write(&mut sim.regs, addr, value);
+        }
+    })
+}
@@ -0,0 +1,93 @@
+// Ready-made peripheral models for the most common busy-wait flags
+use core::ops::Range;
+
+use super::{Peripheral, RegisterFile};
+
+// Keeps `mask` set in the register at `addr` every time it is read
+// e.g. UDRE0 in UCSR0A or TXE in USART2_SR for a transmitter that is always ready
+pub struct AlwaysSet {
+    pub addr: usize,
+    pub mask: u32,
+}
+
+impl Peripheral for AlwaysSet {
+    fn before_read(&mut self, regs: &mut RegisterFile, addr: usize) {
+        if addr == self.addr {
+            regs.set_bits(addr, self.mask);
+        }
+    }
+}
+
+// Sets `mask` in the register at `target` whenever the register at `trigger` is written
+// e.g. SPIF in SPSR once a byte is loaded into SPDR, or SB in I2C_SR1 once START is requested
+pub struct SetOnWrite {
+    pub trigger: usize,
+    pub target: usize,
+    pub mask: u32,
+}
+
+impl Peripheral for SetOnWrite {
+    fn after_write(&mut self, regs: &mut RegisterFile, addr: usize, _value: u32) {
+        if addr == self.trigger {
+            regs.set_bits(self.target, self.mask);
+        }
+    }
+}
+
+// Clears `mask` in the register at `target` whenever the register at `trigger` is read
+// e.g. SPIF is cleared by reading SPSR then SPDR, RXNE by reading USART2_DR
+pub struct ClearOnRead {
+    pub trigger: usize,
+    pub target: usize,
+    pub mask: u32,
+}
+
+impl Peripheral for ClearOnRead {
+    fn before_read(&mut self, regs: &mut RegisterFile, addr: usize) {
+        if addr == self.trigger {
+            regs.clear_bits(self.target, self.mask);
+        }
+    }
+}
+
+// Clears `mask` in the register at `target` whenever the register at `trigger` is written
+// e.g. writing a one to TWINT clears the flag until the TWI finishes the next operation
+pub struct ClearOnWrite {
+    pub trigger: usize,
+    pub target: usize,
+    pub mask: u32,
+}
+
+impl Peripheral for ClearOnWrite {
+    fn after_write(&mut self, regs: &mut RegisterFile, addr: usize, _value: u32) {
+        if addr == self.trigger {
+            regs.clear_bits(self.target, self.mask);
+        }
+    }
+}
+
+// Panics if a register in `block` is accessed while `mask` is cleared in the register at `enable`
+// e.g. USART2 registers touched before USART2EN is set in RCC_APB1ENR, which real hardware silently ignores
+pub struct ClockGate {
+    pub enable: usize,
+    pub mask: u32,
+    pub block: Range<usize>,
+}
+
+impl ClockGate {
+    fn check(&self, regs: &RegisterFile, addr: usize) {
+        if self.block.contains(&addr) && regs.get(self.enable) & self.mask == 0 {
+            panic!("register {:#010x} accessed while its clock is disabled", addr);
+        }
+    }
+}
+
+impl Peripheral for ClockGate {
+    fn before_read(&mut self, regs: &mut RegisterFile, addr: usize) {
+        self.check(regs, addr);
+    }
+
+    fn after_write(&mut self, regs: &mut RegisterFile, addr: usize, _value: u32) {
+        self.check(regs, addr);
+    }
+}
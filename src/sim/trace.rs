@@ -0,0 +1,186 @@
+// Register access trace of the simulated memory map
+// Every read and write made by a driver is recorded in order with its address, width and value.
+// Tests take the trace after calling a driver function and compare it against an `Expected` sequence.
+
+use std::fmt;
+use std::vec::Vec;
+
+use super::SIM;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Read,
+    Write,
+}
+
+// A single register access, `bits` is the width of the access (8 or 32)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Access {
+    pub kind: Kind,
+    pub addr: usize,
+    pub bits: u8,
+    pub value: u32,
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            Kind::Read => "read ",
+            Kind::Write => "write",
+        };
+        write!(f, "{} u{:<2} @ {:#010x} = {:#x}", kind, self.bits, self.addr, self.value)
+    }
+}
+
+// Accesses recorded since the last `take` or `clear`, oldest first
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Trace {
+    accesses: Vec<Access>,
+}
+
+impl Trace {
+    pub fn accesses(&self) -> &[Access] {
+        &self.accesses
+    }
+
+    // Keeps only the writes, which is what most sequences need to lock down
+    pub fn writes(&self) -> Trace {
+        self.filter(|access| access.kind == Kind::Write)
+    }
+
+    // Keeps only the accesses made to the register at `addr`
+    pub fn at(&self, addr: usize) -> Trace {
+        self.filter(|access| access.addr == addr)
+    }
+
+    pub fn filter<F: Fn(&Access) -> bool>(&self, keep: F) -> Trace {
+        Trace { accesses: self.accesses.iter().copied().filter(|access| keep(access)).collect() }
+    }
+
+    // Panics with both sequences side by side if the trace does not follow `expected`
+    pub fn assert_matches(&self, expected: &Expected) {
+        if let Err(mismatch) = expected.check(&self.accesses) {
+            panic!("register trace mismatch at access #{}\n{}\n{}", mismatch, expected, self);
+        }
+    }
+}
+
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "actual:")?;
+        for (i, access) in self.accesses.iter().enumerate() {
+            writeln!(f, "  #{:<3} {}", i, access)?;
+        }
+        Ok(())
+    }
+}
+
+// Returns the accesses recorded on the current thread and starts a new trace
+pub fn take() -> Trace {
+    SIM.with(|sim| Trace { accesses: core::mem::take(&mut sim.borrow_mut().trace) })
+}
+
+// Drops the accesses recorded so far, e.g. to ignore the initialisation of a peripheral
+pub fn clear() {
+    SIM.with(|sim| sim.borrow_mut().trace.clear());
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Step {
+    // Exactly this access
+    Access(Access),
+    // Any number of reads of the register (busy-wait loop), including none
+    Polls(usize),
+}
+
+// Expected register sequence, built with chained calls:
+// Expected::new().write8(TWCR, TWINT | TWSTA | TWEN).polls(TWCR).write8(TWDR, 0x84)
+#[derive(Clone, Debug, Default)]
+pub struct Expected {
+    steps: Vec<Step>,
+}
+
+impl Expected {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn read8(self, addr: usize, value: u8) -> Self {
+        self.access(Kind::Read, addr, 8, value as u32)
+    }
+
+    pub fn write8(self, addr: usize, value: u8) -> Self {
+        self.access(Kind::Write, addr, 8, value as u32)
+    }
+
+    pub fn read32(self, addr: usize, value: u32) -> Self {
+        self.access(Kind::Read, addr, 32, value)
+    }
+
+    pub fn write32(self, addr: usize, value: u32) -> Self {
+        self.access(Kind::Write, addr, 32, value)
+    }
+
+    // Zero or more reads of `addr`, whatever their value
+    // When the next step is a read of `addr` as well, the last read is matched by that step instead
+    pub fn polls(mut self, addr: usize) -> Self {
+        self.steps.push(Step::Polls(addr));
+        self
+    }
+
+    fn access(mut self, kind: Kind, addr: usize, bits: u8, value: u32) -> Self {
+        self.steps.push(Step::Access(Access { kind, addr, bits, value }));
+        self
+    }
+
+    // Returns the index of the first access that does not match
+    fn check(&self, actual: &[Access]) -> Result<(), usize> {
+        let mut index = 0;
+        for (position, step) in self.steps.iter().enumerate() {
+            match *step {
+                Step::Access(expected) => {
+                    if actual.get(index) != Some(&expected) {
+                        return Err(index);
+                    }
+                    index += 1;
+                }
+                Step::Polls(addr) => {
+                    let reads = actual[index..]
+                        .iter()
+                        .take_while(|access| access.kind == Kind::Read && access.addr == addr)
+                        .count();
+                    // The last read is left to a following read of the same register, i.e. the data read after
+                    // a flag wait
+                    let read_follows = matches!(
+                        self.steps.get(position + 1),
+                        Some(Step::Access(next)) if next.kind == Kind::Read && next.addr == addr
+                    );
+                    index += if read_follows { reads.saturating_sub(1) } else { reads };
+                }
+            }
+        }
+        if index == actual.len() {
+            Ok(())
+        } else {
+            Err(index)
+        }
+    }
+}
+
+impl fmt::Display for Expected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "expected:")?;
+        for step in &self.steps {
+            match step {
+                Step::Access(access) => writeln!(f, "        {}", access)?,
+                Step::Polls(addr) => writeln!(f, "        read*     @ {:#010x}", addr)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// Takes the trace of the current thread and checks it against `expected`
+pub fn assert_trace(expected: &Expected) {
+    take().assert_matches(expected);
+}
@@ -1,59 +1,121 @@
-use super::SPI;
+use core::task::Waker;
 
-const SPCR: *mut u8 = 0x4C as *mut u8; // SPI Control Register
-const SPSR: *mut u8 = 0x4D as *mut u8; // SPI Status Register
-const SPDR: *mut u8 = 0x4E as *mut u8; // SPI Data Register
+use super::{AsyncSPI, BitOrder, SpiConfig, DEFAULT_SCK_HZ, SPI};
+use crate::clock::Clocks;
+use crate::global::global;
+use crate::interrupt;
+use crate::reg::{Field, Reg};
+use crate::timeout::wait_until;
+use crate::{HalError, Result};
+
+const SPCR: Reg<u8> = unsafe { Reg::new(0x4C) }; // SPI Control Register
+const SPSR: Reg<u8> = unsafe { Reg::new(0x4D) }; // SPI Status Register
+const SPDR: Reg<u8> = unsafe { Reg::new(0x4E) }; // SPI Data Register
+
+// SPCR bits
+const SPIE: u8 = 1 << 7; // SPI Interrupt Enable
+const SPE: u8 = 1 << 6;  // SPI Enable
+const DORD: u8 = 1 << 5; // Data Order, LSB first when set
+const MSTR: u8 = 1 << 4; // Master Mode (cleared for slave mode)
+const CPOL: u8 = 1 << 3; // Clock Polarity, SCK idles high when set
+const CPHA: u8 = 1 << 2; // Clock Phase, data sampled on the trailing edge when set
+const SPR: Field = Field::new(0, 2); // SPI Clock Rate Select bits (SPR1:SPR0)
+
+// SPSR bits
+const SPIF: u8 = 1 << 7; // SPI Interrupt Flag
+const SPI2X: u8 = 1 << 0; // Double SPI Speed, halves the division of SPR1:SPR0 in master mode
+
+// f_CPU division factors from the fastest to the slowest, with the SPR1:SPR0 value and SPI2X setting producing them
+// (SPR = 0b11 with SPI2X also divides by 64, the same as SPR = 0b10 without)
+const SCK_DIVIDERS: [(u32, u32, bool); 7] =
+    [(2, 0, true), (4, 0, false), (8, 1, true), (16, 1, false), (32, 2, true), (64, 2, false), (128, 3, false)];
+
+// Entering the SPI vector clears SPIF, `spi_on_interrupt` records the completion here instead
+global! { static TRANSFER_DONE: bool = false; }
+global! { static WAKER: Option<Waker> = None; }
+global! { static CONFIG: SpiConfig = SpiConfig::new(DEFAULT_SCK_HZ); } // Of the last init
 
 pub struct Atmega328p;
 
 impl SPI for Atmega328p {
-    // Initialize SPI as master
-    fn spi_init_master() {
-        const SPI_ENABLE: u8 = 1 << 6; // SPI Enable
-        const SPI_MASTER: u8 = 1 << 4; // SPI Master Mode
-        const SPI_CLOCK_DIV16: u8 = 1 << 1; // Clock rate = clockfrequency/16
-
-        unsafe {
-            *SPCR = SPI_ENABLE | SPI_MASTER | SPI_CLOCK_DIV16; //Configures SPI Control Register
-            *SPSR = 0; //Clears SPI Status Register
-        }
+    // Initialize SPI as master, with the smallest division of f_CPU that keeps SCK under `config.frequency`
+    fn spi_init_master(config: SpiConfig, clocks: &Clocks) -> Result<()> {
+        let &(_, spr, double_speed) = SCK_DIVIDERS
+            .iter()
+            .find(|&&(divider, _, _)| clocks.sysclk / divider <= config.frequency)
+            .ok_or(HalError::InvalidClock)?;
+        SPCR.write(SPE | MSTR | frame_format(config) | SPR.val::<u8>(spr)); //Configures SPI Control Register
+        SPSR.write(if double_speed { SPI2X } else { 0 }); //Sets SPI2X, the other SPSR bits are read-only
+        CONFIG.with(|current| current.set(config));
+        Ok(())
     }
 
-    // Initialize SPI as slave
-    fn spi_init_slave() {
-        const SPI_ENABLE: u8 = 1 << 6; // SPI Enable
-        const SPI_SLAVE: u8 = 0; // Clear MSTR bit for slave mode
+    // Initialize SPI as slave, SCK is generated by the master
+    fn spi_init_slave(config: SpiConfig) -> Result<()> {
+        SPCR.write(SPE | frame_format(config)); //Configures SPI Control Register, MSTR left cleared
+        SPSR.write(0); //Clears SPI Status Register
+        CONFIG.with(|current| current.set(config));
+        Ok(())
+    }
 
-        unsafe {
-            *SPCR = SPI_ENABLE | SPI_SLAVE; //Configures SPI Control Register
-            *SPSR = 0; //Clears SPI Status Register
-        }
+    // Loading SPDR starts the frame in master mode, in slave mode the byte waits for the master's SCK
+    fn spi_transfer(data: u8) -> Result<u8> {
+        SPDR.write(data); //Loads data into the SPI Data Register to start transmission
+        wait_until(is_transmission_complete)?;
+        Ok(SPDR.read()) //Returns received data from the SPI Data Register, which also clears SPIF
     }
 
-    fn spi_write(data: u8) {
-        unsafe {
-            *SPDR = data; //Loads data into the SPI Data Register to start transmission
-            while !is_transmission_complete() {}
-        }
+    fn spi_fill_byte() -> u8 {
+        CONFIG.with(|config| config.get().fill_byte)
     }
 
-    fn spi_read() -> u8 {
-        unsafe {
-            while !is_transmission_complete() {}
-            *SPDR //Returns received data from the SPI Data Register
+    // Two byte frames, in the order that keeps the 16 bits in the configured bit order on the wire
+    fn spi_transfer_u16(data: u16) -> Result<u16> {
+        let [high, low] = data.to_be_bytes();
+        if CONFIG.with(|config| config.get().bit_order) == BitOrder::LsbFirst {
+            let low = Self::spi_transfer(low)?;
+            Ok(u16::from_be_bytes([Self::spi_transfer(high)?, low]))
+        } else {
+            let high = Self::spi_transfer(high)?;
+            Ok(u16::from_be_bytes([high, Self::spi_transfer(low)?]))
         }
     }
+}
 
-    // Simultaneously writes and reads data in slave mode
-    fn spi_transfer(data: u8) -> u8 {
-        unsafe {
-            *SPDR = data; //Loads data into the SPI Data Register to start transmission
-            while !is_transmission_complete() {}
-            *SPDR //Returns received data from the SPI Data Register
-        }
+// DORD, CPOL and CPHA bits of SPCR
+fn frame_format(config: SpiConfig) -> u8 {
+    let mut bits = 0;
+    if config.bit_order == BitOrder::LsbFirst {
+        bits |= DORD;
+    }
+    if config.mode.idle_high() {
+        bits |= CPOL;
+    }
+    if config.mode.second_edge() {
+        bits |= CPHA;
     }
+    bits
 }
 
 fn is_transmission_complete() -> bool {
-    unsafe { *SPSR & (1 << 7) != 0 } //Waits for the SPI Interrupt Flag to be set, indicating complete transmission
+    SPSR.is_set(SPIF) //Checks the SPI Interrupt Flag, set once the transmission is complete
+}
+
+impl AsyncSPI for Atmega328p {
+    async fn spi_transfer_async(data: u8) -> Result<u8> {
+        interrupt::free(|| TRANSFER_DONE.with(|done| done.set(false)));
+        SPDR.write(data);
+        let done = || is_transmission_complete() || interrupt::free(|| TRANSFER_DONE.with(|done| done.replace(false)));
+        interrupt::wait_until(&WAKER, || SPCR.set_bits(SPIE), done).await;
+        Ok(SPDR.read())
+    }
+
+    // SPI STC vector: SPIF is already cleared by the hardware, SPIE is only enabled while a task waits for it
+    fn spi_on_interrupt() {
+        if SPCR.is_set(SPIE) {
+            SPCR.clear_bits(SPIE);
+            TRANSFER_DONE.with(|done| done.set(true));
+            interrupt::wake(&WAKER);
+        }
+    }
 }
@@ -0,0 +1,166 @@
+// Handle on an initialised SPI peripheral, implementing the embedded-hal SPI traits on top of an `SPI` implementation
+
+use core::marker::PhantomData;
+
+use embedded_hal::blocking;
+use embedded_hal::spi;
+
+use super::{SpiConfig, Word, SPI};
+#[cfg(feature = "async")]
+use super::AsyncSPI;
+use crate::clock::Clocks;
+use crate::{HalError, Result};
+
+// The frames are 8-bit unless the handle is switched to 16-bit words with `into_words::<u16>()`: the embedded-hal
+// traits are only implemented for the word size of the handle, so that untyped integer literals keep resolving to
+// bytes
+pub struct Spi<S: SPI, W: Word = u8> {
+    received: Option<u8>, // Byte clocked in during the last `FullDuplex::send`, not read yet
+    _spi: PhantomData<(S, W)>,
+}
+
+impl<S: SPI> Spi<S> {
+    pub fn init_master(config: SpiConfig, clocks: &Clocks) -> Result<Self> {
+        S::spi_init_master(config, clocks)?;
+        Ok(Self::new())
+    }
+
+    pub fn init_slave(config: SpiConfig) -> Result<Self> {
+        S::spi_init_slave(config)?;
+        Ok(Self::new())
+    }
+
+    // Wraps an SPI peripheral already initialised with `spi_init_master` or `spi_init_slave`
+    pub const fn new() -> Self {
+        Spi { received: None, _spi: PhantomData }
+    }
+}
+
+impl<S: SPI, W: Word> Spi<S, W> {
+    // Same peripheral moving words of another size, e.g. `Spi::init_master(config, &clocks)?.into_words::<u16>()`
+    pub const fn into_words<V: Word>(self) -> Spi<S, V> {
+        Spi { received: self.received, _spi: PhantomData }
+    }
+}
+
+impl<S: SPI> Default for Spi<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// `send` exchanges a whole frame and keeps the received byte for the following `read`, a `read` without a `send`
+// before it clocks out the fill byte
+impl<S: SPI> spi::FullDuplex<u8> for Spi<S> {
+    type Error = HalError;
+
+    fn read(&mut self) -> nb::Result<u8, HalError> {
+        match self.received.take() {
+            Some(word) => Ok(word),
+            None => S::spi_read().map_err(nb::Error::Other),
+        }
+    }
+
+    fn send(&mut self, word: u8) -> nb::Result<(), HalError> {
+        self.received = Some(S::spi_transfer(word).map_err(nb::Error::Other)?);
+        Ok(())
+    }
+}
+
+// The buffer traits are implemented for the word size of the handle, see `spi::Word`
+macro_rules! blocking_impls {
+    ($($word:ty),*) => {$(
+        impl<S: SPI> blocking::spi::Transfer<$word> for Spi<S, $word> {
+            type Error = HalError;
+
+            // Replaces every word of `words` with the word received while it was sent
+            fn transfer<'w>(&mut self, words: &'w mut [$word]) -> Result<&'w [$word]> {
+                <$word>::transfer_in_place::<S>(words)?;
+                Ok(words)
+            }
+        }
+
+        impl<S: SPI> blocking::spi::Write<$word> for Spi<S, $word> {
+            type Error = HalError;
+
+            // The received words are read and dropped so that the receiver never overruns
+            fn write(&mut self, words: &[$word]) -> Result<()> {
+                <$word>::transfer_slices::<S>(&mut [], words)
+            }
+        }
+    )*};
+}
+
+blocking_impls!(u8, u16);
+
+// embedded-hal 1.0 SPI bus, the chip select is handled by `spi::device::ExclusiveDevice`
+#[cfg(feature = "embedded-hal-1")]
+impl<S: SPI, W: Word> embedded_hal_1::spi::ErrorType for Spi<S, W> {
+    type Error = HalError;
+}
+
+#[cfg(feature = "embedded-hal-1")]
+impl<S: SPI, W: Word> embedded_hal_1::spi::SpiBus<W> for Spi<S, W> {
+    // Clocks out the fill byte of the `SpiConfig` for every byte read
+    fn read(&mut self, words: &mut [W]) -> Result<()> {
+        W::transfer_slices::<S>(words, &[])
+    }
+
+    fn write(&mut self, words: &[W]) -> Result<()> {
+        W::transfer_slices::<S>(&mut [], words)
+    }
+
+    // The shorter buffer is padded: the fill byte is sent past the end of `write`, words past the end of `read` are
+    // dropped
+    fn transfer(&mut self, read: &mut [W], write: &[W]) -> Result<()> {
+        W::transfer_slices::<S>(read, write)
+    }
+
+    fn transfer_in_place(&mut self, words: &mut [W]) -> Result<()> {
+        W::transfer_in_place::<S>(words)
+    }
+
+    // Every word has been received when the buffer operations return, nothing is left in flight
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+// embedded-hal-async SPI bus, sleeping in the SPI interrupt between bytes
+#[cfg(feature = "async")]
+impl<S: AsyncSPI> embedded_hal_async::spi::SpiBus<u8> for Spi<S> {
+    async fn read(&mut self, words: &mut [u8]) -> Result<()> {
+        for word in words.iter_mut() {
+            *word = S::spi_transfer_async(S::spi_fill_byte()).await?;
+        }
+        Ok(())
+    }
+
+    async fn write(&mut self, words: &[u8]) -> Result<()> {
+        for &word in words {
+            S::spi_transfer_async(word).await?;
+        }
+        Ok(())
+    }
+
+    async fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<()> {
+        for i in 0..read.len().max(write.len()) {
+            let received = S::spi_transfer_async(write.get(i).copied().unwrap_or(S::spi_fill_byte())).await?;
+            if let Some(word) = read.get_mut(i) {
+                *word = received;
+            }
+        }
+        Ok(())
+    }
+
+    async fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<()> {
+        for word in words.iter_mut() {
+            *word = S::spi_transfer_async(*word).await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
@@ -1,59 +1,253 @@
-use super::SPI;
+use core::cell::Cell;
+use core::task::Waker;
 
-const SPI1_BASE: u32 = 0x40013000u32; // Base address of SPI1 peripheral
-const SPI1_CR1: *mut u32 = (SPI1_BASE + 0x00) as *mut u32; // Control Register 1
-const SPI1_SR: *mut u32 = (SPI1_BASE + 0x08) as *mut u32;  // Status Register
-const SPI1_DR: *mut u32 = (SPI1_BASE + 0x0C) as *mut u32;  // Data Register
+use super::{AsyncSPI, BitOrder, SpiConfig, SPI};
+use crate::clock::Clocks;
+use crate::global::global;
+use crate::interrupt;
+use crate::rcc::{self, Peripheral};
+use crate::reg::{Field, Reg};
+use crate::timeout::{wait_for, wait_until};
+use crate::{HalError, Result};
+
+const SPI1_BASE: usize = 0x4001_3000; // Base address of SPI1 peripheral
+const SPI1_CR1: Reg<u32> = unsafe { Reg::new(SPI1_BASE) };        // Control Register 1
+const SPI1_CR2: Reg<u32> = unsafe { Reg::new(SPI1_BASE + 0x04) };  // Control Register 2
+const SPI1_SR: Reg<u32> = unsafe { Reg::new(SPI1_BASE + 0x08) };  // Status Register
+const SPI1_DR: Reg<u32> = unsafe { Reg::new(SPI1_BASE + 0x0C) };  // Data Register
+
+// CR1 bits
+const CPHA: u32 = 1 << 0;            // Clock phase, data sampled on the second edge when set
+const CPOL: u32 = 1 << 1;            // Clock polarity, SCK idles high when set
+const MSTR: u32 = 1 << 2;            // Master mode (cleared for slave mode)
+const BR: Field = Field::new(3, 3);  // Baud rate control bits
+const SPE: u32 = 1 << 6;             // SPI Enable
+const LSBFIRST: u32 = 1 << 7;        // Frame format, LSB first when set
+const SSI: u32 = 1 << 8;             // Internal slave select, level seen in place of the NSS pin when SSM is set
+const SSM: u32 = 1 << 9;             // Software slave management
+const DFF: u32 = 1 << 11;            // Data frame format, 16-bit frames when set
+
+// CR2 bits
+const RXNEIE: u32 = 1 << 6; // RX buffer Not Empty Interrupt Enable
+const TXEIE: u32 = 1 << 7;  // TX buffer Empty Interrupt Enable
+
+// SR bits
+const RXNE: u32 = 1 << 0; // Receive buffer Not Empty
+const TXE: u32 = 1 << 1;  // Transmit buffer Empty
+const OVR: u32 = 1 << 6;  // Overrun flag
+const BSY: u32 = 1 << 7;  // Busy, a frame is being shifted
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum FrameSize {
+    Bits8,
+    Bits16,
+}
+
+global! { static WAKER: Option<Waker> = None; }
+global! { static FILL_BYTE: u8 = 0x00; }
+global! { static FRAME_SIZE: FrameSize = FrameSize::Bits8; } // Mirrors DFF, so that 8-bit frames do not read CR1
 
 pub struct CortexM3;
 
-impl SPI for CortexM3 {
+// Waits until `flag` is set in SR and returns the status register value
+fn wait_flag(flag: u32) -> Result<u32> {
+    wait_for(|| {
+        let status = SPI1_SR.read();
+        if status & flag != 0 { Some(status) } else { None }
+    })
+}
 
-    // Initializes SPI1 in master mode with a clock prescaler of fPCLK/8
-    fn spi_init_master() {
-        const MASTER_MODE: u32 = 1 << 2;      //Sets SPI to Master mode
-        const SPI_ENABLE: u32 = 1 << 6;       //Enables the SPI
-        const CLOCK_DIV8: u32 = 0b011 << 3;  //Sets baudrate to clockfrequency/8
+// Sets DFF for the frames that follow. DFF can only be changed with SPI1 disabled, which must wait for the last frame
+// to leave the shift register (TXE set, BSY cleared).
+fn set_frame_size(size: FrameSize) -> Result<()> {
+    if FRAME_SIZE.with(|current| current.get()) == size {
+        return Ok(());
+    }
+    wait_until(|| SPI1_SR.read() & (TXE | BSY) == TXE)?;
+    SPI1_CR1.clear_bits(SPE);
+    match size {
+        FrameSize::Bits8 => SPI1_CR1.clear_bits(DFF),
+        FrameSize::Bits16 => SPI1_CR1.set_bits(DFF),
+    }
+    SPI1_CR1.set_bits(SPE);
+    FRAME_SIZE.with(|current| current.set(size));
+    Ok(())
+}
 
-        unsafe {
-            *SPI1_CR1 = MASTER_MODE | CLOCK_DIV8; // Configures SPI1
-            *SPI1_CR1 |= SPI_ENABLE;              // Enables SPI1
-        }
+// Reads the received frame, an overrun means that a previous frame was lost
+fn read_data(status: u32) -> Result<u16> {
+    let data = SPI1_DR.read() as u16;
+    if status & OVR != 0 {
+        let _ = SPI1_SR.read(); // OVR is cleared by reading DR then SR
+        return Err(HalError::Overrun);
     }
+    Ok(data)
+}
+
+// Waits for the frames still in flight after a failed stream and drops their answers, so that the next operation
+// does not find them in DR
+fn drain() -> Result<()> {
+    wait_until(|| SPI1_SR.read() & (TXE | BSY) == TXE)?;
+    let _ = SPI1_DR.read();
+    let _ = SPI1_SR.read(); // Clears an OVR caused by the dropped frames
+    Ok(())
+}
 
-    // Initializes SPI1 in slave mode
-    fn spi_init_slave() {
-        const SLAVE_MODE_MASK: u32 = !(1 << 2); // Clears MSTR bit (bit 2) to set slave mode
-        const SPI_ENABLE: u32 = 1 << 6;        //Enables the SPI
+// Exchanges a single frame
+fn exchange(size: FrameSize, data: u16) -> Result<u16> {
+    set_frame_size(size)?;
+    wait_flag(TXE)?;               // Wait until TXE flag is set
+    SPI1_DR.write(data as u32);    // Write data to be sent
+    let status = wait_flag(RXNE)?; // Wait until RXNE flag is set
+    read_data(status)              // Read and return received data
+}
 
-        unsafe {
-            *SPI1_CR1 &= SLAVE_MODE_MASK;       // Configures SPI1 as slave
-            *SPI1_CR1 |= SPI_ENABLE;             // Enables SPI1
+// Streams `len` frames with up to two of them in flight, one in the shift register and the next one waiting in DR, so
+// that SCK does not pause between frames. Each received frame is read as soon as RXNE is set, before the next one
+// completes and overruns it. A frame received before the stream started (in slave mode) is dropped first, with the
+// overrun flagged for it, if any: it concerns frames older than the stream. An overrun during the stream fails it
+// with `HalError::Overrun` once the frames in flight are drained.
+fn stream(
+    size: FrameSize,
+    len: usize,
+    mut outgoing: impl FnMut(usize) -> u16,
+    mut incoming: impl FnMut(usize, u16),
+) -> Result<()> {
+    set_frame_size(size)?;
+    let (mut sent, mut received) = (0, 0);
+    while received < len {
+        let can_send = sent < len && sent - received < 2;
+        let status = wait_for(|| {
+            let status = SPI1_SR.read();
+            if status & RXNE != 0 || (can_send && status & TXE != 0) { Some(status) } else { None }
+        })?;
+        if status & RXNE != 0 {
+            let data = read_data(status);
+            if received < sent {
+                match data {
+                    Ok(data) => incoming(received, data),
+                    Err(error) => return drain().and(Err(error)),
+                }
+                received += 1;
+            }
+        }
+        if can_send && status & TXE != 0 {
+            SPI1_DR.write(outgoing(sent) as u32);
+            sent += 1;
         }
     }
+    Ok(())
+}
 
-    fn spi_write(data: u8) {
-        unsafe {
-            while *SPI1_SR & (1 << 1) == 0 {}   // Waits until the transmit buffer is empty (until TXE flag is set)
-            *SPI1_DR = data as u32;             // Writes data to the Data Register to start transmission
-        }
+// CPOL, CPHA and LSBFIRST bits of CR1
+fn frame_format(config: SpiConfig) -> u32 {
+    let mut bits = 0;
+    if config.mode.idle_high() {
+        bits |= CPOL;
     }
+    if config.mode.second_edge() {
+        bits |= CPHA;
+    }
+    if config.bit_order == BitOrder::LsbFirst {
+        bits |= LSBFIRST;
+    }
+    bits
+}
 
-    fn spi_read() -> u8 {
-        unsafe {
-            while *SPI1_SR & (1 << 0) == 0 {}   //Waits until there is data in the receive buffer (until RXNE flag is set)
-            *SPI1_DR as u8  //Reads and returns received data from the Data Register
-        }
+impl SPI for CortexM3 {
+
+    // Initializes SPI1 in master mode, with the smallest prescaler (fPCLK2/2^(BR+1)) that keeps SCK under
+    // `config.frequency`. The NSS pin is not used (SSM with SSI high), chip selects are driven as GPIOs.
+    fn spi_init_master(config: SpiConfig, clocks: &Clocks) -> Result<()> {
+        let br = (0..8).find(|&br| clocks.pclk2 >> (br + 1) <= config.frequency).ok_or(HalError::InvalidClock)?;
+        rcc::enable(Peripheral::Spi1);
+        SPI1_CR1.write(MSTR | SSM | SSI | frame_format(config) | BR.val::<u32>(br)); // Configures SPI1, SPE cleared
+        SPI1_CR1.set_bits(SPE);                                                         // Enables SPI1
+        FILL_BYTE.with(|fill| fill.set(config.fill_byte));
+        FRAME_SIZE.with(|size| size.set(FrameSize::Bits8));
+        Ok(())
     }
 
-    // Simultaneously writes and reads data in slave mode
-    fn spi_transfer(data: u8) -> u8 {
-        unsafe {
-            while *SPI1_SR & (1 << 1) == 0 {}  // Wait until TXE flag is set
-            *SPI1_DR = data as u32;            // Write data to be sent
-            while *SPI1_SR & (1 << 0) == 0 {}  // Wait until RXNE flag is set
-            *SPI1_DR as u8                     // Read and return received data
-        }
+    // Initializes SPI1 in slave mode, selected by the NSS pin (SSM cleared), SCK is generated by the master
+    fn spi_init_slave(config: SpiConfig) -> Result<()> {
+        rcc::enable(Peripheral::Spi1);
+        SPI1_CR1.write(frame_format(config)); // Configures SPI1 as slave, SPE cleared
+        SPI1_CR1.set_bits(SPE);               // Enables SPI1
+        FILL_BYTE.with(|fill| fill.set(config.fill_byte));
+        FRAME_SIZE.with(|size| size.set(FrameSize::Bits8));
+        Ok(())
+    }
+
+    // Writing DR starts the frame in master mode, in slave mode the byte waits for the master's SCK
+    fn spi_transfer(data: u8) -> Result<u8> {
+        exchange(FrameSize::Bits8, data as u16).map(|data| data as u8)
+    }
+
+    fn spi_fill_byte() -> u8 {
+        FILL_BYTE.with(|fill| fill.get())
+    }
+
+    fn spi_write_bytes(data: &[u8]) -> Result<()> {
+        stream(FrameSize::Bits8, data.len(), |i| data[i] as u16, |_, _| {})
+    }
+
+    fn spi_read_bytes(buffer: &mut [u8]) -> Result<()> {
+        let fill = Self::spi_fill_byte() as u16;
+        stream(FrameSize::Bits8, buffer.len(), |_| fill, |i, byte| buffer[i] = byte as u8)
+    }
+
+    fn spi_transfer_bytes(read: &mut [u8], write: &[u8]) -> Result<()> {
+        let (len, fill) = (read.len().max(write.len()), Self::spi_fill_byte());
+        stream(FrameSize::Bits8, len, |i| write.get(i).copied().unwrap_or(fill) as u16, |i, byte| {
+            if let Some(slot) = read.get_mut(i) {
+                *slot = byte as u8;
+            }
+        })
+    }
+
+    // Byte i is always sent before the byte received in its place overwrites it
+    fn spi_transfer_in_place(buffer: &mut [u8]) -> Result<()> {
+        let cells = Cell::from_mut(buffer).as_slice_of_cells();
+        stream(FrameSize::Bits8, cells.len(), |i| cells[i].get() as u16, |i, byte| cells[i].set(byte as u8))
+    }
+
+    // DFF is set before the frame and stays set until the next 8-bit operation
+    fn spi_transfer_u16(data: u16) -> Result<u16> {
+        exchange(FrameSize::Bits16, data)
+    }
+
+    fn spi_transfer_words(read: &mut [u16], write: &[u16]) -> Result<()> {
+        let (len, fill) = (read.len().max(write.len()), u16::from_ne_bytes([Self::spi_fill_byte(); 2]));
+        stream(FrameSize::Bits16, len, |i| write.get(i).copied().unwrap_or(fill), |i, word| {
+            if let Some(slot) = read.get_mut(i) {
+                *slot = word;
+            }
+        })
+    }
+
+    fn spi_transfer_words_in_place(buffer: &mut [u16]) -> Result<()> {
+        let cells = Cell::from_mut(buffer).as_slice_of_cells();
+        stream(FrameSize::Bits16, cells.len(), |i| cells[i].get(), |i, word| cells[i].set(word))
+    }
+
+}
+
+impl AsyncSPI for CortexM3 {
+    async fn spi_transfer_async(data: u8) -> Result<u8> {
+        set_frame_size(FrameSize::Bits8)?;
+        interrupt::wait_until(&WAKER, || SPI1_CR2.set_bits(TXEIE), || SPI1_SR.is_set(TXE)).await;
+        SPI1_DR.write(data as u32);
+        let status = interrupt::wait_for(&WAKER, || SPI1_CR2.set_bits(RXNEIE), || {
+            let status = SPI1_SR.read();
+            if status & RXNE != 0 { Some(status) } else { None }
+        })
+        .await;
+        read_data(status).map(|data| data as u8)
+    }
+
+    // SPI1 vector: masks the buffer interrupts and wakes the waiting task, which checks the flags again
+    fn spi_on_interrupt() {
+        SPI1_CR2.clear_bits(TXEIE | RXNEIE);
+        interrupt::wake(&WAKER);
     }
-    
 }
@@ -0,0 +1,238 @@
+// SPI devices with their own chip select
+// `ExclusiveDevice` owns a bus that has a single slave attached. `SpiDevice` borrows a `SharedBus` for the duration
+// of each transaction, so that several slaves with their own chip select and `SpiConfig` share the same peripheral:
+//     static BUS: SharedBus<ActiveSPI> = SharedBus::new(Clocks::single(16_000_000));
+//     let mut flash = SpiDevice::new(&BUS, flash_cs, SpiConfig { mode: SpiMode::Mode3, ..SpiConfig::new(8_000_000) }, delay)?;
+//     let mut dac = SpiDevice::new(&BUS, dac_cs, SpiConfig::new(1_000_000), delay)?.into_words::<u16>();
+// Both devices move bytes unless they are switched to 16-bit words, like `dac` above.
+// The chip select is driven low for the duration of a transaction and driven high again afterwards,
+// even when the transaction fails, so that the slave never stays selected.
+
+use core::cell::Cell;
+use core::marker::PhantomData;
+
+use embedded_hal::digital::v2::OutputPin;
+
+use super::bus::Spi;
+use super::{SpiConfig, Word, SPI};
+#[cfg(feature = "async")]
+use super::AsyncSPI;
+use crate::clock::Clocks;
+use crate::delay::Delay;
+use crate::interrupt;
+use crate::{HalError, Result};
+
+// Runs `f` with the chip select driven low, then drives it high again whatever `f` returned
+fn selected<CS: OutputPin<Error = HalError>, R, F: FnOnce() -> Result<R>>(cs: &mut CS, f: F) -> Result<R> {
+    cs.set_low()?;
+    let result = f();
+    let deselect = cs.set_high();
+    let value = result?;
+    deselect.map(|()| value)
+}
+
+pub struct ExclusiveDevice<S: SPI, CS, W: Word = u8> {
+    bus: Spi<S, W>,
+    cs: CS,
+    delay: Delay,
+}
+
+impl<S: SPI, CS: OutputPin<Error = HalError>, W: Word> ExclusiveDevice<S, CS, W> {
+    // Takes the bus and the chip select pin, the slave is deselected right away
+    pub fn new(bus: Spi<S, W>, mut cs: CS, delay: Delay) -> Result<Self> {
+        cs.set_high()?;
+        Ok(ExclusiveDevice { bus, cs, delay })
+    }
+
+    // Runs `f` with the slave selected
+    pub fn with_selected<R, F: FnOnce(&mut Spi<S, W>, &mut Delay) -> Result<R>>(&mut self, f: F) -> Result<R> {
+        let (bus, delay) = (&mut self.bus, &mut self.delay);
+        selected(&mut self.cs, || f(bus, delay))
+    }
+
+    // Gives the bus and the chip select pin back
+    pub fn release(self) -> (Spi<S, W>, CS) {
+        (self.bus, self.cs)
+    }
+}
+
+// SPI peripheral in master mode shared by several `SpiDevice`s
+// The peripheral is initialised again whenever a transaction needs another `SpiConfig` than the previous one.
+// A transaction started while another one is in progress, i.e. from an interrupt handler that preempted it, fails
+// with `HalError::Busy` instead of interleaving its frames with the other slave's.
+pub struct SharedBus<S: SPI> {
+    in_use: Cell<bool>, // Claimed by the transaction in progress, only tested and set inside `interrupt::free`
+    config: Cell<Option<SpiConfig>>, // Config the peripheral was last initialised with, None before the first transaction
+    clocks: Clocks,
+    _spi: PhantomData<S>,
+}
+
+// Safety: single-core targets, `in_use` is claimed inside `interrupt::free` and `config` is only touched by the
+// context holding the claim. With `host-sim`, where `interrupt::free` masks nothing, a `static` bus must stay on one
+// test thread, like the simulated registers it drives.
+unsafe impl<S: SPI> Sync for SharedBus<S> {}
+
+// Claim on a `SharedBus`, released when dropped
+struct Claim<'a, S: SPI>(&'a SharedBus<S>);
+
+impl<S: SPI> Drop for Claim<'_, S> {
+    fn drop(&mut self) {
+        interrupt::free(|| self.0.in_use.set(false));
+    }
+}
+
+impl<S: SPI> SharedBus<S> {
+    pub const fn new(clocks: Clocks) -> Self {
+        SharedBus { in_use: Cell::new(false), config: Cell::new(None), clocks, _spi: PhantomData }
+    }
+
+    // Claims the bus and sets it up with `config`, it stays claimed until the returned `Claim` is dropped
+    fn acquire(&self, config: SpiConfig) -> Result<Claim<'_, S>> {
+        if interrupt::free(|| self.in_use.replace(true)) {
+            return Err(HalError::Busy);
+        }
+        let claim = Claim(self);
+        if self.config.get() != Some(config) {
+            self.config.set(None); // Left unknown if the init fails halfway
+            S::spi_init_master(config, &self.clocks)?;
+            self.config.set(Some(config));
+        }
+        Ok(claim)
+    }
+}
+
+// Every device has its own `Spi` handle, i.e. a byte kept by `FullDuplex::send` never leaks to another slave
+pub struct SpiDevice<'a, S: SPI, CS, W: Word = u8> {
+    bus: &'a SharedBus<S>,
+    spi: Spi<S, W>,
+    cs: CS,
+    config: SpiConfig,
+    delay: Delay,
+}
+
+impl<'a, S: SPI, CS: OutputPin<Error = HalError>> SpiDevice<'a, S, CS> {
+    // Attaches a slave with its chip select pin and frame format to the bus, the slave is deselected right away
+    pub fn new(bus: &'a SharedBus<S>, mut cs: CS, config: SpiConfig, delay: Delay) -> Result<Self> {
+        cs.set_high()?;
+        Ok(SpiDevice { bus, spi: Spi::new(), cs, config, delay })
+    }
+}
+
+impl<'a, S: SPI, CS: OutputPin<Error = HalError>, W: Word> SpiDevice<'a, S, CS, W> {
+    // Same slave moving words of another size, see `Spi::into_words`
+    pub fn into_words<V: Word>(self) -> SpiDevice<'a, S, CS, V> {
+        let SpiDevice { bus, spi, cs, config, delay } = self;
+        SpiDevice { bus, spi: spi.into_words(), cs, config, delay }
+    }
+
+    // Runs `f` with the bus set up for this slave and the slave selected
+    pub fn with_selected<R, F: FnOnce(&mut Spi<S, W>, &mut Delay) -> Result<R>>(&mut self, f: F) -> Result<R> {
+        let _claim = self.bus.acquire(self.config)?;
+        let (spi, delay) = (&mut self.spi, &mut self.delay);
+        selected(&mut self.cs, || f(spi, delay))
+    }
+
+    pub fn config(&self) -> SpiConfig {
+        self.config
+    }
+
+    // Used from the next transaction on, e.g. to speed SCK up once a card is initialised
+    pub fn set_config(&mut self, config: SpiConfig) {
+        self.config = config;
+    }
+
+    // Gives the chip select pin back
+    pub fn release(self) -> CS {
+        self.cs
+    }
+}
+
+// Runs the operations of an embedded-hal 1.0 transaction on a selected slave
+#[cfg(feature = "embedded-hal-1")]
+fn run_operations<S: SPI, W: Word>(
+    bus: &mut Spi<S, W>,
+    delay: &mut Delay,
+    operations: &mut [embedded_hal_1::spi::Operation<'_, W>],
+) -> Result<()> {
+    use embedded_hal_1::spi::{Operation, SpiBus};
+
+    for operation in operations.iter_mut() {
+        match operation {
+            Operation::Read(words) => bus.read(words)?,
+            Operation::Write(words) => SpiBus::write(bus, words)?,
+            Operation::Transfer(read, write) => SpiBus::transfer(bus, read, write)?,
+            Operation::TransferInPlace(words) => bus.transfer_in_place(words)?,
+            Operation::DelayNs(ns) => delay.delay_ns(*ns),
+        }
+    }
+    SpiBus::<W>::flush(bus)
+}
+
+#[cfg(feature = "embedded-hal-1")]
+impl<S: SPI, CS: OutputPin<Error = HalError>, W: Word> embedded_hal_1::spi::ErrorType for ExclusiveDevice<S, CS, W> {
+    type Error = HalError;
+}
+
+#[cfg(feature = "embedded-hal-1")]
+impl<S: SPI, CS: OutputPin<Error = HalError>, W: Word> embedded_hal_1::spi::SpiDevice<W> for ExclusiveDevice<S, CS, W> {
+    fn transaction(&mut self, operations: &mut [embedded_hal_1::spi::Operation<'_, W>]) -> Result<()> {
+        self.with_selected(|bus, delay| run_operations(bus, delay, operations))
+    }
+}
+
+#[cfg(feature = "embedded-hal-1")]
+impl<S: SPI, CS: OutputPin<Error = HalError>, W: Word> embedded_hal_1::spi::ErrorType for SpiDevice<'_, S, CS, W> {
+    type Error = HalError;
+}
+
+#[cfg(feature = "embedded-hal-1")]
+impl<S: SPI, CS: OutputPin<Error = HalError>, W: Word> embedded_hal_1::spi::SpiDevice<W> for SpiDevice<'_, S, CS, W> {
+    fn transaction(&mut self, operations: &mut [embedded_hal_1::spi::Operation<'_, W>]) -> Result<()> {
+        self.with_selected(|bus, delay| run_operations(bus, delay, operations))
+    }
+}
+
+// Async counterpart of `run_operations`, the chip select is driven by the caller around it and a delay busy-waits
+#[cfg(feature = "async")]
+async fn run_operations_async<S: AsyncSPI>(
+    bus: &mut Spi<S>,
+    delay: &mut Delay,
+    operations: &mut [embedded_hal_1::spi::Operation<'_, u8>],
+) -> Result<()> {
+    use embedded_hal_1::spi::Operation;
+    use embedded_hal_async::spi::SpiBus;
+
+    for operation in operations.iter_mut() {
+        match operation {
+            Operation::Read(words) => bus.read(words).await?,
+            Operation::Write(words) => SpiBus::write(bus, words).await?,
+            Operation::Transfer(read, write) => SpiBus::transfer(bus, read, write).await?,
+            Operation::TransferInPlace(words) => SpiBus::transfer_in_place(bus, words).await?,
+            Operation::DelayNs(ns) => delay.delay_ns(*ns),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "async")]
+impl<S: AsyncSPI, CS: OutputPin<Error = HalError>> embedded_hal_async::spi::SpiDevice<u8> for ExclusiveDevice<S, CS> {
+    async fn transaction(&mut self, operations: &mut [embedded_hal_1::spi::Operation<'_, u8>]) -> Result<()> {
+        self.cs.set_low()?;
+        let result = run_operations_async(&mut self.bus, &mut self.delay, operations).await;
+        let deselect = self.cs.set_high();
+        result.and(deselect)
+    }
+}
+
+// The bus stays borrowed while the transaction is pending: another task using the bus meanwhile gets
+// `HalError::Busy` instead of waiting, there is no queue of tasks to wake
+#[cfg(feature = "async")]
+impl<S: AsyncSPI, CS: OutputPin<Error = HalError>> embedded_hal_async::spi::SpiDevice<u8> for SpiDevice<'_, S, CS> {
+    async fn transaction(&mut self, operations: &mut [embedded_hal_1::spi::Operation<'_, u8>]) -> Result<()> {
+        let _claim = self.bus.acquire(self.config)?;
+        self.cs.set_low()?;
+        let result = run_operations_async(&mut self.spi, &mut self.delay, operations).await;
+        let deselect = self.cs.set_high();
+        result.and(deselect)
+    }
+}
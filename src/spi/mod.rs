@@ -1,39 +1,268 @@
 pub mod atmega328p;
+pub mod bus;
 pub mod cortex_m3;
+pub mod device;
 
+use core::future::Future;
+
+use crate::clock::Clocks;
+use crate::Result;
+
+// SCK frequency of `SpiConfig::default()`, slow enough for most SPI sensors and memories
+pub const DEFAULT_SCK_HZ: u32 = 1_000_000;
+
+// Clock polarity (CPOL, level of SCK between frames) and phase (CPHA, edge on which data is sampled)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpiMode {
+    Mode0, // SCK idles low, data sampled on the rising edge
+    Mode1, // SCK idles low, data sampled on the falling edge
+    Mode2, // SCK idles high, data sampled on the falling edge
+    Mode3, // SCK idles high, data sampled on the rising edge
+}
+
+impl SpiMode {
+    // CPOL: SCK idles high
+    pub const fn idle_high(self) -> bool {
+        matches!(self, SpiMode::Mode2 | SpiMode::Mode3)
+    }
+
+    // CPHA: data is sampled on the second edge of SCK
+    pub const fn second_edge(self) -> bool {
+        matches!(self, SpiMode::Mode1 | SpiMode::Mode3)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BitOrder {
+    MsbFirst,
+    LsbFirst,
+}
+
+// Frame format and SCK frequency of an SPI bus, e.g. a flash chip: `SpiConfig { mode: SpiMode::Mode3, ..SpiConfig::new(8_000_000) }`
+// `frequency` is an upper bound: the master picks the prescaler giving the fastest SCK that does not exceed it, and
+// refuses a frequency below the slowest SCK of the peripheral. A slave ignores it, SCK comes from the master.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SpiConfig {
+    pub frequency: u32,
+    pub mode: SpiMode,
+    pub bit_order: BitOrder,
+    pub fill_byte: u8, // Sent by `spi_read` and the buffer reads, e.g. 0xFF for SD cards
+}
+
+impl SpiConfig {
+    // Mode 0, MSB first, SCK at most `frequency`, reads send 0x00
+    pub const fn new(frequency: u32) -> Self {
+        SpiConfig { frequency, mode: SpiMode::Mode0, bit_order: BitOrder::MsbFirst, fill_byte: 0x00 }
+    }
+}
+
+impl Default for SpiConfig {
+    fn default() -> Self {
+        SpiConfig::new(DEFAULT_SCK_HZ)
+    }
+}
+
+// Every byte moved over SPI is an exchange: a frame shifts one byte out and one byte in at the same time. A master
+// generates SCK for the frame, a slave loads its byte and waits for the master to clock it. `spi_write` and
+// `spi_read` are both full frames, so that a byte received during a write is never mistaken for the answer of a read.
 pub trait SPI {
-    fn spi_init_master();
-    fn spi_init_slave();
-    fn spi_write(data: u8);
-    fn spi_read() -> u8;
-    fn spi_transfer(data: u8) -> u8 {
-        Self::spi_write(data);
-        Self::spi_read()
+    fn spi_init_master(config: SpiConfig, clocks: &Clocks) -> Result<()>;
+    fn spi_init_slave(config: SpiConfig) -> Result<()>;
+
+    // Sends `data` and returns the byte received meanwhile
+    fn spi_transfer(data: u8) -> Result<u8>;
+
+    // `fill_byte` of the `SpiConfig` the peripheral was initialised with
+    fn spi_fill_byte() -> u8;
+
+    // Sends `data`, the byte received meanwhile is dropped
+    fn spi_write(data: u8) -> Result<()> {
+        Self::spi_transfer(data).map(|_| ())
+    }
+
+    // Sends the fill byte and returns the byte received meanwhile
+    fn spi_read() -> Result<u8> {
+        Self::spi_transfer(Self::spi_fill_byte())
+    }
+
+    // Buffer variants of `spi_transfer`, one byte at a time unless the backend can stream them
+
+    // Sends `data`, the bytes received meanwhile are dropped
+    fn spi_write_bytes(data: &[u8]) -> Result<()> {
+        for &byte in data {
+            Self::spi_transfer(byte)?;
+        }
+        Ok(())
+    }
+
+    // Fills `buffer` with the bytes received while sending the fill byte
+    fn spi_read_bytes(buffer: &mut [u8]) -> Result<()> {
+        for byte in buffer.iter_mut() {
+            *byte = Self::spi_read()?;
+        }
+        Ok(())
+    }
+
+    // Sends `write` while receiving into `read`, the shorter buffer is padded: the fill byte is sent past the end of
+    // `write`, bytes past the end of `read` are dropped
+    fn spi_transfer_bytes(read: &mut [u8], write: &[u8]) -> Result<()> {
+        for i in 0..read.len().max(write.len()) {
+            let received = Self::spi_transfer(write.get(i).copied().unwrap_or(Self::spi_fill_byte()))?;
+            if let Some(byte) = read.get_mut(i) {
+                *byte = received;
+            }
+        }
+        Ok(())
+    }
+
+    // Replaces every byte of `buffer` with the byte received while it was sent
+    fn spi_transfer_in_place(buffer: &mut [u8]) -> Result<()> {
+        for byte in buffer.iter_mut() {
+            *byte = Self::spi_transfer(*byte)?;
+        }
+        Ok(())
+    }
+
+    // 16-bit frames, shifted in the bit order of the `SpiConfig`: a native frame on the Cortex-M3, two byte frames
+    // (most significant byte first unless the config is LSB first) on the Atmega328p
+    fn spi_transfer_u16(data: u16) -> Result<u16>;
+
+    // 16-bit variant of `spi_transfer_bytes`, the fill byte is sent in both halves of the padding words
+    fn spi_transfer_words(read: &mut [u16], write: &[u16]) -> Result<()> {
+        let fill = u16::from_ne_bytes([Self::spi_fill_byte(); 2]);
+        for i in 0..read.len().max(write.len()) {
+            let received = Self::spi_transfer_u16(write.get(i).copied().unwrap_or(fill))?;
+            if let Some(word) = read.get_mut(i) {
+                *word = received;
+            }
+        }
+        Ok(())
+    }
+
+    // 16-bit variant of `spi_transfer_in_place`
+    fn spi_transfer_words_in_place(buffer: &mut [u16]) -> Result<()> {
+        for word in buffer.iter_mut() {
+            *word = Self::spi_transfer_u16(*word)?;
+        }
+        Ok(())
+    }
+}
+
+// Frame size of the word-generic operations (`Spi<S>` implements the embedded-hal buffer traits for both):
+// `u8` goes through the byte operations of `SPI`, `u16` through the 16-bit ones
+pub trait Word: Copy + 'static {
+    fn transfer<S: SPI>(self) -> Result<Self>;
+    fn transfer_slices<S: SPI>(read: &mut [Self], write: &[Self]) -> Result<()>;
+    fn transfer_in_place<S: SPI>(buffer: &mut [Self]) -> Result<()>;
+}
+
+impl Word for u8 {
+    fn transfer<S: SPI>(self) -> Result<u8> {
+        S::spi_transfer(self)
+    }
+
+    fn transfer_slices<S: SPI>(read: &mut [u8], write: &[u8]) -> Result<()> {
+        S::spi_transfer_bytes(read, write)
+    }
+
+    fn transfer_in_place<S: SPI>(buffer: &mut [u8]) -> Result<()> {
+        S::spi_transfer_in_place(buffer)
     }
 }
 
+impl Word for u16 {
+    fn transfer<S: SPI>(self) -> Result<u16> {
+        S::spi_transfer_u16(self)
+    }
+
+    fn transfer_slices<S: SPI>(read: &mut [u16], write: &[u16]) -> Result<()> {
+        S::spi_transfer_words(read, write)
+    }
+
+    fn transfer_in_place<S: SPI>(buffer: &mut [u16]) -> Result<()> {
+        S::spi_transfer_words_in_place(buffer)
+    }
+}
+
+// Interrupt-driven variant of `spi_transfer`, the task sleeps until the peripheral interrupt wakes it
+// `spi_on_interrupt` must be called from the SPI interrupt vector of the chip
+pub trait AsyncSPI: SPI {
+    fn spi_transfer_async(data: u8) -> impl Future<Output = Result<u8>>;
+    fn spi_on_interrupt();
+}
+
 #[cfg(feature = "atmega328p")]
 pub type ActiveSPI = atmega328p::Atmega328p;
 
 #[cfg(feature = "cortex_m3")]
 pub type ActiveSPI = cortex_m3::CortexM3;
 
-pub fn spi_init_master() {
-    ActiveSPI::spi_init_master();
+#[cfg(any(feature = "atmega328p", feature = "cortex_m3"))]
+pub fn spi_init_master(config: SpiConfig, clocks: &Clocks) -> Result<()> {
+    ActiveSPI::spi_init_master(config, clocks)
 }
 
-pub fn spi_init_slave() {
-    ActiveSPI::spi_init_slave();
+#[cfg(any(feature = "atmega328p", feature = "cortex_m3"))]
+pub fn spi_init_slave(config: SpiConfig) -> Result<()> {
+    ActiveSPI::spi_init_slave(config)
 }
 
-pub fn spi_write(data: u8) {
-    ActiveSPI::spi_write(data);
+#[cfg(any(feature = "atmega328p", feature = "cortex_m3"))]
+pub fn spi_write(data: u8) -> Result<()> {
+    ActiveSPI::spi_write(data)
 }
 
-pub fn spi_read() -> u8 {
+#[cfg(any(feature = "atmega328p", feature = "cortex_m3"))]
+pub fn spi_read() -> Result<u8> {
     ActiveSPI::spi_read()
 }
 
-pub fn spi_transfer(data: u8) -> u8 {
+#[cfg(any(feature = "atmega328p", feature = "cortex_m3"))]
+pub fn spi_transfer(data: u8) -> Result<u8> {
     ActiveSPI::spi_transfer(data)
-}
\ No newline at end of file
+}
+
+#[cfg(any(feature = "atmega328p", feature = "cortex_m3"))]
+pub fn spi_write_bytes(data: &[u8]) -> Result<()> {
+    ActiveSPI::spi_write_bytes(data)
+}
+
+#[cfg(any(feature = "atmega328p", feature = "cortex_m3"))]
+pub fn spi_read_bytes(buffer: &mut [u8]) -> Result<()> {
+    ActiveSPI::spi_read_bytes(buffer)
+}
+
+#[cfg(any(feature = "atmega328p", feature = "cortex_m3"))]
+pub fn spi_transfer_bytes(read: &mut [u8], write: &[u8]) -> Result<()> {
+    ActiveSPI::spi_transfer_bytes(read, write)
+}
+
+#[cfg(any(feature = "atmega328p", feature = "cortex_m3"))]
+pub fn spi_transfer_in_place(buffer: &mut [u8]) -> Result<()> {
+    ActiveSPI::spi_transfer_in_place(buffer)
+}
+
+#[cfg(any(feature = "atmega328p", feature = "cortex_m3"))]
+pub fn spi_transfer_u16(data: u16) -> Result<u16> {
+    ActiveSPI::spi_transfer_u16(data)
+}
+
+#[cfg(any(feature = "atmega328p", feature = "cortex_m3"))]
+pub fn spi_transfer_words(read: &mut [u16], write: &[u16]) -> Result<()> {
+    ActiveSPI::spi_transfer_words(read, write)
+}
+
+#[cfg(any(feature = "atmega328p", feature = "cortex_m3"))]
+pub fn spi_transfer_words_in_place(buffer: &mut [u16]) -> Result<()> {
+    ActiveSPI::spi_transfer_words_in_place(buffer)
+}
+
+#[cfg(any(feature = "atmega328p", feature = "cortex_m3"))]
+pub async fn spi_transfer_async(data: u8) -> Result<u8> {
+    ActiveSPI::spi_transfer_async(data).await
+}
+
+#[cfg(any(feature = "atmega328p", feature = "cortex_m3"))]
+pub fn spi_on_interrupt() {
+    ActiveSPI::spi_on_interrupt()
+}
@@ -0,0 +1,63 @@
+// Bounded busy-waiting for the blocking operations of the USART, SPI and I2C drivers
+// Every loop waiting on a status flag goes through `wait_until`/`wait_for`, which give up with
+// `HalError::Timeout` once the active policy expires instead of spinning forever on a stuck bus.
+
+use crate::global::global;
+use crate::{HalError, Result};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Timeout {
+    Never,           // Waits forever
+    Iterations(u32), // Gives up after polling the flag that many times
+    Ticks(u32),      // Gives up once that many ticks of the time source have elapsed
+}
+
+// Free-running tick counter (e.g. SysTick or a hardware timer), allowed to wrap around
+pub type TimeSource = fn() -> u32;
+
+// Policy used until `set_timeout` is called, comfortably longer than one byte at 300 baud on both chips
+pub const DEFAULT_TIMEOUT: Timeout = Timeout::Iterations(1_000_000);
+
+global! { static POLICY: Timeout = DEFAULT_TIMEOUT; }
+global! { static TIME_SOURCE: Option<TimeSource> = None; }
+
+pub fn set_timeout(timeout: Timeout) {
+    POLICY.with(|policy| policy.set(timeout));
+}
+
+pub fn timeout() -> Timeout {
+    POLICY.with(|policy| policy.get())
+}
+
+// Installs the time source used by `Timeout::Ticks`
+// Without one, tick-based timeouts count iterations instead
+pub fn set_time_source(source: TimeSource) {
+    TIME_SOURCE.with(|time_source| time_source.set(Some(source)));
+}
+
+// Polls `ready` until it returns a value or the active policy expires
+pub fn wait_for<T, F: FnMut() -> Option<T>>(mut ready: F) -> Result<T> {
+    let policy = timeout();
+    let time_source = TIME_SOURCE.with(|time_source| time_source.get());
+    let start = time_source.map(|now| now());
+    let mut iterations: u32 = 0;
+    loop {
+        if let Some(value) = ready() {
+            return Ok(value);
+        }
+        iterations = iterations.wrapping_add(1);
+        let expired = match (policy, time_source, start) {
+            (Timeout::Never, _, _) => false,
+            (Timeout::Ticks(ticks), Some(now), Some(start)) => now().wrapping_sub(start) >= ticks,
+            (Timeout::Iterations(limit), _, _) | (Timeout::Ticks(limit), _, _) => iterations >= limit,
+        };
+        if expired {
+            return Err(HalError::Timeout);
+        }
+    }
+}
+
+// Polls `condition` until it is true or the active policy expires
+pub fn wait_until<F: FnMut() -> bool>(mut condition: F) -> Result<()> {
+    wait_for(|| if condition() { Some(()) } else { None })
+}
@@ -1,43 +1,199 @@
-use super::USART;
+use core::task::Waker;
 
-const UBRR0H: *mut u8 = 0xC5 as *mut u8;    // High byte of the baud rate register
-const UBRR0L: *mut u8 = 0xC4 as *mut u8;    // Low byte of the baud rate register
-const UCSR0A: *mut u8 = 0xC0 as *mut u8;    // USART Control and Status Register A: contains the status of the USART
-const UCSR0B: *mut u8 = 0xC1 as *mut u8;    // USART Control and Status Register B: activates or deactivates transmission and reception
-const UCSR0C: *mut u8 = 0xC2 as *mut u8;    // USART Control and Status Register C: configures the frame format
-const UDR0: *mut u8 = 0xC6 as *mut u8;      // USART I/O Data Register
+use super::{baud, check_receive_errors, AsyncUSART, BufferedUSART, DataBits, Event, Parity, StopBits, UsartConfig, UsartErrors, USART};
+use crate::clock::Clocks;
+use crate::global::global;
+use crate::interrupt;
+use crate::reg::{Field, Reg};
+use crate::timeout::{wait_for, wait_until};
+use crate::{HalError, Result};
 
-const TX_ENABLE: u8 = 1 << 3; // Transmitter Enable (bit 3 of UCSR0B)
-const RX_ENABLE: u8 = 1 << 4; // Receiver Enable (bit 4 of UCSR0B)
-const FRAME_FORMAT: u8 = (1 << 1) | (1 << 2); // 8 data bits, 1 stop bit
+const UBRR0H: Reg<u8> = unsafe { Reg::new(0xC5) };    // High byte of the baud rate register
+const UBRR0L: Reg<u8> = unsafe { Reg::new(0xC4) };    // Low byte of the baud rate register
+const UCSR0A: Reg<u8> = unsafe { Reg::new(0xC0) };    // USART Control and Status Register A: contains the status of the USART
+const UCSR0B: Reg<u8> = unsafe { Reg::new(0xC1) };    // USART Control and Status Register B: activates or deactivates transmission and reception
+const UCSR0C: Reg<u8> = unsafe { Reg::new(0xC2) };    // USART Control and Status Register C: configures the frame format
+const UDR0: Reg<u8> = unsafe { Reg::new(0xC6) };      // USART I/O Data Register
+
+// UCSR0A bits
+const RXC0: u8 = 1 << 7;  // Receive Complete
+const TXC0: u8 = 1 << 6;  // Transmit Complete (cleared by writing a one)
+const UDRE0: u8 = 1 << 5; // Data Register Empty (ready to emit)
+const FE0: u8 = 1 << 4;   // Frame Error
+const DOR0: u8 = 1 << 3;  // Data OverRun
+const UPE0: u8 = 1 << 2;  // USART Parity Error
+const U2X0: u8 = 1 << 1;  // Double the USART Transmission Speed
+const MPCM0: u8 = 1 << 0; // Multi-processor Communication Mode
+
+// Receive error flags, most serious first. They describe the byte at the head of the receive buffer: they must be
+// read before UDR0, and reading UDR0 clears them
+const RX_ERRORS: [(u8, HalError); 3] = [(FE0, HalError::Framing), (UPE0, HalError::Parity), (DOR0, HalError::Overrun)];
+
+// UCSR0B bits
+const RXCIE0: u8 = 1 << 7; // RX Complete Interrupt Enable
+const UDRIE0: u8 = 1 << 5; // Data Register Empty Interrupt Enable
+const RXEN0: u8 = 1 << 4; // Receiver Enable
+const TXEN0: u8 = 1 << 3; // Transmitter Enable
+
+// UCSR0C fields
+const UPM0: Field = Field::new(4, 2);  // Parity mode: 0b00 none, 0b10 even, 0b11 odd
+const USBS0: u8 = 1 << 3;              // Stop bit select: 2 stop bits when set
+const UCSZ0: Field = Field::new(1, 2); // Character size bits 1:0: 0b011 for 8 data bits, UCSZ02 in UCSR0B stays clear
+
+// TXC0 is only set once a transmission completes, a flush without anything sent would never see it
+global! { static TX_PENDING: bool = false; }
+
+global! { static ERRORS: UsartErrors = UsartErrors::new(); }
+
+// Tasks waiting for the transmitter (UDRE0) and for the receiver (RXC0)
+global! { static TX_WAKER: Option<Waker> = None; }
+global! { static RX_WAKER: Option<Waker> = None; }
 
 pub struct Atmega328p;
 
 impl USART for Atmega328p {
     // Initializes the USART with the given baud rate and frame format, enabling transmission and reception
-    fn usart_init(baud_rate: u32) {
-        let ubrr_value = (16_000_000 / (16 * baud_rate) - 1) as u16; // Calculate baud rate value
-        unsafe {
-            *UBRR0H = (ubrr_value >> 8) as u8;  // Sets high byte of UBRR
-            *UBRR0L = ubrr_value as u8;         // Sets low byte of UBRR
-            *UCSR0B = TX_ENABLE | RX_ENABLE;    
-            *UCSR0C = FRAME_FORMAT;             
-        }
+    // The USART has no 1.5 stop bit mode, and 9 data bits would need RXB80/TXB80 that `usart_read`/`usart_write` lack
+    fn usart_init(config: UsartConfig, clocks: &Clocks) -> Result<()> {
+        let ucsz = match config.data_bits {
+            DataBits::Five => 0b000,
+            DataBits::Six => 0b001,
+            DataBits::Seven => 0b010,
+            DataBits::Eight => 0b011,
+            DataBits::Nine => return Err(HalError::InvalidConfig),
+        };
+        let upm = match config.parity {
+            Parity::None => 0b00,
+            Parity::Even => 0b10,
+            Parity::Odd => 0b11,
+        };
+        let usbs = match config.stop_bits {
+            StopBits::One => 0,
+            StopBits::Two => USBS0,
+            StopBits::OneAndHalf => return Err(HalError::InvalidConfig),
+        };
+
+        let baud = baud::atmega328p(clocks.sysclk, config.baud_rate, baud::MAX_ERROR_PPM)?;
+        UCSR0A.write(if baud.double_speed { U2X0 } else { 0 }); // The error flags read as zero, TXC0 is left alone
+        UBRR0H.write((baud.divisor >> 8) as u8);  // Sets high byte of UBRR
+        UBRR0L.write(baud.divisor as u8);         // Sets low byte of UBRR
+        UCSR0B.write(TXEN0 | RXEN0);
+        UCSR0C.write(UPM0.val::<u8>(upm) | usbs | UCSZ0.val::<u8>(ucsz));
+        Ok(())
     }
 
     // Waits until the transmit buffer is ready to emit data, then sends the data
-    fn usart_write(data: u8) {
-        unsafe {
-            while *UCSR0A & (1 << 5) == 0 {} // Wait for transmit buffer bit to be set to 1 (ready to emit)
-            *UDR0 = data; // Data is written into the buffer to be sent
-        }
+    fn usart_write(data: u8) -> Result<()> {
+        wait_until(|| UCSR0A.is_set(UDRE0))?; // Wait for transmit buffer bit to be set to 1 (ready to emit)
+        clear_tx_complete();
+        UDR0.write(data); // Data is written into the buffer to be sent
+        TX_PENDING.with(|pending| pending.set(true));
+        Ok(())
     }
 
     // Waits until data is received, then reads the data from the receive buffer
-    fn usart_read() -> u8 {
-        unsafe {
-            while *UCSR0A & (1 << 7) == 0 {} // if *UCSR0A == 1, data was received
-            *UDR0 // Reads data from the receive buffer
+    fn usart_read() -> Result<u8> {
+        let status = wait_for(rx_status)?;
+        receive(status)
+    }
+
+    // Waits until the last written byte has been shifted out (TXC0)
+    fn usart_flush() -> Result<()> {
+        if TX_PENDING.with(|pending| pending.get()) {
+            wait_until(|| UCSR0A.is_set(TXC0))?;
+            TX_PENDING.with(|pending| pending.set(false));
+        }
+        Ok(())
+    }
+
+    fn usart_errors() -> UsartErrors {
+        interrupt::free(|| ERRORS.with(|errors| errors.get()))
+    }
+
+    fn usart_clear_errors() {
+        interrupt::free(|| ERRORS.with(|errors| errors.set(UsartErrors::new())));
+    }
+}
+
+// Clears the completion of the previous byte
+// The error flags must be written as zero, a read-modify-write of UCSR0A would write back the ones that are set
+fn clear_tx_complete() {
+    UCSR0A.write((UCSR0A.read() & (U2X0 | MPCM0)) | TXC0);
+}
+
+// UCSR0A value once data was received (RXC0 set), with the error flags of the received byte
+fn rx_status() -> Option<u8> {
+    let status = UCSR0A.read();
+    (status & RXC0 != 0).then_some(status)
+}
+
+// Takes the byte at the head of the receive buffer, `status` is the UCSR0A value read before it
+fn receive(status: u8) -> Result<u8> {
+    let data = UDR0.read(); // Reading data from the receive buffer also clears FE0, DOR0 and UPE0
+    check_receive_errors(&ERRORS, status, &RX_ERRORS)?;
+    Ok(data)
+}
+
+impl AsyncUSART for Atmega328p {
+    async fn usart_write_async(data: u8) -> Result<()> {
+        interrupt::wait_until(&TX_WAKER, || UCSR0B.set_bits(UDRIE0), || UCSR0A.is_set(UDRE0)).await;
+        clear_tx_complete();
+        UDR0.write(data);
+        TX_PENDING.with(|pending| pending.set(true));
+        Ok(())
+    }
+
+    async fn usart_read_async() -> Result<u8> {
+        let status = interrupt::wait_for(&RX_WAKER, || UCSR0B.set_bits(RXCIE0), rx_status).await;
+        receive(status)
+    }
+
+    // The TX complete interrupt clears TXC0 on its own and cannot be told apart from the other USART vectors:
+    // the task sleeps until the last byte moved to the shift register, then polls TXC0 for at most one frame
+    async fn usart_flush_async() -> Result<()> {
+        if TX_PENDING.with(|pending| pending.get()) {
+            interrupt::wait_until(&TX_WAKER, || UCSR0B.set_bits(UDRIE0), || UCSR0A.is_set(UDRE0)).await;
+            Self::usart_flush()?;
         }
+        Ok(())
+    }
+
+    // USART_RX and USART_UDRE vectors: masks the interrupts whose flag is set and wakes their task
+    fn usart_on_interrupt() {
+        let status = UCSR0A.read();
+        let fired = [(RXC0, RXCIE0), (UDRE0, UDRIE0)]
+            .iter()
+            .filter(|&&(flag, _)| status & flag != 0)
+            .fold(0, |mask, &(_, enable)| mask | enable)
+            & UCSR0B.read();
+        UCSR0B.clear_bits(fired);
+        if fired & UDRIE0 != 0 {
+            interrupt::wake(&TX_WAKER);
+        }
+        if fired & RXCIE0 != 0 {
+            interrupt::wake(&RX_WAKER);
+        }
+    }
+}
+
+impl BufferedUSART for Atmega328p {
+    fn usart_listen(event: Event, enable: bool) {
+        let mask = match event {
+            Event::RxNotEmpty => RXCIE0,
+            Event::TxEmpty => UDRIE0,
+        };
+        if enable {
+            UCSR0B.set_bits(mask);
+        } else {
+            UCSR0B.clear_bits(mask);
+        }
+    }
+
+    fn usart_rx_ready() -> bool {
+        UCSR0A.is_set(RXC0)
+    }
+
+    fn usart_tx_ready() -> bool {
+        UCSR0A.is_set(UDRE0)
     }
 }
@@ -0,0 +1,88 @@
+// Baud rate divisors of both USART backends
+// The divisors are rounded to the nearest achievable rate and the result carries the baud rate actually produced,
+// so that a rate the clock cannot approach closely enough is refused at init instead of garbling every frame.
+// The functions are plain arithmetic on the clock frequency and can be used (and tested) without a chip.
+
+use crate::{HalError, Result};
+
+// Largest accepted error between the requested and the achieved baud rate, in parts per million (2.5 %)
+// Both ends of an 8N1 link together must stay within about 4.5 %, this leaves the other end about the same share
+pub const MAX_ERROR_PPM: u32 = 25_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BaudSetting {
+    pub divisor: u16,       // UBRR0 on the Atmega328p, BRR (mantissa << 4 | fraction) on the Cortex-M3
+    pub double_speed: bool, // U2X0 on the Atmega328p, always false on the Cortex-M3
+    pub achieved: u32,      // Baud rate produced by the divisor, rounded to the nearest integer
+    pub error_ppm: i32,     // (achieved - requested) / requested in parts per million, positive when too fast
+}
+
+impl BaudSetting {
+    pub fn error_percent(&self) -> f32 {
+        self.error_ppm as f32 / 10_000.0
+    }
+
+    // Divider closest to clock / (samples * baud_rate) and the error of the rate it produces, None if out of `range`
+    fn nearest(clock: u32, baud_rate: u32, samples: u64, range: (u64, u64)) -> Option<(u64, i32)> {
+        let (clock, baud_rate) = (clock as u64, baud_rate as u64);
+        let value = rounded_div(clock, samples * baud_rate);
+        if !(range.0..=range.1).contains(&value) {
+            return None;
+        }
+        let error_ppm = rounded_div(clock * 1_000_000, samples * value * baud_rate) as i64 - 1_000_000;
+        Some((value, error_ppm as i32))
+    }
+
+    fn checked(self, max_error_ppm: u32) -> Result<Self> {
+        if self.error_ppm.unsigned_abs() > max_error_ppm {
+            return Err(HalError::InvalidBaud);
+        }
+        Ok(self)
+    }
+}
+
+fn rounded_div(numerator: u64, denominator: u64) -> u64 {
+    (numerator + denominator / 2) / denominator
+}
+
+// UBRR0 and U2X0 for `baud_rate` with a CPU clock of `f_cpu`
+// Normal speed samples each bit 16 times (baud = f_CPU / (16 * (UBRR0 + 1))), double speed only 8 times: it is
+// picked only when it gets strictly closer to the requested rate, e.g. 115200 baud at 16 MHz (+2.1 % instead of -3.5 %)
+pub fn atmega328p(f_cpu: u32, baud_rate: u32, max_error_ppm: u32) -> Result<BaudSetting> {
+    if baud_rate == 0 {
+        return Err(HalError::InvalidBaud);
+    }
+    let setting = |samples: u64| {
+        BaudSetting::nearest(f_cpu, baud_rate, samples, (1, 0x1000)).map(|(ubrr, error_ppm)| BaudSetting {
+            divisor: (ubrr - 1) as u16, // UBRR0 is 12 bits wide
+            double_speed: samples == 8,
+            achieved: rounded_div(f_cpu as u64, samples * ubrr) as u32,
+            error_ppm,
+        })
+    };
+    let best = match (setting(16), setting(8)) {
+        (Some(normal), Some(double)) if double.error_ppm.unsigned_abs() < normal.error_ppm.unsigned_abs() => double,
+        (Some(normal), _) => normal,
+        (None, Some(double)) => double,
+        (None, None) => return Err(HalError::InvalidBaud),
+    };
+    best.checked(max_error_ppm)
+}
+
+// BRR for `baud_rate` with a peripheral clock of `pclk` and 16 samples per bit
+// USARTDIV = pclk / (16 * baud) is a 12.4 fixed-point number: the 12-bit mantissa and the 4-bit fraction (USARTDIV * 16
+// rounded) together are pclk / baud, a fraction rounding up to 16 carries into the mantissa
+pub fn cortex_m3(pclk: u32, baud_rate: u32, max_error_ppm: u32) -> Result<BaudSetting> {
+    if baud_rate == 0 {
+        return Err(HalError::InvalidBaud);
+    }
+    let (usartdiv, error_ppm) =
+        BaudSetting::nearest(pclk, baud_rate, 1, (0x10, 0xFFFF)).ok_or(HalError::InvalidBaud)?; // Mantissa >= 1
+    BaudSetting {
+        divisor: usartdiv as u16,
+        double_speed: false,
+        achieved: rounded_div(pclk as u64, usartdiv) as u32,
+        error_ppm,
+    }
+    .checked(max_error_ppm)
+}
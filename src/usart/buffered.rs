@@ -0,0 +1,120 @@
+// Interrupt-driven USART with a receive and a transmit queue
+// The interrupt handler moves received bytes into the RX queue and feeds the data register from the TX queue, so
+// bytes are not lost while the main program is busy and writes return as soon as they are queued. The queue sizes
+// are chosen with the const parameters, a queue of N slots holds N - 1 bytes:
+//     static SERIAL: BufferedSerial<CortexM3, 64, 64> = BufferedSerial::new();
+// `on_interrupt` must be called from the USART vector(s) (`USART_RX` and `USART_UDRE` on the Atmega328p, `USARTx` of the
+// instance on the Cortex-M3), in place of `usart_on_interrupt`. Reads and writes are made from the main program only.
+
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use super::ring::RingBuffer;
+use super::{BufferedUSART, Event, UsartConfig};
+use crate::clock::Clocks;
+use crate::interrupt;
+use crate::timeout::{wait_for, wait_until};
+use crate::{HalError, Result};
+
+// Errors that can cause a received byte to be dropped
+const RX_ERRORS: [HalError; 4] = [HalError::Overrun, HalError::Framing, HalError::Parity, HalError::Noise];
+
+pub struct BufferedSerial<U: BufferedUSART, const RX: usize, const TX: usize> {
+    rx: RingBuffer<RX>,
+    tx: RingBuffer<TX>,
+    rx_error: AtomicU8, // Latest receive error not reported yet, as a position in `RX_ERRORS` plus one (0: none)
+    _usart: PhantomData<U>,
+}
+
+impl<U: BufferedUSART, const RX: usize, const TX: usize> BufferedSerial<U, RX, TX> {
+    pub const fn new() -> Self {
+        BufferedSerial {
+            rx: RingBuffer::new(),
+            tx: RingBuffer::new(),
+            rx_error: AtomicU8::new(0),
+            _usart: PhantomData,
+        }
+    }
+
+    // Initialises the USART and starts receiving in the interrupt handler
+    pub fn init(&self, config: UsartConfig, clocks: &Clocks) -> Result<()> {
+        U::usart_init(config, clocks)?;
+        interrupt::free(|| U::usart_listen(Event::RxNotEmpty, true));
+        Ok(())
+    }
+
+    // Number of received bytes waiting in the RX queue
+    pub fn available(&self) -> usize {
+        self.rx.len()
+    }
+
+    // Next received byte, `None` if the RX queue is empty
+    // Once bytes were dropped, because the queue was full (`HalError::Overrun`) or because the USART flagged them
+    // (see `USART::usart_errors`), the next call reports the latest cause instead
+    pub fn try_read(&self) -> Result<Option<u8>> {
+        match self.rx_error.swap(0, Ordering::Relaxed) {
+            0 => Ok(self.rx.pop()),
+            code => Err(RX_ERRORS[code as usize - 1]),
+        }
+    }
+
+    // Waits (bounded by the timeout policy) for the next received byte
+    pub fn read(&self) -> Result<u8> {
+        wait_for(|| self.try_read().transpose())?
+    }
+
+    // Queues as many bytes of `data` as fit in the TX queue and returns their number
+    pub fn try_write(&self, data: &[u8]) -> usize {
+        let queued = data.iter().take_while(|&&byte| self.tx.push(byte)).count();
+        if queued > 0 {
+            interrupt::free(|| U::usart_listen(Event::TxEmpty, true));
+        }
+        queued
+    }
+
+    // Queues every byte of `data`, waiting (bounded by the timeout policy) for room in the TX queue
+    pub fn write(&self, data: &[u8]) -> Result<()> {
+        for &byte in data {
+            let pushed = wait_until(|| self.tx.push(byte));
+            interrupt::free(|| U::usart_listen(Event::TxEmpty, true));
+            pushed?;
+        }
+        Ok(())
+    }
+
+    // Waits until the TX queue is empty and the last byte has left the shift register
+    pub fn flush(&self) -> Result<()> {
+        wait_until(|| self.tx.is_empty())?;
+        U::usart_flush()
+    }
+
+    // Interrupt handler: drains the data register into the RX queue and refills it from the TX queue
+    pub fn on_interrupt(&self) {
+        while U::usart_rx_ready() {
+            let error = match U::usart_read() {
+                Ok(byte) if self.rx.push(byte) => continue,
+                Ok(_) => HalError::Overrun,
+                Err(error) => error,
+            };
+            let code = RX_ERRORS.iter().position(|&known| known == error).map_or(0, |index| index as u8 + 1);
+            self.rx_error.store(code, Ordering::Relaxed);
+        }
+        while U::usart_tx_ready() {
+            match self.tx.pop() {
+                Some(byte) => {
+                    let _ = U::usart_write(byte); // The data register is empty, the write cannot wait
+                }
+                None => {
+                    U::usart_listen(Event::TxEmpty, false); // Nothing left to send, TXE would fire forever
+                    break;
+                }
+            }
+        }
+    }
+}
+
+impl<U: BufferedUSART, const RX: usize, const TX: usize> Default for BufferedSerial<U, RX, TX> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,38 +1,279 @@
-use super::USART;
+use core::marker::PhantomData;
+use core::task::Waker;
 
-const USART2_SR: *mut u32 = 0x40004400u32 as *mut u32; // Status Register
-const USART2_DR: *mut u32 = 0x40004404u32 as *mut u32;   // Data Register
-const USART2_BRR: *mut u32 = 0x40004408u32 as *mut u32;  // Baud Rate Register
-const USART2_CR1: *mut u32 = 0x4000440Cu32 as *mut u32;  // Control Register 1
+use super::{baud, check_receive_errors, AsyncUSART, BufferedUSART, DataBits, Event, Parity, StopBits, UsartConfig, UsartErrors, USART};
+use crate::clock::Clocks;
+use crate::global::{global, Global};
+use crate::gpio::{cortex_m3::configure_alternate, Port};
+use crate::interrupt;
+use crate::rcc::{self, Peripheral};
+use crate::reg::{Field, Reg};
+use crate::timeout::{wait_for, wait_until};
+use crate::{HalError, Result};
 
-const TXE_BIT: u32 = 1 << 7;    // Transmit Data Register Empty bit from SR
-const RXNE_BIT: u32 = 1 << 5;   // Read Data Register Not Empty bit from SR
-
-pub struct CortexM3;
+// Registers of a USART, every instance has the same layout at its own base address
+struct Regs {
+    sr: Reg<u32>,  // Status Register
+    dr: Reg<u32>,  // Data Register
+    brr: Reg<u32>, // Baud Rate Register
+    cr1: Reg<u32>, // Control Register 1
+    cr2: Reg<u32>, // Control Register 2
+}
 
-impl USART for CortexM3 {
-    // Initializes the USART with the given baud rate, enabling transmission and reception
-    fn usart_init(baud_rate: u32) {
-        let baud_div = 16_000_000 / baud_rate;  //16_000_000 is the clock rate
+impl Regs {
+    const fn new(base: usize) -> Self {
         unsafe {
-            *USART2_BRR = baud_div; //We set the baud rate
-            *USART2_CR1 = (1 << 3) | (1 << 2) | (1 << 13);  //Enables transmission (TX), reception (RX) and USART
+            Regs {
+                sr: Reg::new(base),
+                dr: Reg::new(base + 0x04),
+                brr: Reg::new(base + 0x08),
+                cr1: Reg::new(base + 0x0C),
+                cr2: Reg::new(base + 0x10),
+            }
         }
     }
+}
+
+// SR bits
+const TXE: u32 = 1 << 7;    // Transmit Data Register Empty
+const TC: u32 = 1 << 6;     // Transmission Complete
+const RXNE: u32 = 1 << 5;   // Read Data Register Not Empty
+const ORE: u32 = 1 << 3;    // Overrun Error
+const NE: u32 = 1 << 2;     // Noise Error
+const FE: u32 = 1 << 1;     // Framing Error
+const PE: u32 = 1 << 0;     // Parity Error
+
+// Receive error flags, most serious first. They are cleared by a read of SR followed by a read of DR, the read
+// path therefore keeps the SR value that saw RXNE instead of reading it again
+const RX_ERRORS: [(u32, HalError); 4] = [
+    (FE, HalError::Framing),
+    (PE, HalError::Parity),
+    (NE, HalError::Noise),
+    (ORE, HalError::Overrun),
+];
+
+// CR1 bits
+const UE: u32 = 1 << 13;    // USART Enable
+const M: u32 = 1 << 12;     // Word length: 9 bits (parity included) when set, 8 bits otherwise
+const PCE: u32 = 1 << 10;   // Parity Control Enable
+const PS: u32 = 1 << 9;     // Parity Selection: odd when set
+const TE: u32 = 1 << 3;     // Transmitter Enable
+const RE: u32 = 1 << 2;     // Receiver Enable
+const TXEIE: u32 = 1 << 7;  // TXE Interrupt Enable
+const TCIE: u32 = 1 << 6;   // Transmission Complete Interrupt Enable
+const RXNEIE: u32 = 1 << 5; // RXNE Interrupt Enable
+
+// CR2 fields
+const STOP: Field = Field::new(12, 2); // Stop bits: 0b00 1, 0b10 2, 0b11 1.5
+
+// Driver state of one USART, every instance has its own
+pub struct State {
+    rx_mask: &'static Global<u8>, // Data bits of the received frames: with 7 data bits and parity, DR bit 7 holds the parity bit
+    errors: &'static Global<UsartErrors>,
+    tx_waker: &'static Global<Option<Waker>>, // Task waiting for the transmitter (TXE or TC)
+    rx_waker: &'static Global<Option<Waker>>, // Task waiting for the receiver (RXNE)
+}
+
+// Register block, clock and pins of one USART of the chip
+pub trait Instance {
+    const BASE: usize;
+    const CLOCK: Peripheral;
+    const TX: (Port, u8);
+    const RX: (Port, u8);
+    const ALTERNATE: u8; // Alternate function of the TX and RX pins
+    const HALF_STOP_BITS: bool; // 0.5 and 1.5 stop bits, only available on the USARTs
+    fn pclk(clocks: &Clocks) -> u32; // Clock of the APB bus the USART sits on
+    fn state() -> State;
+}
+
+macro_rules! instances {
+    ($($name:ident => $base:expr, $clock:ident, $pclk:ident, tx: $tx:expr, rx: $rx:expr, af: $af:expr, half_stop: $half:expr;)*) => {
+        $(
+            pub struct $name;
+
+            impl Instance for $name {
+                const BASE: usize = $base;
+                const CLOCK: Peripheral = Peripheral::$clock;
+                const TX: (Port, u8) = $tx;
+                const RX: (Port, u8) = $rx;
+                const ALTERNATE: u8 = $af;
+                const HALF_STOP_BITS: bool = $half;
+
+                fn pclk(clocks: &Clocks) -> u32 {
+                    clocks.$pclk
+                }
+
+                fn state() -> State {
+                    global! { static RX_MASK: u8 = 0xFF; }
+                    global! { static ERRORS: UsartErrors = UsartErrors::new(); }
+                    global! { static TX_WAKER: Option<Waker> = None; }
+                    global! { static RX_WAKER: Option<Waker> = None; }
+                    State { rx_mask: &RX_MASK, errors: &ERRORS, tx_waker: &TX_WAKER, rx_waker: &RX_WAKER }
+                }
+            }
+        )*
+    };
+}
+
+// USART1 sits on APB2, the others on APB1. UART4 and UART5 only exist on the high-density parts
+instances! {
+    USART1 => 0x4001_3800, Usart1, pclk2, tx: (Port::A, 9), rx: (Port::A, 10), af: 7, half_stop: true;
+    USART2 => 0x4000_4400, Usart2, pclk1, tx: (Port::A, 2), rx: (Port::A, 3), af: 7, half_stop: true;
+    USART3 => 0x4000_4800, Usart3, pclk1, tx: (Port::B, 10), rx: (Port::B, 11), af: 7, half_stop: true;
+    UART4 => 0x4000_4C00, Uart4, pclk1, tx: (Port::C, 10), rx: (Port::C, 11), af: 5, half_stop: false;
+    UART5 => 0x4000_5000, Uart5, pclk1, tx: (Port::C, 12), rx: (Port::D, 2), af: 5, half_stop: false;
+}
+
+// One USART of the chip, e.g. `Usart<USART1>` for the console and `Usart<USART3>` for a modem
+// The instances have separate registers and driver state and can be used at the same time
+pub struct Usart<I: Instance>(PhantomData<I>);
+
+// USART2 on PA2/PA3, the port of `usart::ActiveUSART`
+pub type CortexM3 = Usart<USART2>;
+
+fn regs<I: Instance>() -> Regs {
+    Regs::new(I::BASE)
+}
+
+impl<I: Instance> USART for Usart<I> {
+    // Initializes the USART with the given baud rate and frame format, enabling transmission and reception
+    // The frame is 8 or 9 bits long with the parity bit included: 7 or 8 data bits with parity, 8 without. 9 data
+    // bits would need DR bit 8, which `usart_read`/`usart_write` do not carry
+    fn usart_init(config: UsartConfig, clocks: &Clocks) -> Result<()> {
+        let (word_length, rx_mask) = match (config.data_bits, config.parity) {
+            (DataBits::Seven, Parity::Even | Parity::Odd) => (0, 0x7F),
+            (DataBits::Eight, Parity::None) => (0, 0xFF),
+            (DataBits::Eight, Parity::Even | Parity::Odd) => (M, 0xFF),
+            _ => return Err(HalError::InvalidConfig),
+        };
+        let parity = match config.parity {
+            Parity::None => 0,
+            Parity::Even => PCE,
+            Parity::Odd => PCE | PS,
+        };
+        let stop = match config.stop_bits {
+            StopBits::One => 0b00,
+            StopBits::Two => 0b10,
+            StopBits::OneAndHalf if I::HALF_STOP_BITS => 0b11,
+            StopBits::OneAndHalf => return Err(HalError::InvalidConfig),
+        };
+
+        let baud = baud::cortex_m3(I::pclk(clocks), config.baud_rate, baud::MAX_ERROR_PPM)?;
+        let regs = regs::<I>();
+        rcc::enable(I::CLOCK);
+        configure_alternate(I::TX.0, I::TX.1, I::ALTERNATE)?;
+        configure_alternate(I::RX.0, I::RX.1, I::ALTERNATE)?;
+        regs.brr.write(baud.divisor as u32); //We set the baud rate
+        regs.cr2.write_field(STOP, stop);
+        regs.cr1.write(word_length | parity | TE | RE | UE);  //Enables transmission (TX), reception (RX) and USART
+        I::state().rx_mask.with(|mask| mask.set(rx_mask));
+        Ok(())
+    }
 
     // Waits until Transmit Data Register Empty bit is 1 to write data in DR
-    fn usart_write(data: u8) {
-        unsafe {
-            while *USART2_SR & TXE_BIT == 0 {} 
-            *USART2_DR = data as u32;
-        }
+    fn usart_write(data: u8) -> Result<()> {
+        let regs = regs::<I>();
+        wait_until(|| regs.sr.is_set(TXE))?;
+        regs.dr.write(data as u32);
+        Ok(())
     }
 
     // Waits until Read Data Register Not Empty bit is 1 to read data from DR
-    fn usart_read() -> u8 {
-        unsafe {
-            while *USART2_SR & RXNE_BIT == 0 {}
-            *USART2_DR as u8 
+    fn usart_read() -> Result<u8> {
+        let status = wait_for(rx_status::<I>)?;
+        receive::<I>(status)
+    }
+
+    // Waits until Transmission Complete is 1, TC is set at reset and cleared by the next write to DR
+    fn usart_flush() -> Result<()> {
+        let regs = regs::<I>();
+        wait_until(|| regs.sr.is_set(TC))
+    }
+
+    fn usart_errors() -> UsartErrors {
+        let errors = I::state().errors;
+        interrupt::free(|| errors.with(|errors| errors.get()))
+    }
+
+    fn usart_clear_errors() {
+        let errors = I::state().errors;
+        interrupt::free(|| errors.with(|errors| errors.set(UsartErrors::new())));
+    }
+}
+
+// SR value once a byte was received
+fn rx_status<I: Instance>() -> Option<u32> {
+    let status = regs::<I>().sr.read();
+    (status & RXNE != 0).then_some(status)
+}
+
+// Reads the received byte of DR, which completes the clear sequence of the error flags found in `status`
+fn receive<I: Instance>(status: u32) -> Result<u8> {
+    let state = I::state();
+    let data = regs::<I>().dr.read() as u8 & state.rx_mask.with(|mask| mask.get());
+    check_receive_errors(state.errors, status, &RX_ERRORS)?;
+    Ok(data)
+}
+
+impl<I: Instance> AsyncUSART for Usart<I> {
+    async fn usart_write_async(data: u8) -> Result<()> {
+        let regs = regs::<I>();
+        interrupt::wait_until(I::state().tx_waker, || regs.cr1.set_bits(TXEIE), || regs.sr.is_set(TXE)).await;
+        regs.dr.write(data as u32);
+        Ok(())
+    }
+
+    async fn usart_read_async() -> Result<u8> {
+        let cr1 = regs::<I>().cr1;
+        let status = interrupt::wait_for(I::state().rx_waker, || cr1.set_bits(RXNEIE), rx_status::<I>).await;
+        receive::<I>(status)
+    }
+
+    async fn usart_flush_async() -> Result<()> {
+        let regs = regs::<I>();
+        interrupt::wait_until(I::state().tx_waker, || regs.cr1.set_bits(TCIE), || regs.sr.is_set(TC)).await;
+        Ok(())
+    }
+
+    // USARTx vector of the instance: masks the interrupts whose flag is set, they would fire again until the task
+    // clears the flag
+    fn usart_on_interrupt() {
+        let (regs, state) = (regs::<I>(), I::state());
+        let status = regs.sr.read();
+        let fired = [(TXE, TXEIE), (TC, TCIE), (RXNE, RXNEIE)]
+            .iter()
+            .filter(|&&(flag, _)| status & flag != 0)
+            .fold(0, |mask, &(_, enable)| mask | enable)
+            & regs.cr1.read();
+        regs.cr1.clear_bits(fired);
+        if fired & (TXEIE | TCIE) != 0 {
+            interrupt::wake(state.tx_waker);
+        }
+        if fired & RXNEIE != 0 {
+            interrupt::wake(state.rx_waker);
+        }
+    }
+}
+
+impl<I: Instance> BufferedUSART for Usart<I> {
+    fn usart_listen(event: Event, enable: bool) {
+        let mask = match event {
+            Event::RxNotEmpty => RXNEIE,
+            Event::TxEmpty => TXEIE,
+        };
+        let cr1 = regs::<I>().cr1;
+        if enable {
+            cr1.set_bits(mask);
+        } else {
+            cr1.clear_bits(mask);
         }
     }
+
+    fn usart_rx_ready() -> bool {
+        regs::<I>().sr.is_set(RXNE)
+    }
+
+    fn usart_tx_ready() -> bool {
+        regs::<I>().sr.is_set(TXE)
+    }
 }
@@ -1,11 +1,159 @@
 pub mod atmega328p;
+pub mod baud;
+pub mod buffered;
 pub mod cortex_m3;
+pub mod print;
+mod ring;
+pub mod serial;
+
+use core::future::Future;
+
+use crate::clock::Clocks;
+use crate::global::Global;
+use crate::interrupt;
+use crate::mmio::RegisterWidth;
+use crate::{HalError, Result};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DataBits {
+    Five,
+    Six,
+    Seven,
+    Eight,
+    Nine, // Rejected with `HalError::InvalidConfig`, `usart_write`/`usart_read` carry 8 bits
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopBits {
+    One,
+    OneAndHalf,
+    Two,
+}
+
+// Baud rate and frame format of a USART, e.g. 7E1: `UsartConfig { data_bits: DataBits::Seven, parity: Parity::Even, ..UsartConfig::new(9600) }`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UsartConfig {
+    pub baud_rate: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+}
+
+impl UsartConfig {
+    // 8N1 frames at `baud_rate`
+    pub const fn new(baud_rate: u32) -> Self {
+        UsartConfig { baud_rate, data_bits: DataBits::Eight, parity: Parity::None, stop_bits: StopBits::One }
+    }
+}
+
+impl Default for UsartConfig {
+    fn default() -> Self {
+        UsartConfig::new(9600)
+    }
+}
+
+// Receive errors seen by a USART since reset or the last `usart_clear_errors`, whichever read path found them
+// The counters saturate instead of wrapping around
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct UsartErrors {
+    pub framing: u32, // Stop bit not found (`HalError::Framing`), also the symptom of a baud rate mismatch
+    pub parity: u32,  // Wrong parity bit (`HalError::Parity`)
+    pub noise: u32,   // Samples of a bit disagreed (`HalError::Noise`), only detected by the Cortex-M3
+    pub overrun: u32, // Bytes lost because the data register was not read in time (`HalError::Overrun`)
+}
+
+impl UsartErrors {
+    pub const fn new() -> Self {
+        UsartErrors { framing: 0, parity: 0, noise: 0, overrun: 0 }
+    }
+
+    // Sum of all the counters
+    pub fn total(&self) -> u32 {
+        self.framing.saturating_add(self.parity).saturating_add(self.noise).saturating_add(self.overrun)
+    }
+
+    fn count(&mut self, error: HalError) {
+        let counter = match error {
+            HalError::Framing => &mut self.framing,
+            HalError::Parity => &mut self.parity,
+            HalError::Noise => &mut self.noise,
+            HalError::Overrun => &mut self.overrun,
+            _ => return,
+        };
+        *counter = counter.saturating_add(1);
+    }
+}
+
+// Counts every receive error whose flag is set in `status` and returns the first one of `flags`
+// The backends list their flags from the most to the least serious: a byte with a framing or parity error is
+// garbage, whereas an overrun only means that the bytes before it were lost, so an overrun alone is counted and the
+// byte kept
+pub(crate) fn check_receive_errors<T: RegisterWidth>(
+    counters: &'static Global<UsartErrors>,
+    status: T,
+    flags: &[(T, HalError)],
+) -> Result<()> {
+    let mut flagged = flags.iter().filter(|&&(flag, _)| status & flag != T::from_u32(0)).map(|&(_, error)| error);
+    let Some(first) = flagged.next() else {
+        return Ok(());
+    };
+    interrupt::free(|| {
+        counters.with(|cell| {
+            let mut errors = cell.get();
+            errors.count(first);
+            flagged.for_each(|error| errors.count(error));
+            cell.set(errors);
+        })
+    });
+    match first {
+        HalError::Overrun => Ok(()),
+        error => Err(error),
+    }
+}
 
 // USART trait defines the interface for USART operations
+// The baud rate divider is computed from the peripheral clock reported by `clocks`, a frame format the chip cannot
+// produce is rejected with `HalError::InvalidConfig`
+// A received byte with a framing, parity or noise flag is dropped and the read returns the error instead, after
+// clearing the flags and counting them in `usart_errors`. A byte flagged with an overrun alone is valid, only the
+// bytes before it were lost: the overrun is counted and the read returns the byte
 pub trait USART {
-    fn usart_init(baud_rate: u32);
-    fn usart_write(data: u8);
-    fn usart_read() -> u8;
+    fn usart_init(config: UsartConfig, clocks: &Clocks) -> Result<()>;
+    fn usart_write(data: u8) -> Result<()>;
+    fn usart_read() -> Result<u8>;
+    fn usart_flush() -> Result<()>; // Waits until every written byte has left the shift register
+    fn usart_errors() -> UsartErrors;
+    fn usart_clear_errors();
+}
+
+// Interrupt-driven variant of the USART operations, the task sleeps until the peripheral interrupt wakes it
+// `usart_on_interrupt` must be called from the USART interrupt vector(s) of the chip
+pub trait AsyncUSART: USART {
+    fn usart_write_async(data: u8) -> impl Future<Output = Result<()>>;
+    fn usart_read_async() -> impl Future<Output = Result<u8>>;
+    fn usart_flush_async() -> impl Future<Output = Result<()>>;
+    fn usart_on_interrupt();
+}
+
+// USART interrupt sources used by the buffered driver
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    RxNotEmpty, // A received byte is waiting in the data register
+    TxEmpty,    // The data register can take the next byte
+}
+
+// Flag-level access for the interrupt handler of `buffered::BufferedSerial`, none of these wait
+pub trait BufferedUSART: USART {
+    fn usart_listen(event: Event, enable: bool);
+    fn usart_rx_ready() -> bool; // `usart_read` returns without waiting
+    fn usart_tx_ready() -> bool; // `usart_write` returns without waiting
 }
 
 #[cfg(feature = "atmega328p")]
@@ -15,15 +163,52 @@ pub type ActiveUSART = atmega328p::Atmega328p;
 pub type ActiveUSART = cortex_m3::CortexM3;
 
 // Public functions to initialize, write, and read using USART
-pub fn usart_init(baud_rate: u32) {
-    ActiveUSART::usart_init(baud_rate);
+#[cfg(any(feature = "atmega328p", feature = "cortex_m3"))]
+pub fn usart_init(config: UsartConfig, clocks: &Clocks) -> Result<()> {
+    ActiveUSART::usart_init(config, clocks)
 }
 
-pub fn usart_write(data: u8) {
-    ActiveUSART::usart_write(data);
+#[cfg(any(feature = "atmega328p", feature = "cortex_m3"))]
+pub fn usart_write(data: u8) -> Result<()> {
+    ActiveUSART::usart_write(data)
 }
 
-pub fn usart_read() -> u8 {
+#[cfg(any(feature = "atmega328p", feature = "cortex_m3"))]
+pub fn usart_read() -> Result<u8> {
     ActiveUSART::usart_read()
 }
 
+#[cfg(any(feature = "atmega328p", feature = "cortex_m3"))]
+pub fn usart_flush() -> Result<()> {
+    ActiveUSART::usart_flush()
+}
+
+#[cfg(any(feature = "atmega328p", feature = "cortex_m3"))]
+pub fn usart_errors() -> UsartErrors {
+    ActiveUSART::usart_errors()
+}
+
+#[cfg(any(feature = "atmega328p", feature = "cortex_m3"))]
+pub fn usart_clear_errors() {
+    ActiveUSART::usart_clear_errors()
+}
+
+#[cfg(any(feature = "atmega328p", feature = "cortex_m3"))]
+pub async fn usart_write_async(data: u8) -> Result<()> {
+    ActiveUSART::usart_write_async(data).await
+}
+
+#[cfg(any(feature = "atmega328p", feature = "cortex_m3"))]
+pub async fn usart_read_async() -> Result<u8> {
+    ActiveUSART::usart_read_async().await
+}
+
+#[cfg(any(feature = "atmega328p", feature = "cortex_m3"))]
+pub async fn usart_flush_async() -> Result<()> {
+    ActiveUSART::usart_flush_async().await
+}
+
+#[cfg(any(feature = "atmega328p", feature = "cortex_m3"))]
+pub fn usart_on_interrupt() {
+    ActiveUSART::usart_on_interrupt()
+}
@@ -0,0 +1,102 @@
+// Formatted diagnostics on a USART
+// `usart_print!` and `usart_println!` format their arguments straight into `usart_write`, one byte at a time and
+// without any buffer, on `ActiveUSART` or on the USART named before `=>`:
+//     usart_println!("temperature: {} C", celsius)?;
+//     usart_println!(Usart<USART1> => "boot {}", version)?;
+// Lines end with "\r\n" for serial terminals. Both macros return the `Result` of the first byte that failed.
+
+use core::fmt::{self, Write};
+use core::marker::PhantomData;
+
+use super::USART;
+use crate::Result;
+
+// Keeps the `HalError` that `fmt::Write` can only report as `fmt::Error`
+struct Writer<U: USART> {
+    result: Result<()>,
+    _usart: PhantomData<U>,
+}
+
+impl<U: USART> Write for Writer<U> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.result = s.bytes().try_for_each(U::usart_write);
+        self.result.map_err(|_| fmt::Error)
+    }
+}
+
+// Sends the formatted `args`, used by the macros
+// An error of a `Display` implementation only cuts the output short
+pub fn print<U: USART>(args: fmt::Arguments<'_>) -> Result<()> {
+    let mut writer = Writer::<U> { result: Ok(()), _usart: PhantomData };
+    let _ = writer.write_fmt(args);
+    writer.result
+}
+
+// Sends `data` as a hex dump, see `HexDump`
+pub fn hex_dump<U: USART>(data: &[u8]) -> Result<()> {
+    print::<U>(format_args!("{}", HexDump::new(data)))
+}
+
+// Formats bytes 16 per line as offset, hex values and printable ASCII:
+// 00000000: 48 65 6c 6c 6f 0d 0a                             |Hello..|
+pub struct HexDump<'a> {
+    data: &'a [u8],
+    address: usize,
+}
+
+impl<'a> HexDump<'a> {
+    pub const fn new(data: &'a [u8]) -> Self {
+        HexDump { data, address: 0 }
+    }
+
+    // Numbers the lines from `address`, e.g. the location of the dumped memory
+    pub const fn at(self, address: usize) -> Self {
+        HexDump { address, ..self }
+    }
+}
+
+impl fmt::Display for HexDump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, line) in self.data.chunks(16).enumerate() {
+            write!(f, "{:08x}:", self.address.wrapping_add(index * 16))?;
+            for column in 0..16 {
+                match line.get(column) {
+                    Some(byte) => write!(f, " {:02x}", byte)?,
+                    None => f.write_str("   ")?,
+                }
+            }
+            f.write_str("  |")?;
+            for &byte in line {
+                f.write_char(if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })?;
+            }
+            f.write_str("|\r\n")?;
+        }
+        Ok(())
+    }
+}
+
+#[macro_export]
+macro_rules! usart_print {
+    ($usart:ty => $($arg:tt)*) => {
+        $crate::usart::print::print::<$usart>(format_args!($($arg)*))
+    };
+    ($($arg:tt)*) => {
+        $crate::usart::print::print::<$crate::usart::ActiveUSART>(format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! usart_println {
+    ($usart:ty =>) => {
+        $crate::usart::print::print::<$usart>(format_args!("\r\n"))
+    };
+    ($usart:ty => $fmt:literal $($arg:tt)*) => {
+        $crate::usart::print::print::<$usart>(format_args!(concat!($fmt, "\r\n") $($arg)*))
+    };
+    () => {
+        $crate::usart::print::print::<$crate::usart::ActiveUSART>(format_args!("\r\n"))
+    };
+    ($fmt:literal $($arg:tt)*) => {
+        $crate::usart::print::print::<$crate::usart::ActiveUSART>(format_args!(concat!($fmt, "\r\n") $($arg)*))
+    };
+}
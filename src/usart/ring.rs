@@ -0,0 +1,59 @@
+// Single-producer single-consumer byte queue shared by the main program and an interrupt handler
+// The producer only moves `head` and the consumer only moves `tail`, each side publishes its index with Release
+// ordering after touching the slot and reads the other one with Acquire ordering, so no critical section is needed.
+// One slot always stays empty to tell a full queue from an empty one: a `RingBuffer<N>` holds N - 1 bytes.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+pub(crate) struct RingBuffer<const N: usize> {
+    slots: UnsafeCell<[u8; N]>,
+    head: AtomicUsize, // Next slot written by the producer
+    tail: AtomicUsize, // Next slot read by the consumer
+}
+
+// Safety: a slot is only written by the producer while it is outside of [tail, head) and only read by the consumer
+// while it is inside, the owners of `BufferedSerial` keep to one producer and one consumer per queue
+unsafe impl<const N: usize> Sync for RingBuffer<N> {}
+
+impl<const N: usize> RingBuffer<N> {
+    const VALID: () = assert!(N >= 2, "a ring buffer needs at least 2 slots");
+
+    pub(crate) const fn new() -> Self {
+        let () = Self::VALID;
+        RingBuffer { slots: UnsafeCell::new([0; N]), head: AtomicUsize::new(0), tail: AtomicUsize::new(0) }
+    }
+
+    // Producer side, returns false (and drops the byte) when the queue is full
+    pub(crate) fn push(&self, byte: u8) -> bool {
+        let head = self.head.load(Ordering::Relaxed);
+        let next = (head + 1) % N;
+        if next == self.tail.load(Ordering::Acquire) {
+            return false;
+        }
+        unsafe { self.slots.get().cast::<u8>().add(head).write(byte) };
+        self.head.store(next, Ordering::Release);
+        true
+    }
+
+    // Consumer side
+    pub(crate) fn pop(&self) -> Option<u8> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail == self.head.load(Ordering::Acquire) {
+            return None;
+        }
+        let byte = unsafe { self.slots.get().cast::<u8>().add(tail).read() };
+        self.tail.store((tail + 1) % N, Ordering::Release);
+        Some(byte)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        let head = self.head.load(Ordering::Acquire);
+        let tail = self.tail.load(Ordering::Acquire);
+        (head + N - tail) % N
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
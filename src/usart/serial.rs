@@ -0,0 +1,148 @@
+// Handle on an initialised USART, implementing the embedded-hal serial traits on top of a `USART` implementation
+// e.g. `Serial::<Atmega328p>::init(UsartConfig::new(9600), &clocks)?` can be handed to any driver crate expecting `serial::Write<u8>`
+
+use core::fmt;
+use core::marker::PhantomData;
+
+use embedded_hal::blocking;
+use embedded_hal::serial;
+
+use super::{BufferedUSART, UsartConfig, USART};
+#[cfg(feature = "async")]
+use super::AsyncUSART;
+use crate::clock::Clocks;
+use crate::{HalError, Result};
+
+pub struct Serial<U: USART> {
+    _usart: PhantomData<U>,
+}
+
+impl<U: USART> Serial<U> {
+    pub fn init(config: UsartConfig, clocks: &Clocks) -> Result<Self> {
+        U::usart_init(config, clocks)?;
+        Ok(Self::new())
+    }
+
+    // Wraps a USART already initialised with `usart_init`
+    pub fn new() -> Self {
+        Serial { _usart: PhantomData }
+    }
+}
+
+impl<U: USART> Default for Serial<U> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// `WouldBlock` until the USART has received a byte or can take the next one
+impl<U: BufferedUSART> serial::Read<u8> for Serial<U> {
+    type Error = HalError;
+
+    fn read(&mut self) -> nb::Result<u8, HalError> {
+        if !U::usart_rx_ready() {
+            return Err(nb::Error::WouldBlock);
+        }
+        U::usart_read().map_err(nb::Error::Other)
+    }
+}
+
+impl<U: BufferedUSART> serial::Write<u8> for Serial<U> {
+    type Error = HalError;
+
+    fn write(&mut self, word: u8) -> nb::Result<(), HalError> {
+        if !U::usart_tx_ready() {
+            return Err(nb::Error::WouldBlock);
+        }
+        U::usart_write(word).map_err(nb::Error::Other)
+    }
+
+    // Waits (bounded by the timeout policy) for the end of the transmission, the USART has no flag to poll for it
+    // without waiting
+    fn flush(&mut self) -> nb::Result<(), HalError> {
+        U::usart_flush().map_err(nb::Error::Other)
+    }
+}
+
+// Waits bounded by the timeout policy, unlike a `nb::block!` loop around `serial::Write`
+impl<U: USART> blocking::serial::Write<u8> for Serial<U> {
+    type Error = HalError;
+
+    fn bwrite_all(&mut self, buffer: &[u8]) -> Result<()> {
+        buffer.iter().try_for_each(|&word| U::usart_write(word))
+    }
+
+    fn bflush(&mut self) -> Result<()> {
+        U::usart_flush()
+    }
+}
+
+// Formatted output with `write!`, a byte the USART failed to send ends the output with `fmt::Error`
+// (`usart::print::print` reports the `HalError` itself)
+impl<U: USART> fmt::Write for Serial<U> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        s.bytes().try_for_each(U::usart_write).map_err(|_| fmt::Error)
+    }
+}
+
+// embedded-io byte streams
+#[cfg(feature = "embedded-hal-1")]
+impl<U: USART> embedded_io::ErrorType for Serial<U> {
+    type Error = HalError;
+}
+
+#[cfg(feature = "embedded-hal-1")]
+impl<U: USART> embedded_io::Read for Serial<U> {
+    // Waits for one byte and returns it alone, as `read` only has to return what is already available
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match buf.first_mut() {
+            Some(byte) => {
+                *byte = U::usart_read()?;
+                Ok(1)
+            }
+            None => Ok(0),
+        }
+    }
+}
+
+#[cfg(feature = "embedded-hal-1")]
+impl<U: USART> embedded_io::Write for Serial<U> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        for &byte in buf {
+            U::usart_write(byte)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        U::usart_flush()
+    }
+}
+
+// embedded-io-async byte streams, sleeping in the USART interrupt instead of polling
+#[cfg(feature = "async")]
+impl<U: AsyncUSART> embedded_io_async::Read for Serial<U> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match buf.first_mut() {
+            Some(byte) => {
+                *byte = U::usart_read_async().await?;
+                Ok(1)
+            }
+            None => Ok(0),
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl<U: AsyncUSART> embedded_io_async::Write for Serial<U> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        for &byte in buf {
+            U::usart_write_async(byte).await?;
+        }
+        Ok(buf.len())
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        U::usart_flush_async().await
+    }
+}
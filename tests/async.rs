@@ -0,0 +1,219 @@
+#![cfg(feature = "host-sim")]
+
+use hal_project::i2c::{AsyncI2C, Operation};
+use hal_project::sim;
+use hal_project::sim::trace;
+use hal_project::spi::AsyncSPI;
+use hal_project::usart::AsyncUSART;
+use hal_project::{i2c, spi, usart};
+
+mod common;
+use common::*;
+
+const USART2_SR: usize = 0x4000_4400;
+const USART2_DR: usize = 0x4000_4404;
+const USART2_CR1: usize = 0x4000_440C;
+const TXE: u32 = 1 << 7;
+const RXNE: u32 = 1 << 5;
+
+const UCSR0A: usize = 0xC0;
+const UCSR0B: usize = 0xC1;
+const UDR0: usize = 0xC6;
+const RXC0: u32 = 1 << 7;
+
+const SPCR: usize = 0x4C;
+const SPDR: usize = 0x4E;
+const SPIE: u32 = 1 << 7;
+
+const SPI1_CR2: usize = 0x4001_3004;
+const SPI1_SR: usize = 0x4001_3008;
+const SPI1_DR: usize = 0x4001_300C;
+
+const I2C_CR1: usize = 0x4000_5400;
+const I2C_CR2: usize = 0x4000_5404;
+const I2C_DR: usize = 0x4000_5410;
+const I2C_SR1: usize = 0x4000_5414;
+const STOP: u32 = 1 << 9;
+const I2C_IT: u32 = 0b111 << 8; // ITERREN, ITEVTEN, ITBUFEN
+
+// Raises `flags` in the status register and runs the interrupt handler if one of them is enabled
+fn raise(status: usize, flags: u32, control: usize, enables: u32, handler: fn()) {
+    sim::poke(status, sim::peek(status) | flags);
+    if sim::peek(control) & enables != 0 {
+        handler();
+    }
+}
+
+#[test]
+fn cortex_m3_write_sleeps_until_txe() {
+    sim::reset();
+    let (result, pending) = block_on(usart::cortex_m3::CortexM3::usart_write_async(b'a'), || {
+        assert_eq!(sim::peek(USART2_CR1) & TXE, TXE); // TXEIE enabled while the task sleeps
+        raise(USART2_SR, TXE, USART2_CR1, TXE, usart::cortex_m3::CortexM3::usart_on_interrupt);
+    });
+    result.unwrap();
+    assert_eq!(pending, 1);
+    assert_eq!(sim::peek(USART2_DR), b'a' as u32);
+    assert_eq!(sim::peek(USART2_CR1) & TXE, 0); // Masked again by the interrupt handler
+}
+
+#[test]
+fn cortex_m3_read_sleeps_until_rxne() {
+    sim::reset();
+    let (byte, pending) = block_on(usart::cortex_m3::CortexM3::usart_read_async(), || {
+        sim::poke(USART2_DR, b'z' as u32);
+        raise(USART2_SR, RXNE, USART2_CR1, RXNE, usart::cortex_m3::CortexM3::usart_on_interrupt);
+    });
+    assert_eq!(byte.unwrap(), b'z');
+    assert_eq!(pending, 1);
+}
+
+#[test]
+fn atmega328p_read_sleeps_until_rxc0() {
+    sim::reset();
+    let (byte, pending) = block_on(usart::atmega328p::Atmega328p::usart_read_async(), || {
+        sim::poke(UDR0, 0x42);
+        raise(UCSR0A, RXC0, UCSR0B, RXC0, usart::atmega328p::Atmega328p::usart_on_interrupt);
+    });
+    assert_eq!(byte.unwrap(), 0x42);
+    assert_eq!(pending, 1);
+    assert_eq!(sim::peek(UCSR0B) & RXC0, 0); // RXCIE0 masked again
+}
+
+#[test]
+fn usart_ready_flag_completes_without_sleeping() {
+    sim::reset();
+    sim::poke(USART2_SR, TXE);
+    let (result, pending) = block_on(usart::cortex_m3::CortexM3::usart_write_async(b'b'), || unreachable!());
+    result.unwrap();
+    assert_eq!(pending, 0);
+    assert_eq!(sim::peek(USART2_CR1), 0); // The interrupt was never enabled
+}
+
+#[test]
+fn atmega328p_spi_transfer_completes_in_the_vector() {
+    sim::reset();
+    let (received, pending) = block_on(spi::atmega328p::Atmega328p::spi_transfer_async(0x5A), || {
+        // Entering the vector clears SPIF on real hardware, so the flag is never raised here
+        assert_ne!(sim::peek(SPCR) & SPIE, 0);
+        spi::atmega328p::Atmega328p::spi_on_interrupt();
+    });
+    assert_eq!(received.unwrap(), 0x5A); // Loopback data register
+    assert_eq!(pending, 1);
+    assert_eq!(sim::peek(SPCR) & SPIE, 0);
+    assert_eq!(sim::peek(SPDR), 0x5A);
+}
+
+#[test]
+fn cortex_m3_spi_transfer_sleeps_on_txe_then_rxne() {
+    sim::reset();
+    let mut step = 0;
+    let (received, pending) = block_on(spi::cortex_m3::CortexM3::spi_transfer_async(0x33), || {
+        step += 1;
+        let flag = if step == 1 { 1 << 1 } else { 1 << 0 }; // TXE, then RXNE once the byte was shifted
+        raise(SPI1_SR, flag, SPI1_CR2, (1 << 7) | (1 << 6), spi::cortex_m3::CortexM3::spi_on_interrupt);
+    });
+    assert_eq!(received.unwrap(), 0x33);
+    assert_eq!(pending, 2);
+    assert_eq!(sim::peek(SPI1_DR), 0x33);
+    assert_eq!(sim::peek(SPI1_CR2), 0);
+}
+
+#[test]
+fn atmega328p_transaction_matches_the_blocking_sequence() {
+    sim::reset();
+    sim::attach(TwiBus::new(0x42));
+    sim::attach(ConstantSlave { data_register: TWDR, byte: 0x5A });
+    let mut buffer = [0u8; 2];
+    let mut operations = [Operation::Write(&[0x0F]), Operation::Read(&mut buffer)];
+    let (result, _) = block_on(
+        i2c::atmega328p::Atmega328p::i2c_transaction_async(0x42, &mut operations),
+        i2c::atmega328p::Atmega328p::i2c_on_interrupt,
+    );
+    result.unwrap();
+    assert_eq!(buffer, [0x5A; 2]);
+
+    let commands: Vec<u32> = trace::take().at(TWCR).writes().accesses().iter().map(|a| a.value).collect();
+    assert_eq!(
+        commands,
+        [
+            TWINT | TWSTA | TWEN,
+            TWINT | TWEN,
+            TWINT | TWEN,
+            TWINT | TWSTA | TWEN,
+            TWINT | TWEN,
+            TWINT | TWEN | TWEA,
+            TWINT | TWEN,
+            TWINT | TWSTO | TWEN,
+        ]
+    );
+}
+
+#[test]
+fn atmega328p_interrupt_handler_masks_twie_without_clearing_twint() {
+    sim::reset();
+    sim::poke(TWCR, TWINT | TWEN | 1); // TWIE
+    i2c::atmega328p::Atmega328p::i2c_on_interrupt();
+    // Writing a one to TWINT would start the next operation, the handler writes it as zero
+    assert_eq!(trace::take().at(TWCR).writes().accesses()[0].value, TWEN);
+}
+
+#[test]
+fn cortex_m3_transaction_sleeps_on_every_bus_event() {
+    sim::reset();
+    sim::attach(ConstantSlave { data_register: I2C_DR, byte: 0x77 });
+    let mut buffer = [0u8; 1];
+    let mut operations = [Operation::Write(&[0x10]), Operation::Read(&mut buffer)];
+    let (result, pending) = block_on(i2c::cortex_m3::CortexM3::i2c_transaction_async(0x42, &mut operations), || {
+        raise(I2C_SR1, 0b11 | (1 << 7) | (1 << 6), I2C_CR2, I2C_IT, i2c::cortex_m3::CortexM3::i2c_on_interrupt);
+    });
+    result.unwrap();
+    assert_eq!(buffer, [0x77]);
+    assert_eq!(pending, 1); // SB, then the flags stay set in the simulated SR1
+    assert_eq!(sim::peek(I2C_CR2) & I2C_IT, 0);
+    assert_ne!(sim::peek(I2C_CR1) & STOP, 0);
+}
+
+#[test]
+fn cortex_m3_nack_while_asleep_releases_the_bus() {
+    sim::reset();
+    sim::poke(I2C_SR1, 1); // SB already set
+    let mut operations = [Operation::Write(&[0x10])];
+    let (result, _) = block_on(i2c::cortex_m3::CortexM3::i2c_transaction_async(0x42, &mut operations), || {
+        raise(I2C_SR1, 1 << 10, I2C_CR2, I2C_IT, i2c::cortex_m3::CortexM3::i2c_on_interrupt); // AF
+    });
+    assert_eq!(result, Err(hal_project::HalError::Nack));
+    assert_ne!(sim::peek(I2C_CR1) & STOP, 0);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn serial_and_spi_device_through_the_async_traits() {
+    use embedded_hal_async::spi::SpiDevice;
+    use embedded_io_async::Write;
+    use hal_project::clock::Clocks;
+    use hal_project::delay::Delay;
+    use hal_project::gpio::atmega328p::PortB;
+    use hal_project::gpio::pin::{Pin, Unconfigured};
+    use hal_project::spi::bus::Spi;
+    use hal_project::spi::device::ExclusiveDevice;
+    use hal_project::usart::serial::Serial;
+
+    sim::reset();
+    let mut serial = Serial::<usart::cortex_m3::CortexM3>::new();
+    let (result, pending) = block_on(serial.write_all(b"hi"), || {
+        raise(USART2_SR, TXE | (1 << 6), USART2_CR1, TXE | (1 << 6), usart::cortex_m3::CortexM3::usart_on_interrupt);
+    });
+    result.unwrap();
+    assert_eq!(pending, 1);
+    assert_eq!(sim::peek(USART2_DR), b'i' as u32);
+
+    let cs = Pin::<PortB, 2, Unconfigured>::new().into_push_pull_output().unwrap();
+    let mut device = ExclusiveDevice::new(Spi::<spi::atmega328p::Atmega328p>::new(), cs, Delay::new(&Clocks::single(16_000_000))).unwrap();
+    let mut buffer = [0x11, 0x22];
+    let (result, pending) = block_on(device.transfer_in_place(&mut buffer), spi::atmega328p::Atmega328p::spi_on_interrupt);
+    result.unwrap();
+    assert_eq!(buffer, [0x11, 0x22]);
+    assert_eq!(pending, 2);
+    assert_ne!(sim::peek(0x25) & (1 << 2), 0); // Chip select released
+}
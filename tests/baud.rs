@@ -0,0 +1,101 @@
+#![cfg(feature = "host-sim")]
+
+use hal_project::clock::Clocks;
+use hal_project::sim;
+use hal_project::usart::atmega328p::Atmega328p;
+use hal_project::usart::baud::{self, BaudSetting, MAX_ERROR_PPM};
+use hal_project::usart::{UsartConfig, USART};
+use hal_project::HalError;
+
+const UCSR0A: usize = 0xC0;
+const UBRR0L: usize = 0xC4;
+const U2X0: u32 = 1 << 1;
+
+#[test]
+fn atmega328p_divisor_is_rounded() {
+    // 16 MHz / (16 * 9600) = 104.17: UBRR0 = 103, +0.16 %
+    let setting = baud::atmega328p(16_000_000, 9600, MAX_ERROR_PPM).unwrap();
+    assert_eq!(setting, BaudSetting { divisor: 103, double_speed: false, achieved: 9615, error_ppm: 1603 });
+
+    // 16 MHz / (16 * 57600) = 17.36, truncating to 16 would be 3.7 % off
+    let setting = baud::atmega328p(16_000_000, 57_600, MAX_ERROR_PPM).unwrap();
+    assert_eq!((setting.divisor, setting.double_speed, setting.achieved), (34, true, 57_143));
+    assert_eq!(setting.error_ppm, -7937);
+}
+
+#[test]
+fn atmega328p_double_speed_only_when_closer() {
+    // Normal speed: UBRR0 = 8, -3.5 %, double speed: UBRR0 = 16, +2.1 %
+    let setting = baud::atmega328p(16_000_000, 115_200, MAX_ERROR_PPM).unwrap();
+    assert_eq!((setting.divisor, setting.double_speed, setting.achieved), (16, true, 117_647));
+    assert_eq!(setting.error_ppm, 21_242);
+    assert!((setting.error_percent() - 2.1242).abs() < 0.0001);
+
+    // Exact at both speeds: the normal speed samples more and wins
+    let setting = baud::atmega328p(18_432_000, 115_200, MAX_ERROR_PPM).unwrap();
+    assert_eq!(setting, BaudSetting { divisor: 9, double_speed: false, achieved: 115_200, error_ppm: 0 });
+}
+
+#[test]
+fn atmega328p_rates_out_of_reach_are_refused() {
+    assert_eq!(baud::atmega328p(16_000_000, 0, MAX_ERROR_PPM), Err(HalError::InvalidBaud));
+    assert_eq!(baud::atmega328p(16_000_000, 200, MAX_ERROR_PPM), Err(HalError::InvalidBaud)); // UBRR0 > 4095
+    assert_eq!(baud::atmega328p(16_000_000, 4_000_000, MAX_ERROR_PPM), Err(HalError::InvalidBaud));
+    assert!(baud::atmega328p(16_000_000, 2_000_000, MAX_ERROR_PPM).is_ok()); // UBRR0 = 0 with U2X0
+
+    // 230400 baud at 16 MHz is 3.5 % off at best
+    assert_eq!(baud::atmega328p(16_000_000, 230_400, MAX_ERROR_PPM), Err(HalError::InvalidBaud));
+    assert!(baud::atmega328p(16_000_000, 230_400, 40_000).is_ok());
+    assert_eq!(baud::atmega328p(16_000_000, 115_200, 20_000), Err(HalError::InvalidBaud));
+}
+
+#[test]
+fn cortex_m3_brr_has_mantissa_and_fraction() {
+    // USARTDIV = 72 MHz / (16 * 115200) = 39.0625: mantissa 39, fraction 1
+    let setting = baud::cortex_m3(72_000_000, 115_200, MAX_ERROR_PPM).unwrap();
+    assert_eq!(setting, BaudSetting { divisor: (39 << 4) | 1, double_speed: false, achieved: 115_200, error_ppm: 0 });
+
+    // USARTDIV = 36 MHz / (16 * 9600) = 234.375: mantissa 234, fraction 6
+    let setting = baud::cortex_m3(36_000_000, 9600, MAX_ERROR_PPM).unwrap();
+    assert_eq!(setting.divisor, (234 << 4) | 6);
+    assert_eq!(setting.error_ppm, 0);
+
+    // USARTDIV = 8 MHz / (16 * 115200) = 4.34: fraction 5.44 rounded to 5
+    let setting = baud::cortex_m3(8_000_000, 115_200, MAX_ERROR_PPM).unwrap();
+    assert_eq!((setting.divisor, setting.achieved, setting.error_ppm), ((4 << 4) | 5, 115_942, 6441));
+}
+
+#[test]
+fn cortex_m3_fraction_carries_into_mantissa() {
+    // USARTDIV = 15.9375 exactly: mantissa 15, fraction 15
+    let setting = baud::cortex_m3(255_000, 1000, MAX_ERROR_PPM).unwrap();
+    assert_eq!(setting.divisor, (15 << 4) | 15);
+
+    // USARTDIV = 15.99: the fraction rounds to 16 and becomes mantissa 16, fraction 0
+    let setting = baud::cortex_m3(255_900, 1000, MAX_ERROR_PPM).unwrap();
+    assert_eq!(setting.divisor, 16 << 4);
+}
+
+#[test]
+fn cortex_m3_rates_out_of_reach_are_refused() {
+    assert_eq!(baud::cortex_m3(8_000_000, 0, MAX_ERROR_PPM), Err(HalError::InvalidBaud));
+    assert_eq!(baud::cortex_m3(8_000_000, 1_000_000, MAX_ERROR_PPM), Err(HalError::InvalidBaud)); // Mantissa 0
+    assert_eq!(baud::cortex_m3(72_000_000, 1000, MAX_ERROR_PPM), Err(HalError::InvalidBaud)); // Mantissa > 4095
+    assert_eq!(baud::cortex_m3(8_000_000, 484_848, MAX_ERROR_PPM), Err(HalError::InvalidBaud)); // 16.5 -> 17, -2.9 %
+    assert!(baud::cortex_m3(8_000_000, 484_848, 30_000).is_ok());
+}
+
+#[test]
+fn atmega328p_init_programs_u2x0() {
+    let clocks = Clocks::single(16_000_000);
+    sim::reset();
+    Atmega328p::usart_init(UsartConfig::new(115_200), &clocks).unwrap();
+    assert_eq!(sim::peek(UCSR0A), U2X0);
+    assert_eq!(sim::peek(UBRR0L), 16);
+
+    Atmega328p::usart_init(UsartConfig::new(9600), &clocks).unwrap();
+    assert_eq!(sim::peek(UCSR0A), 0);
+    assert_eq!(sim::peek(UBRR0L), 103);
+
+    assert_eq!(Atmega328p::usart_init(UsartConfig::new(230_400), &clocks), Err(HalError::InvalidBaud));
+}
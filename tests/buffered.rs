@@ -0,0 +1,140 @@
+#![cfg(feature = "host-sim")]
+
+use hal_project::clock::Clocks;
+use hal_project::sim;
+use hal_project::sim::models::{AlwaysSet, ClearOnRead, ClearOnWrite};
+use hal_project::sim::trace;
+use hal_project::timeout::{set_timeout, Timeout};
+use hal_project::usart::buffered::BufferedSerial;
+use hal_project::usart::{atmega328p::Atmega328p, cortex_m3::CortexM3, UsartConfig, USART};
+use hal_project::HalError;
+
+const CLOCKS: Clocks = Clocks::single(16_000_000);
+
+const USART2_SR: usize = 0x4000_4400;
+const USART2_DR: usize = 0x4000_4404;
+const USART2_CR1: usize = 0x4000_440C;
+const TXE: u32 = 1 << 7;
+const TC: u32 = 1 << 6;
+const RXNE: u32 = 1 << 5;
+const FE: u32 = 1 << 1;
+const TXEIE: u32 = 1 << 7;
+const RXNEIE: u32 = 1 << 5;
+
+const UCSR0A: usize = 0xC0;
+const UCSR0B: usize = 0xC1;
+const UDR0: usize = 0xC6;
+const RXC0: u32 = 1 << 7;
+const UDRE0: u32 = 1 << 5;
+const RXCIE0: u32 = 1 << 7;
+const UDRIE0: u32 = 1 << 5;
+
+// A byte arriving on the Cortex-M3 line: RXNE is set until the data register is read
+fn receive(serial: &BufferedSerial<CortexM3, 4, 4>, byte: u8) {
+    sim::poke(USART2_DR, byte as u32);
+    sim::poke(USART2_SR, sim::peek(USART2_SR) | RXNE);
+    serial.on_interrupt();
+}
+
+#[test]
+fn received_bytes_wait_in_the_rx_queue() {
+    sim::reset();
+    sim::attach(ClearOnRead { trigger: USART2_DR, target: USART2_SR, mask: RXNE });
+    let serial = BufferedSerial::<CortexM3, 4, 4>::new();
+    serial.init(UsartConfig::new(115_200), &CLOCKS).unwrap();
+    assert_eq!(sim::peek(USART2_CR1) & RXNEIE, RXNEIE);
+
+    assert_eq!(serial.try_read(), Ok(None));
+    receive(&serial, b'a');
+    receive(&serial, b'b');
+    assert_eq!(serial.available(), 2);
+    assert_eq!(serial.try_read(), Ok(Some(b'a')));
+    assert_eq!(serial.read(), Ok(b'b'));
+    assert_eq!(serial.available(), 0);
+}
+
+#[test]
+fn full_rx_queue_reports_an_overrun_once() {
+    sim::reset();
+    sim::attach(ClearOnRead { trigger: USART2_DR, target: USART2_SR, mask: RXNE });
+    let serial = BufferedSerial::<CortexM3, 4, 4>::new();
+    for byte in 1..=5 {
+        receive(&serial, byte);
+    }
+    assert_eq!(serial.available(), 3); // 4 slots hold 3 bytes
+    assert_eq!(serial.try_read(), Err(HalError::Overrun));
+    assert_eq!(serial.try_read(), Ok(Some(1)));
+    assert_eq!(serial.try_read(), Ok(Some(2)));
+    assert_eq!(serial.try_read(), Ok(Some(3)));
+    assert_eq!(serial.try_read(), Ok(None));
+}
+
+#[test]
+fn line_errors_drop_the_byte_and_are_reported() {
+    sim::reset();
+    sim::attach(ClearOnRead { trigger: USART2_DR, target: USART2_SR, mask: RXNE | FE });
+    let serial = BufferedSerial::<CortexM3, 4, 4>::new();
+    receive(&serial, b'a');
+    sim::poke(USART2_SR, FE);
+    receive(&serial, b'?');
+    receive(&serial, b'b');
+
+    assert_eq!(serial.try_read(), Err(HalError::Framing));
+    assert_eq!(serial.try_read(), Ok(Some(b'a')));
+    assert_eq!(serial.try_read(), Ok(Some(b'b')));
+    assert_eq!(CortexM3::usart_errors().framing, 1);
+}
+
+#[test]
+fn writes_are_queued_and_sent_from_the_interrupt() {
+    sim::reset();
+    let serial = BufferedSerial::<CortexM3, 4, 4>::new();
+    assert_eq!(serial.try_write(b"abcde"), 3); // The rest does not fit
+    assert_eq!(sim::peek(USART2_CR1) & TXEIE, TXEIE);
+    assert!(trace::take().at(USART2_DR).writes().accesses().is_empty());
+
+    sim::attach(AlwaysSet { addr: USART2_SR, mask: TXE | TC });
+    serial.on_interrupt();
+    let sent: Vec<u32> = trace::take().at(USART2_DR).writes().accesses().iter().map(|a| a.value).collect();
+    assert_eq!(sent, [b'a' as u32, b'b' as u32, b'c' as u32]);
+    assert_eq!(sim::peek(USART2_CR1) & TXEIE, 0); // Queue empty, TXE no longer listened to
+    serial.flush().unwrap();
+}
+
+#[test]
+fn write_and_flush_time_out_without_the_interrupt() {
+    sim::reset();
+    set_timeout(Timeout::Iterations(10));
+    let serial = BufferedSerial::<CortexM3, 4, 4>::new();
+    serial.write(b"abc").unwrap();
+    assert_eq!(serial.write(b"d"), Err(HalError::Timeout));
+    assert_eq!(serial.flush(), Err(HalError::Timeout));
+    assert_eq!(serial.read(), Err(HalError::Timeout));
+}
+
+#[test]
+fn atmega328p_uses_rx_complete_and_data_register_empty_interrupts() {
+    sim::reset();
+    sim::attach(ClearOnRead { trigger: UDR0, target: UCSR0A, mask: RXC0 });
+    sim::attach(ClearOnWrite { trigger: UDR0, target: UCSR0A, mask: UDRE0 });
+    let serial = BufferedSerial::<Atmega328p, 8, 8>::new();
+    serial.init(UsartConfig::new(9600), &CLOCKS).unwrap();
+    assert_eq!(sim::peek(UCSR0B) & RXCIE0, RXCIE0);
+
+    serial.write(b"hi").unwrap();
+    assert_eq!(sim::peek(UCSR0B) & UDRIE0, UDRIE0);
+
+    // One byte is sent per UDRE interrupt, the data register is busy right after a write
+    sim::poke(UCSR0A, UDRE0);
+    serial.on_interrupt();
+    sim::poke(UCSR0A, UDRE0 | RXC0);
+    sim::poke(UDR0, b'x' as u32);
+    serial.on_interrupt();
+    sim::poke(UCSR0A, UDRE0);
+    serial.on_interrupt();
+
+    let sent: Vec<u32> = trace::take().at(UDR0).writes().accesses().iter().map(|a| a.value).collect();
+    assert_eq!(sent, [b'h' as u32, b'i' as u32]);
+    assert_eq!(sim::peek(UCSR0B) & UDRIE0, 0);
+    assert_eq!(serial.try_read(), Ok(Some(b'x')));
+}
@@ -0,0 +1,118 @@
+#![cfg(feature = "host-sim")]
+
+use hal_project::clock::atmega328p::{self, Atmega328p};
+use hal_project::clock::cortex_m3::{self, CortexM3, PllSource, SysClkSource};
+use hal_project::clock::{Clock, Clocks};
+use hal_project::sim::trace::{self, Expected};
+use hal_project::sim::{self, Peripheral, RegisterFile};
+use hal_project::HalError;
+
+const CLKPR: usize = 0x61;
+
+const RCC_CR: usize = 0x4002_1000;
+const RCC_CFGR: usize = 0x4002_1004;
+const FLASH_ACR: usize = 0x4002_2000;
+const HSEON: u32 = 1 << 16;
+const HSERDY: u32 = 1 << 17;
+const PLLON: u32 = 1 << 24;
+const PLLRDY: u32 = 1 << 25;
+
+// RCC of the Cortex-M3: oscillators are ready as soon as they are enabled and SWS follows SW
+struct Rcc;
+
+impl Peripheral for Rcc {
+    fn after_write(&mut self, regs: &mut RegisterFile, addr: usize, value: u32) {
+        if addr == RCC_CR {
+            if value & HSEON != 0 {
+                regs.set_bits(RCC_CR, HSERDY);
+            }
+            if value & PLLON != 0 {
+                regs.set_bits(RCC_CR, PLLRDY);
+            }
+        }
+        if addr == RCC_CFGR {
+            regs.set(RCC_CFGR, (value & !0b1100) | ((value & 0b11) << 2));
+        }
+    }
+}
+
+#[test]
+fn atmega328p_prescaler_is_unlocked_then_written() {
+    sim::reset();
+    let config = atmega328p::ClockConfig { prescaler: 2, ..Default::default() };
+    assert_eq!(Atmega328p::clock_init(config).unwrap(), Clocks::single(8_000_000));
+    trace::assert_trace(&Expected::new().write8(CLKPR, 0x80).write8(CLKPR, 0x01));
+    assert_eq!(Atmega328p::clocks(), Clocks::single(8_000_000));
+}
+
+#[test]
+fn atmega328p_rejects_invalid_configuration() {
+    sim::reset();
+    let config = atmega328p::ClockConfig { prescaler: 3, ..Default::default() };
+    assert_eq!(Atmega328p::clock_init(config), Err(HalError::InvalidClock));
+    let config = atmega328p::ClockConfig { source: atmega328p::ClockSource::ExternalCrystal(24_000_000), prescaler: 1 };
+    assert_eq!(Atmega328p::clock_init(config), Err(HalError::InvalidClock));
+    assert_eq!(Atmega328p::clocks(), Clocks::single(16_000_000)); // Untouched reset values
+}
+
+#[test]
+fn cortex_m3_default_keeps_hsi() {
+    sim::reset();
+    sim::attach(Rcc);
+    assert_eq!(CortexM3::clock_init(cortex_m3::ClockConfig::default()).unwrap(), Clocks::single(8_000_000));
+    assert_eq!(sim::peek(RCC_CR) & HSEON, 0);
+    assert_eq!(sim::peek(RCC_CFGR), 0);
+}
+
+#[test]
+fn cortex_m3_pll_from_hse_runs_at_72_mhz() {
+    sim::reset();
+    sim::attach(Rcc);
+    let config = cortex_m3::ClockConfig {
+        source: SysClkSource::Pll { source: PllSource::Hse(8_000_000), mul: 9 },
+        apb1_prescaler: 2,
+        ..Default::default()
+    };
+    let clocks = CortexM3::clock_init(config).unwrap();
+    assert_eq!(clocks, Clocks { sysclk: 72_000_000, hclk: 72_000_000, pclk1: 36_000_000, pclk2: 72_000_000 });
+    assert_eq!(CortexM3::clocks(), clocks);
+
+    assert_eq!(sim::peek(FLASH_ACR), (1 << 4) | 2); // Prefetch and two wait states
+    assert_eq!(sim::peek(RCC_CR), HSEON | HSERDY | PLLON | PLLRDY);
+    // PLLMUL = 9 - 2, PLLSRC = HSE, PPRE1 = /2, SW = SWS = PLL
+    assert_eq!(sim::peek(RCC_CFGR), (7 << 18) | (1 << 16) | (0b100 << 8) | (0b10 << 2) | 0b10);
+}
+
+#[test]
+fn cortex_m3_flash_latency_is_set_before_switching() {
+    sim::reset();
+    sim::attach(Rcc);
+    let config = cortex_m3::ClockConfig { source: SysClkSource::Pll { source: PllSource::HsiDiv2, mul: 8 }, ..Default::default() };
+    assert_eq!(CortexM3::clock_init(config).unwrap().sysclk, 32_000_000);
+
+    let writes = trace::take().writes();
+    let position = |addr| writes.accesses().iter().position(|access| access.addr == addr).unwrap();
+    let last_cfgr = writes.accesses().iter().rposition(|access| access.addr == RCC_CFGR).unwrap();
+    assert!(position(FLASH_ACR) < last_cfgr);
+    assert_eq!(sim::peek(FLASH_ACR) & 0b111, 1);
+}
+
+#[test]
+fn cortex_m3_rejects_out_of_spec_trees() {
+    sim::reset();
+    sim::attach(Rcc);
+    let too_fast = SysClkSource::Pll { source: PllSource::Hse(8_000_000), mul: 10 };
+    let config = cortex_m3::ClockConfig { source: too_fast, apb1_prescaler: 2, ..Default::default() };
+    assert_eq!(CortexM3::clock_init(config), Err(HalError::InvalidClock));
+
+    let apb1_too_fast = SysClkSource::Pll { source: PllSource::Hse(8_000_000), mul: 9 };
+    let config = cortex_m3::ClockConfig { source: apb1_too_fast, ..Default::default() };
+    assert_eq!(CortexM3::clock_init(config), Err(HalError::InvalidClock));
+
+    let config = cortex_m3::ClockConfig { ahb_prescaler: 32, ..Default::default() };
+    assert_eq!(CortexM3::clock_init(config), Err(HalError::InvalidClock));
+
+    let config = cortex_m3::ClockConfig { source: SysClkSource::Hse(25_000_000), ..Default::default() };
+    assert_eq!(CortexM3::clock_init(config), Err(HalError::InvalidClock));
+    assert!(trace::take().accesses().is_empty()); // Nothing is touched when the configuration is rejected
+}
@@ -0,0 +1,108 @@
+// Peripheral models shared by the host-side driver tests
+#![allow(dead_code)]
+
+use std::future::Future;
+use std::pin::pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+
+use hal_project::sim::{Peripheral, RegisterFile};
+
+pub const TWSR: usize = 0xB9;
+pub const TWDR: usize = 0xBB;
+pub const TWCR: usize = 0xBC;
+pub const TWINT: u32 = 1 << 7;
+pub const TWEA: u32 = 1 << 6;
+pub const TWSTA: u32 = 1 << 5;
+pub const TWSTO: u32 = 1 << 4;
+pub const TWEN: u32 = 1 << 2;
+
+// TWI of the ATmega328p with a single slave at `address` on the bus
+// Every command written to TWCR completes immediately and TWSR reports the status a real bus would give,
+// any other address is NACKed
+pub struct TwiBus {
+    pub address: u8,
+    reading: bool,
+    held: bool, // A START was sent and no STOP yet: the next START is a repeated START
+}
+
+impl TwiBus {
+    pub fn new(address: u8) -> Self {
+        TwiBus { address, reading: false, held: false }
+    }
+}
+
+impl Peripheral for TwiBus {
+    fn after_write(&mut self, regs: &mut RegisterFile, addr: usize, value: u32) {
+        if addr != TWCR || value & TWINT == 0 {
+            return;
+        }
+        if value & TWSTO != 0 {
+            self.held = false;
+            return;
+        }
+        let status = if value & TWSTA != 0 {
+            let status = if self.held { 0x10 } else { 0x08 }; // (repeated) START transmitted
+            self.held = true;
+            status
+        } else if matches!(regs.get(TWSR) & 0xF8, 0x08 | 0x10) {
+            let sla = regs.get(TWDR) as u8;
+            self.reading = sla & 1 == 1;
+            match (sla >> 1 == self.address, self.reading) {
+                (true, false) => 0x18,
+                (false, false) => 0x20,
+                (true, true) => 0x40,
+                (false, true) => 0x48,
+            }
+        } else if self.reading {
+            if value & TWEA != 0 { 0x50 } else { 0x58 }
+        } else {
+            0x28 // Data transmitted, ACK received
+        };
+        regs.set(TWSR, status);
+        regs.set_bits(TWCR, TWINT);
+    }
+}
+
+// Slave that answers every read of the data register with the same byte
+pub struct ConstantSlave {
+    pub data_register: usize,
+    pub byte: u32,
+}
+
+impl Peripheral for ConstantSlave {
+    fn before_read(&mut self, regs: &mut RegisterFile, addr: usize) {
+        if addr == self.data_register {
+            regs.set(addr, self.byte);
+        }
+    }
+}
+
+#[derive(Default)]
+struct WakeFlag(AtomicBool);
+
+impl Wake for WakeFlag {
+    fn wake(self: Arc<Self>) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+// Single-future executor for the async drivers
+// Every time the future is pending, `hardware` lets the simulated peripheral progress and calls its interrupt
+// handler, and the future must have been woken before it is polled again.
+// Returns the output and the number of times the future was pending
+pub fn block_on<F: Future>(future: F, mut hardware: impl FnMut()) -> (F::Output, usize) {
+    let flag = Arc::new(WakeFlag::default());
+    let waker = Waker::from(flag.clone());
+    let mut cx = Context::from_waker(&waker);
+    let mut future = pin!(future);
+    for pending in 0..100 {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return (output, pending);
+        }
+        hardware();
+        assert!(flag.0.swap(false, Ordering::SeqCst), "the interrupt handler did not wake the task");
+    }
+    panic!("the future never completed");
+}
@@ -0,0 +1,155 @@
+#![cfg(feature = "host-sim")]
+
+use embedded_hal::blocking::i2c::{Write as I2cWrite, WriteRead};
+use embedded_hal::blocking::serial::Write as _;
+use embedded_hal::blocking::spi::{Transfer, Write as SpiWrite};
+use embedded_hal::digital::v2::{InputPin, OutputPin, StatefulOutputPin, ToggleableOutputPin};
+use embedded_hal::serial::{Read, Write};
+use embedded_hal::spi::FullDuplex;
+use hal_project::clock::Clocks;
+use hal_project::gpio::atmega328p::PortB;
+use hal_project::gpio::cortex_m3::GpioA;
+use hal_project::gpio::pin::{Pin, Unconfigured};
+use hal_project::i2c::bus::I2c;
+use hal_project::usart::serial::Serial;
+use hal_project::usart::UsartConfig;
+use hal_project::sim;
+use hal_project::sim::models::{AlwaysSet, ClearOnWrite, SetOnWrite};
+use hal_project::sim::trace;
+use hal_project::spi::bus::Spi;
+use hal_project::spi::SpiConfig;
+use hal_project::{i2c, spi, usart, HalError};
+
+mod common;
+use common::*;
+
+const CLOCKS: Clocks = Clocks::single(16_000_000);
+
+// Stand-ins for driver crates: they only know the embedded-hal traits
+fn blink<P: OutputPin + ToggleableOutputPin<Error = <P as OutputPin>::Error>>(led: &mut P) -> Result<(), <P as OutputPin>::Error> {
+    led.set_high()?;
+    led.toggle()
+}
+
+fn read_register<B: WriteRead>(bus: &mut B, address: u8, register: u8) -> Result<u8, B::Error> {
+    let mut value = [0u8];
+    bus.write_read(address, &[register], &mut value)?;
+    Ok(value[0])
+}
+
+#[test]
+fn pins_work_through_the_digital_traits() {
+    sim::reset();
+    let mut led = Pin::<PortB, 5, Unconfigured>::new().into_push_pull_output().unwrap();
+    blink(&mut led).unwrap();
+    assert!(StatefulOutputPin::is_set_low(&led).unwrap());
+
+    let button = Pin::<GpioA, 0, Unconfigured>::new().into_pull_up_input().unwrap();
+    sim::poke(0x4800_0010, 1);
+    assert!(InputPin::is_high(&button).unwrap());
+}
+
+#[test]
+fn serial_write_read_and_flush() {
+    sim::reset();
+    sim::attach(AlwaysSet { addr: 0x4000_4400, mask: (1 << 7) | (1 << 6) | (1 << 5) }); // TXE, TC, RXNE
+    let mut serial = Serial::<usart::cortex_m3::CortexM3>::init(UsartConfig::new(115_200), &CLOCKS).unwrap();
+    serial.bwrite_all(b"ok").unwrap();
+    serial.bflush().unwrap();
+    assert_eq!(sim::peek(0x4000_4404), b'k' as u32);
+
+    sim::poke(0x4000_4404, b'x' as u32);
+    assert_eq!(Read::read(&mut serial).unwrap(), b'x');
+}
+
+#[test]
+fn serial_read_and_write_would_block_until_the_flags_are_set() {
+    sim::reset();
+    let mut avr = Serial::<usart::atmega328p::Atmega328p>::new();
+    let mut stm = Serial::<usart::cortex_m3::CortexM3>::new();
+    assert_eq!(Read::read(&mut avr), Err(nb::Error::WouldBlock));
+    assert_eq!(Read::read(&mut stm), Err(nb::Error::WouldBlock));
+    assert_eq!(Write::write(&mut avr, b'a'), Err(nb::Error::WouldBlock));
+    assert_eq!(Write::write(&mut stm, b'a'), Err(nb::Error::WouldBlock));
+    assert!(trace::take().writes().accesses().is_empty()); // Nothing was sent or waited for
+
+    sim::poke(0xC0, 1 << 7); // RXC0
+    sim::poke(0xC6, 0x42);
+    assert_eq!(Read::read(&mut avr), Ok(0x42));
+}
+
+#[test]
+fn atmega328p_flush_waits_for_txc0_only_after_a_write() {
+    sim::reset();
+    let mut serial = Serial::<usart::atmega328p::Atmega328p>::new();
+    Write::flush(&mut serial).unwrap();
+    assert!(trace::take().accesses().is_empty()); // Nothing sent, nothing to wait for
+
+    sim::attach(AlwaysSet { addr: 0xC0, mask: 1 << 5 }); // UDRE0, TXC0 never comes
+    sim::attach(ClearOnWrite { trigger: 0xC0, target: 0xC0, mask: 1 << 6 }); // TXC0 is cleared by writing a one
+    hal_project::timeout::set_timeout(hal_project::timeout::Timeout::Iterations(10));
+    Write::write(&mut serial, b'a').unwrap();
+    assert_eq!(Write::flush(&mut serial), Err(nb::Error::Other(HalError::Timeout)));
+}
+
+#[test]
+fn spi_full_duplex_and_blocking_transfers() {
+    sim::reset();
+    sim::attach(SetOnWrite { trigger: 0x4E, target: 0x4D, mask: 1 << 7 }); // SPIF once SPDR is loaded
+    let mut bus = Spi::<spi::atmega328p::Atmega328p>::init_master(SpiConfig::default(), &CLOCKS).unwrap();
+
+    // The simulated data register is a loopback: MOSI is wired to MISO
+    let mut words = [1, 2, 3];
+    assert_eq!(bus.transfer(&mut words).unwrap(), [1, 2, 3]);
+    SpiWrite::write(&mut bus, &[0x42]).unwrap();
+    assert_eq!(sim::peek(0x4E), 0x42);
+
+    FullDuplex::send(&mut bus, 0x77).unwrap();
+    assert_eq!(FullDuplex::read(&mut bus).unwrap(), 0x77);
+}
+
+#[test]
+fn atmega328p_write_read_uses_a_repeated_start() {
+    sim::reset();
+    sim::attach(TwiBus::new(0x42));
+    sim::attach(ConstantSlave { data_register: TWDR, byte: 0x5A });
+    let mut bus = I2c::<i2c::atmega328p::Atmega328p>::new();
+    assert_eq!(read_register(&mut bus, 0x42, 0x0F).unwrap(), 0x5A);
+
+    let commands: Vec<u32> = trace::take().at(TWCR).writes().accesses().iter().map(|a| a.value).collect();
+    assert_eq!(
+        commands,
+        [
+            TWINT | TWSTA | TWEN,
+            TWINT | TWEN,
+            TWINT | TWEN,
+            TWINT | TWSTA | TWEN, // Repeated START, no STOP in between
+            TWINT | TWEN,
+            TWINT | TWEN,
+            TWINT | TWSTO | TWEN,
+        ]
+    );
+
+    assert_eq!(I2cWrite::write(&mut bus, 0x50, &[0x01]), Err(HalError::Nack));
+}
+
+#[test]
+fn cortex_m3_write_read_sends_stop_once() {
+    const I2C_CR1: usize = 0x4000_5400;
+    const I2C_DR: usize = 0x4000_5410;
+    const I2C_SR1: usize = 0x4000_5414;
+    const STOP: u32 = 1 << 9;
+
+    sim::reset();
+    sim::attach(SetOnWrite { trigger: I2C_CR1, target: I2C_SR1, mask: 1 << 0 }); // SB
+    sim::attach(SetOnWrite { trigger: I2C_DR, target: I2C_SR1, mask: 1 << 1 }); // ADDR
+    sim::attach(AlwaysSet { addr: I2C_SR1, mask: (1 << 7) | (1 << 6) }); // TXE, RXNE
+    sim::attach(ConstantSlave { data_register: I2C_DR, byte: 0x33 });
+    let mut bus = I2c::<i2c::cortex_m3::CortexM3>::new();
+    assert_eq!(read_register(&mut bus, 0x42, 0x0F).unwrap(), 0x33);
+
+    let cr1 = trace::take().at(I2C_CR1).writes();
+    let stops = cr1.accesses().iter().filter(|a| a.value & STOP != 0).count();
+    assert_eq!(stops, 1);
+    assert_ne!(cr1.accesses().last().unwrap().value & STOP, 0);
+}
@@ -0,0 +1,229 @@
+#![cfg(all(feature = "host-sim", feature = "embedded-hal-1"))]
+
+use embedded_hal_1::digital::{InputPin, OutputPin, StatefulOutputPin};
+use embedded_hal_1::i2c::{I2c as _, Operation as I2cOperation};
+use embedded_hal_1::spi::{Operation as SpiOperation, SpiBus, SpiDevice};
+use embedded_io::{Read, Write};
+use hal_project::clock::Clocks;
+use hal_project::delay::Delay;
+use hal_project::gpio::atmega328p::PortB;
+use hal_project::gpio::cortex_m3::GpioA;
+use hal_project::gpio::pin::{Pin, Unconfigured};
+use hal_project::i2c::bus::I2c;
+use hal_project::sim;
+use hal_project::sim::models::{AlwaysSet, SetOnWrite};
+use hal_project::sim::trace;
+use hal_project::spi::bus::Spi;
+use hal_project::spi::SpiConfig;
+use hal_project::spi::device::{ExclusiveDevice, SharedBus, SpiDevice as SharedSpiDevice};
+use hal_project::timeout::{set_timeout, Timeout};
+use hal_project::usart::serial::Serial;
+use hal_project::usart::UsartConfig;
+use hal_project::{i2c, spi, usart, HalError};
+
+mod common;
+use common::*;
+
+const CLOCKS: Clocks = Clocks::single(16_000_000);
+
+const PORTB: usize = 0x25;
+const SPSR: usize = 0x4D;
+const SPDR: usize = 0x4E;
+const SPIF: u32 = 1 << 7;
+
+// Stand-in for a driver crate written against embedded-hal 1.0
+fn read_register<B: embedded_hal_1::i2c::I2c>(bus: &mut B, address: u8, register: u8) -> Result<u8, B::Error> {
+    let mut value = [0u8];
+    bus.write_read(address, &[register], &mut value)?;
+    Ok(value[0])
+}
+
+#[test]
+fn errors_map_to_error_kinds() {
+    use embedded_hal_1::i2c::{Error as _, ErrorKind, NoAcknowledgeSource};
+    use embedded_hal_1::spi::ErrorKind as SpiErrorKind;
+    use embedded_io::ErrorKind as IoErrorKind;
+
+    assert_eq!(HalError::Nack.kind(), ErrorKind::NoAcknowledge(NoAcknowledgeSource::Unknown));
+    assert_eq!(HalError::ArbitrationLost.kind(), ErrorKind::ArbitrationLoss);
+    assert_eq!(HalError::BusError.kind(), ErrorKind::Bus);
+    assert_eq!(HalError::Timeout.kind(), ErrorKind::Other);
+    assert_eq!(embedded_hal_1::spi::Error::kind(&HalError::Overrun), SpiErrorKind::Overrun);
+    assert_eq!(embedded_hal_1::spi::Error::kind(&HalError::Framing), SpiErrorKind::FrameFormat);
+    assert_eq!(embedded_io::Error::kind(&HalError::Parity), IoErrorKind::InvalidData);
+    assert_eq!(embedded_io::Error::kind(&HalError::Timeout), IoErrorKind::TimedOut);
+    assert_eq!(embedded_io::Error::kind(&HalError::InvalidBaud), IoErrorKind::InvalidInput);
+}
+
+#[test]
+fn pins_work_through_the_digital_traits() {
+    sim::reset();
+    let mut led = Pin::<PortB, 5, Unconfigured>::new().into_push_pull_output().unwrap();
+    OutputPin::set_high(&mut led).unwrap();
+    StatefulOutputPin::toggle(&mut led).unwrap();
+    assert!(StatefulOutputPin::is_set_low(&mut led).unwrap());
+
+    let mut button = Pin::<GpioA, 0, Unconfigured>::new().into_pull_up_input().unwrap();
+    sim::poke(0x4800_0010, 1);
+    assert!(InputPin::is_high(&mut button).unwrap());
+}
+
+#[test]
+fn spi_bus_pads_uneven_transfers() {
+    sim::reset();
+    sim::attach(SetOnWrite { trigger: SPDR, target: SPSR, mask: SPIF });
+    let mut bus = Spi::<spi::atmega328p::Atmega328p>::init_master(SpiConfig::default(), &CLOCKS).unwrap();
+    trace::clear();
+
+    // The simulated data register is a loopback: MOSI is wired to MISO
+    let mut read = [0xFF; 3];
+    SpiBus::transfer(&mut bus, &mut read, &[1, 2]).unwrap();
+    assert_eq!(read, [1, 2, 0]);
+    let sent: Vec<u32> = trace::take().at(SPDR).writes().accesses().iter().map(|a| a.value).collect();
+    assert_eq!(sent, [1, 2, 0]);
+
+    let mut read = [0xFF; 1];
+    SpiBus::transfer(&mut bus, &mut read, &[7, 8]).unwrap();
+    assert_eq!(read, [7]);
+    assert_eq!(sim::peek(SPDR), 8);
+}
+
+#[test]
+fn spi_bus_moves_sixteen_bit_words() {
+    sim::reset();
+    sim::attach(SetOnWrite { trigger: SPDR, target: SPSR, mask: SPIF });
+    let bus = Spi::<spi::atmega328p::Atmega328p>::init_master(SpiConfig::default(), &CLOCKS).unwrap();
+    let mut bus = bus.into_words::<u16>();
+    trace::clear();
+
+    let mut words = [0x0102, 0x0304];
+    bus.transfer_in_place(&mut words).unwrap();
+    assert_eq!(words, [0x0102, 0x0304]); // Loopback
+    let sent: Vec<u32> = trace::take().at(SPDR).writes().accesses().iter().map(|a| a.value).collect();
+    assert_eq!(sent, [1, 2, 3, 4]);
+}
+
+#[test]
+fn spi_device_selects_the_slave_around_a_transaction() {
+    sim::reset();
+    sim::attach(SetOnWrite { trigger: SPDR, target: SPSR, mask: SPIF });
+    let bus = Spi::<spi::atmega328p::Atmega328p>::init_master(SpiConfig::default(), &CLOCKS).unwrap();
+    let cs = Pin::<PortB, 2, Unconfigured>::new().into_push_pull_output().unwrap();
+    let mut device = ExclusiveDevice::new(bus, cs, Delay::new(&CLOCKS)).unwrap();
+    trace::clear();
+
+    let mut buffer = [0u8; 2];
+    device
+        .transaction(&mut [SpiOperation::Write(&[0x9F]), SpiOperation::DelayNs(100), SpiOperation::Read(&mut buffer)])
+        .unwrap();
+    let cs_levels: Vec<u32> = trace::take().at(PORTB).writes().accesses().iter().map(|a| a.value & (1 << 2)).collect();
+    assert_eq!(cs_levels, [0, 1 << 2]);
+
+    // A failed transfer still deselects the slave
+    sim::reset();
+    set_timeout(Timeout::Iterations(10));
+    assert_eq!(device.write(&[0x01]), Err(HalError::Timeout));
+    assert_ne!(sim::peek(PORTB) & (1 << 2), 0);
+}
+
+#[test]
+fn shared_bus_devices_implement_spi_device() {
+    sim::reset();
+    sim::attach(SetOnWrite { trigger: SPDR, target: SPSR, mask: SPIF });
+    let bus = SharedBus::<spi::atmega328p::Atmega328p>::new(CLOCKS);
+    let cs = Pin::<PortB, 2, Unconfigured>::new().into_push_pull_output().unwrap();
+    let config = SpiConfig { fill_byte: 0xFF, ..SpiConfig::default() };
+    let mut device = SharedSpiDevice::new(&bus, cs, config, Delay::new(&CLOCKS)).unwrap();
+    trace::clear();
+
+    let mut buffer = [0u8; 2];
+    device.transaction(&mut [SpiOperation::Write(&[0x03]), SpiOperation::Read(&mut buffer)]).unwrap();
+    assert_eq!(buffer, [0xFF, 0xFF]); // Loopback of the fill byte
+    let cs_levels: Vec<u32> = trace::take().at(PORTB).writes().accesses().iter().map(|a| a.value & (1 << 2)).collect();
+    assert_eq!(cs_levels, [0, 1 << 2]);
+
+    // The same slave switched to 16-bit words
+    let mut device = device.into_words::<u16>();
+    trace::clear();
+    device.write(&[0x0A0B]).unwrap();
+    let sent: Vec<u32> = trace::take().at(SPDR).writes().accesses().iter().map(|a| a.value).collect();
+    assert_eq!(sent, [0x0A, 0x0B]);
+}
+
+#[test]
+fn atmega328p_transaction_merges_operations_of_the_same_kind() {
+    sim::reset();
+    sim::attach(TwiBus::new(0x42));
+    sim::attach(ConstantSlave { data_register: TWDR, byte: 0x5A });
+    let mut bus = I2c::<i2c::atmega328p::Atmega328p>::new();
+    let (mut first, mut second) = ([0u8; 1], [0u8; 1]);
+    bus.transaction(
+        0x42,
+        &mut [
+            I2cOperation::Write(&[0x01]),
+            I2cOperation::Write(&[0x02]),
+            I2cOperation::Read(&mut first),
+            I2cOperation::Read(&mut second),
+        ],
+    )
+    .unwrap();
+    assert_eq!((first, second), ([0x5A], [0x5A]));
+
+    let commands: Vec<u32> = trace::take().at(TWCR).writes().accesses().iter().map(|a| a.value).collect();
+    assert_eq!(
+        commands,
+        [
+            TWINT | TWSTA | TWEN,
+            TWINT | TWEN,
+            TWINT | TWEN,
+            TWINT | TWEN,         // Second write continues the first one
+            TWINT | TWSTA | TWEN, // Repeated START for the direction change
+            TWINT | TWEN,
+            TWINT | TWEN | TWEA,  // First read is not the last byte, ACKed
+            TWINT | TWEN,
+            TWINT | TWSTO | TWEN,
+        ]
+    );
+}
+
+#[test]
+fn cortex_m3_transaction_nacks_only_the_last_byte_read() {
+    const I2C_CR1: usize = 0x4000_5400;
+    const I2C_DR: usize = 0x4000_5410;
+    const I2C_SR1: usize = 0x4000_5414;
+    const START: u32 = 1 << 8;
+    const STOP: u32 = 1 << 9;
+    const ACK: u32 = 1 << 10;
+
+    sim::reset();
+    sim::attach(SetOnWrite { trigger: I2C_CR1, target: I2C_SR1, mask: 1 << 0 }); // SB
+    sim::attach(SetOnWrite { trigger: I2C_DR, target: I2C_SR1, mask: 1 << 1 }); // ADDR
+    sim::attach(AlwaysSet { addr: I2C_SR1, mask: (1 << 7) | (1 << 6) }); // TXE, RXNE
+    sim::attach(ConstantSlave { data_register: I2C_DR, byte: 0x33 });
+    let mut bus = I2c::<i2c::cortex_m3::CortexM3>::new();
+    assert_eq!(read_register(&mut bus, 0x42, 0x0F).unwrap(), 0x33);
+
+    let (mut first, mut second) = ([0u8; 1], [0u8; 1]);
+    trace::clear();
+    bus.transaction(0x42, &mut [I2cOperation::Read(&mut first), I2cOperation::Read(&mut second)]).unwrap();
+    let cr1: Vec<u32> = trace::take().at(I2C_CR1).writes().accesses().iter().map(|a| a.value).collect();
+    assert_eq!(cr1[0] & ACK, ACK); // Two bytes in the run, ACK before addressing the slave
+    assert_eq!(cr1[1] & (ACK | START), ACK | START);
+    assert_eq!(cr1.iter().filter(|&&value| value & START != 0 && value & ACK == 0).count(), 2); // ACK cleared before the last byte, then STOP
+    assert_ne!(cr1.last().unwrap() & STOP, 0);
+}
+
+#[test]
+fn serial_is_an_io_stream() {
+    sim::reset();
+    sim::attach(AlwaysSet { addr: 0x4000_4400, mask: (1 << 7) | (1 << 6) | (1 << 5) }); // TXE, TC, RXNE
+    let mut serial = Serial::<usart::cortex_m3::CortexM3>::init(UsartConfig::new(115_200), &CLOCKS).unwrap();
+    serial.write_all(b"ok").unwrap();
+    serial.flush().unwrap();
+    assert_eq!(sim::peek(0x4000_4404), b'k' as u32);
+
+    sim::poke(0x4000_4404, b'x' as u32);
+    let mut buffer = [0u8; 4];
+    assert_eq!(serial.read(&mut buffer).unwrap(), 1);
+    assert_eq!(buffer[0], b'x');
+}
@@ -0,0 +1,148 @@
+#![cfg(feature = "host-sim")]
+
+use hal_project::gpio::atmega328p::Atmega328p;
+use hal_project::gpio::cortex_m3::CortexM3;
+use hal_project::gpio::{PinMode, PinValue, Port, GPIO};
+use hal_project::sim;
+use hal_project::HalError;
+use hal_project::sim::trace::{self, Expected};
+
+const DDRB: usize = 0x24;
+const PORTB: usize = 0x25;
+const PINB: usize = 0x23;
+const PINC: usize = 0x26;
+const DDRD: usize = 0x2A;
+const PORTD: usize = 0x2B;
+
+const GPIOA_MODER: usize = 0x4800_0000;
+const GPIOA_ODR: usize = 0x4800_0014;
+const GPIOA_IDR: usize = 0x4800_0010;
+const GPIOE_MODER: usize = 0x4800_1000;
+const GPIOE_ODR: usize = 0x4800_1014;
+const RCC_AHBENR: usize = 0x4002_1014;
+
+#[test]
+fn atmega328p_output_pin_drives_portb() {
+    sim::reset();
+    Atmega328p::configure_pin(Port::B, 5, PinMode::Output).unwrap();
+    Atmega328p::write_pin(Port::B, 5, PinValue::High).unwrap();
+    assert_eq!(sim::peek(DDRB), 1 << 5);
+    assert_eq!(sim::peek(PORTB), 1 << 5);
+
+    Atmega328p::write_pin(Port::B, 5, PinValue::Low).unwrap();
+    assert_eq!(sim::peek(PORTB), 0);
+}
+
+#[test]
+fn atmega328p_read_pin_samples_pinb() {
+    sim::reset();
+    sim::poke(PINB, 1 << 3);
+    assert!(matches!(Atmega328p::read_pin(Port::B, 3).unwrap(), PinValue::High));
+    assert!(matches!(Atmega328p::read_pin(Port::B, 2).unwrap(), PinValue::Low));
+}
+
+#[test]
+fn cortex_m3_configure_pin_uses_two_moder_bits() {
+    sim::reset();
+    sim::poke(GPIOA_MODER, 0b11 << 4);
+    CortexM3::configure_pin(Port::A, 2, PinMode::Output).unwrap();
+    assert_eq!(sim::peek(GPIOA_MODER), 0b01 << 4);
+
+    CortexM3::configure_pin(Port::A, 2, PinMode::Input).unwrap();
+    assert_eq!(sim::peek(GPIOA_MODER), 0);
+}
+
+#[test]
+fn cortex_m3_write_and_read_pin() {
+    sim::reset();
+    CortexM3::write_pin(Port::A, 7, PinValue::High).unwrap();
+    assert_eq!(sim::peek(GPIOA_ODR), 1 << 7);
+
+    sim::poke(GPIOA_IDR, 1 << 7);
+    assert!(matches!(CortexM3::read_pin(Port::A, 7).unwrap(), PinValue::High));
+}
+
+#[test]
+fn atmega328p_configure_pin_is_a_read_modify_write_of_ddrb() {
+    sim::reset();
+    sim::poke(DDRB, 0b0000_0001);
+    Atmega328p::configure_pin(Port::B, 5, PinMode::Output).unwrap();
+    trace::assert_trace(&Expected::new().read8(DDRB, 0b0000_0001).write8(DDRB, 0b0010_0001));
+}
+
+#[test]
+fn cortex_m3_write_pin_sequence() {
+    sim::reset();
+    CortexM3::write_pin(Port::A, 7, PinValue::High).unwrap();
+    CortexM3::write_pin(Port::A, 7, PinValue::Low).unwrap();
+    trace::assert_trace(
+        &Expected::new()
+            .read32(GPIOA_ODR, 0)
+            .write32(GPIOA_ODR, 1 << 7)
+            .read32(GPIOA_ODR, 1 << 7)
+            .write32(GPIOA_ODR, 0),
+    );
+}
+
+#[test]
+#[should_panic(expected = "register trace mismatch at access #1")]
+fn trace_mismatch_is_reported() {
+    sim::reset();
+    Atmega328p::configure_pin(Port::B, 5, PinMode::Output).unwrap();
+    trace::assert_trace(&Expected::new().read8(DDRB, 0).write8(DDRB, 1 << 4));
+}
+
+#[test]
+fn polls_leave_the_last_read_to_a_read_of_the_same_register() {
+    sim::reset();
+    sim::poke(PINB, 1 << 3);
+    for _ in 0..3 {
+        Atmega328p::read_pin(Port::B, 3).unwrap();
+    }
+    trace::assert_trace(&Expected::new().polls(PINB).read8(PINB, 1 << 3));
+
+    Atmega328p::read_pin(Port::B, 3).unwrap();
+    trace::assert_trace(&Expected::new().polls(PINB).read8(PINB, 1 << 3));
+}
+
+#[test]
+fn pins_past_the_port_width_are_rejected() {
+    sim::reset();
+    assert_eq!(Atmega328p::configure_pin(Port::B, 8, PinMode::Output).err(), Some(HalError::InvalidPin));
+    assert_eq!(CortexM3::write_pin(Port::A, 16, PinValue::High).err(), Some(HalError::InvalidPin));
+    assert!(trace::take().accesses().is_empty());
+}
+
+#[test]
+fn atmega328p_ports_c_and_d_have_their_own_registers() {
+    sim::reset();
+    Atmega328p::configure_pin(Port::D, 7, PinMode::Output).unwrap();
+    Atmega328p::write_pin(Port::D, 7, PinValue::High).unwrap();
+    assert_eq!(sim::peek(DDRD), 1 << 7);
+    assert_eq!(sim::peek(PORTD), 1 << 7);
+    assert_eq!(sim::peek(DDRB), 0);
+
+    sim::poke(PINC, 1 << 5);
+    assert!(matches!(Atmega328p::read_pin(Port::C, 5).unwrap(), PinValue::High));
+}
+
+#[test]
+fn cortex_m3_gpioe_is_clocked_and_driven() {
+    sim::reset();
+    CortexM3::configure_pin(Port::E, 15, PinMode::Output).unwrap();
+    CortexM3::write_pin(Port::E, 15, PinValue::High).unwrap();
+    assert_eq!(sim::peek(RCC_AHBENR), 1 << 21);
+    assert_eq!(sim::peek(GPIOE_MODER), 0b01 << 30);
+    assert_eq!(sim::peek(GPIOE_ODR), 1 << 15);
+    assert_eq!(sim::peek(GPIOA_MODER), 0);
+}
+
+#[test]
+fn missing_ports_and_pins_are_rejected() {
+    sim::reset();
+    assert_eq!(Atmega328p::configure_pin(Port::A, 0, PinMode::Output).err(), Some(HalError::InvalidPin));
+    assert_eq!(Atmega328p::write_pin(Port::E, 0, PinValue::High).err(), Some(HalError::InvalidPin));
+    assert_eq!(Atmega328p::read_pin(Port::C, 7).err(), Some(HalError::InvalidPin)); // There is no PC7
+    assert_eq!(CortexM3::configure_pin(Port::E, 16, PinMode::Output).err(), Some(HalError::InvalidPin));
+    assert!(trace::take().accesses().is_empty());
+}
@@ -0,0 +1,222 @@
+#![cfg(feature = "host-sim")]
+
+use hal_project::clock::Clocks;
+use hal_project::i2c::atmega328p::Atmega328p;
+use hal_project::i2c::cortex_m3::CortexM3;
+use hal_project::i2c::I2C;
+use hal_project::sim;
+use hal_project::sim::trace::{self, Expected};
+use hal_project::sim::models::{AlwaysSet, SetOnWrite};
+use hal_project::HalError;
+
+mod common;
+use common::*;
+
+const CLOCKS: Clocks = Clocks::single(16_000_000);
+
+const TWBR: usize = 0xB8;
+
+const I2C_CR1: usize = 0x4000_5400;
+const I2C_CR2: usize = 0x4000_5404;
+const I2C_DR: usize = 0x4000_5410;
+const I2C_SR1: usize = 0x4000_5414;
+const I2C_SR2: usize = 0x4000_5418;
+const I2C_CCR: usize = 0x4000_541C;
+const I2C_TRISE: usize = 0x4000_5420;
+const SB: u32 = 1 << 0;
+const ADDR: u32 = 1 << 1;
+const RXNE: u32 = 1 << 6;
+const TXE: u32 = 1 << 7;
+const START: u32 = 1 << 8;
+const STOP: u32 = 1 << 9;
+const ACK: u32 = 1 << 10;
+const AF: u32 = 1 << 10;
+
+#[test]
+fn atmega328p_init_sets_bit_rate() {
+    sim::reset();
+    Atmega328p::i2c_init(100_000, &CLOCKS).unwrap();
+    assert_eq!(sim::peek(TWBR), 72);
+    assert_eq!(sim::peek(TWCR), TWEN);
+}
+
+#[test]
+fn atmega328p_init_rejects_unreachable_clock() {
+    sim::reset();
+    assert_eq!(Atmega328p::i2c_init(1_000_000, &CLOCKS), Err(HalError::InvalidClock));
+    assert_eq!(Atmega328p::i2c_init(0, &CLOCKS), Err(HalError::InvalidClock));
+}
+
+#[test]
+fn atmega328p_write_ends_with_stop() {
+    sim::reset();
+    sim::attach(TwiBus::new(0x42));
+    Atmega328p::i2c_write(0x42, &[0x01, 0x02]).unwrap();
+    assert_eq!(sim::peek(TWDR), 0x02);
+    assert_eq!(sim::peek(TWCR), TWINT | TWSTO | TWEN);
+}
+
+#[test]
+fn cortex_m3_write_and_read_complete() {
+    sim::reset();
+    sim::attach(SetOnWrite { trigger: I2C_CR1, target: I2C_SR1, mask: SB });
+    sim::attach(SetOnWrite { trigger: I2C_DR, target: I2C_SR1, mask: ADDR });
+    sim::attach(AlwaysSet { addr: I2C_SR1, mask: TXE | RXNE });
+
+    CortexM3::i2c_write(0x42, &[0x10]).unwrap();
+    assert_eq!(sim::peek(I2C_DR), 0x10);
+    assert_ne!(sim::peek(I2C_CR1) & STOP, 0);
+
+    sim::attach(ConstantSlave { data_register: I2C_DR, byte: 0x99 });
+    let mut buffer = [0u8; 2];
+    CortexM3::i2c_read(0x42, &mut buffer).unwrap();
+    assert_eq!(buffer, [0x99, 0x99]);
+}
+
+#[test]
+fn atmega328p_write_sequence() {
+    sim::reset();
+    sim::attach(TwiBus::new(0x42));
+    Atmega328p::i2c_write(0x42, &[0x01]).unwrap();
+    trace::assert_trace(
+        &Expected::new()
+            .write8(TWCR, (TWINT | TWSTA | TWEN) as u8)
+            .polls(TWCR)
+            .read8(TWSR, 0x08)
+            .write8(TWDR, 0x84)
+            .write8(TWCR, (TWINT | TWEN) as u8)
+            .polls(TWCR)
+            .read8(TWSR, 0x18)
+            .write8(TWDR, 0x01)
+            .write8(TWCR, (TWINT | TWEN) as u8)
+            .polls(TWCR)
+            .read8(TWSR, 0x28)
+            .write8(TWCR, (TWINT | TWSTO | TWEN) as u8),
+    );
+}
+
+#[test]
+fn atmega328p_missing_slave_is_nacked_and_bus_released() {
+    sim::reset();
+    sim::attach(TwiBus::new(0x42));
+    assert_eq!(Atmega328p::i2c_write(0x50, &[0x01]), Err(HalError::Nack));
+    assert_eq!(sim::peek(TWCR), TWINT | TWSTO | TWEN);
+
+    let mut buffer = [0u8; 2];
+    assert_eq!(Atmega328p::i2c_read(0x50, &mut buffer), Err(HalError::Nack));
+}
+
+#[test]
+fn atmega328p_read_acks_all_but_last_byte() {
+    sim::reset();
+    sim::attach(TwiBus::new(0x42));
+    sim::attach(ConstantSlave { data_register: TWDR, byte: 0x5A });
+    let mut buffer = [0u8; 3];
+    Atmega328p::i2c_read(0x42, &mut buffer).unwrap();
+    assert_eq!(buffer, [0x5A; 3]);
+
+    let commands: Vec<u32> = trace::take().at(TWCR).writes().accesses().iter().map(|a| a.value).collect();
+    assert_eq!(
+        commands,
+        [
+            TWINT | TWSTA | TWEN,
+            TWINT | TWEN,
+            TWINT | TWEN | TWEA,
+            TWINT | TWEN | TWEA,
+            TWINT | TWEN,
+            TWINT | TWSTO | TWEN,
+        ]
+    );
+}
+
+#[test]
+fn cortex_m3_read_enables_ack_before_start_and_nacks_last_byte() {
+    sim::reset();
+    sim::attach(SetOnWrite { trigger: I2C_CR1, target: I2C_SR1, mask: SB });
+    sim::attach(SetOnWrite { trigger: I2C_DR, target: I2C_SR1, mask: ADDR });
+    sim::attach(AlwaysSet { addr: I2C_SR1, mask: RXNE });
+    sim::attach(ConstantSlave { data_register: I2C_DR, byte: 0x99 });
+
+    let mut buffer = [0u8; 2];
+    CortexM3::i2c_read(0x42, &mut buffer).unwrap();
+    trace::assert_trace(
+        &Expected::new()
+            .read32(I2C_CR1, 0)
+            .write32(I2C_CR1, ACK)
+            .read32(I2C_CR1, ACK)
+            .write32(I2C_CR1, ACK | START)
+            .polls(I2C_SR1)
+            .write32(I2C_DR, 0x85)
+            .polls(I2C_SR1)
+            .read32(I2C_SR2, 0)
+            .polls(I2C_SR1)
+            .read32(I2C_DR, 0x99)
+            .read32(I2C_CR1, ACK | START)
+            .write32(I2C_CR1, START)
+            .polls(I2C_SR1)
+            .read32(I2C_DR, 0x99)
+            .read32(I2C_CR1, START)
+            .write32(I2C_CR1, START | STOP),
+    );
+}
+
+#[test]
+fn cortex_m3_single_byte_read_is_nacked() {
+    sim::reset();
+    sim::attach(SetOnWrite { trigger: I2C_CR1, target: I2C_SR1, mask: SB });
+    sim::attach(SetOnWrite { trigger: I2C_DR, target: I2C_SR1, mask: ADDR });
+    sim::attach(AlwaysSet { addr: I2C_SR1, mask: RXNE });
+    sim::poke(I2C_CR1, ACK);
+
+    let mut buffer = [0u8; 1];
+    CortexM3::i2c_read(0x42, &mut buffer).unwrap();
+    assert_eq!(trace::take().at(I2C_CR1).writes().accesses()[0].value, 0);
+}
+
+#[test]
+fn cortex_m3_acknowledge_failure_is_reported_as_nack() {
+    sim::reset();
+    sim::attach(SetOnWrite { trigger: I2C_CR1, target: I2C_SR1, mask: SB });
+    sim::attach(SetOnWrite { trigger: I2C_DR, target: I2C_SR1, mask: AF });
+
+    assert_eq!(CortexM3::i2c_write(0x42, &[0x10]), Err(HalError::Nack));
+    assert_eq!(sim::peek(I2C_SR1) & AF, 0);
+    assert_ne!(sim::peek(I2C_CR1) & STOP, 0);
+}
+
+#[test]
+fn atmega328p_slow_bus_uses_twi_prescaler() {
+    sim::reset();
+    Atmega328p::i2c_init(10_000, &CLOCKS).unwrap();
+    assert_eq!(sim::peek(TWSR) & 0b11, 0b01); // Prescaler 4
+    assert_eq!(sim::peek(TWBR), 198);
+
+    Atmega328p::i2c_init(100_000, &Clocks::single(8_000_000)).unwrap();
+    assert_eq!(sim::peek(TWSR) & 0b11, 0b00);
+    assert_eq!(sim::peek(TWBR), 32);
+}
+
+#[test]
+fn cortex_m3_init_takes_freq_from_apb1() {
+    let clocks = Clocks { sysclk: 72_000_000, hclk: 72_000_000, pclk1: 36_000_000, pclk2: 72_000_000 };
+
+    sim::reset();
+    CortexM3::i2c_init(100_000, &clocks).unwrap();
+    assert_eq!(sim::peek(I2C_CR2), 36);
+    assert_eq!(sim::peek(I2C_CCR), 180);
+    assert_eq!(sim::peek(I2C_TRISE), 37);
+    assert_eq!(sim::peek(I2C_CR1), 1); // PE
+
+    sim::reset();
+    CortexM3::i2c_init(400_000, &clocks).unwrap();
+    assert_eq!(sim::peek(I2C_CCR), (1 << 15) | 30);
+    assert_eq!(sim::peek(I2C_TRISE), 11);
+}
+
+#[test]
+fn cortex_m3_init_rejects_unusable_apb1() {
+    sim::reset();
+    assert_eq!(CortexM3::i2c_init(100_000, &Clocks::single(1_000_000)), Err(HalError::InvalidClock));
+    assert_eq!(CortexM3::i2c_init(100_000, &Clocks::single(72_000_000)), Err(HalError::InvalidClock));
+    assert_eq!(CortexM3::i2c_init(1_000_000, &Clocks::single(36_000_000)), Err(HalError::InvalidClock));
+}
@@ -0,0 +1,52 @@
+#![cfg(feature = "host-sim")]
+
+use core::panic::Location;
+
+use hal_project::panic::report;
+use hal_project::sim;
+use hal_project::sim::models::AlwaysSet;
+use hal_project::sim::trace;
+use hal_project::timeout::{set_timeout, Timeout};
+use hal_project::usart::atmega328p::Atmega328p;
+use hal_project::usart::cortex_m3::CortexM3;
+use hal_project::HalError;
+
+const UCSR0A: usize = 0xC0;
+const UDR0: usize = 0xC6;
+const UDRE0: u32 = 1 << 5;
+const TXC0: u32 = 1 << 6;
+
+const USART2_SR: usize = 0x4000_4400;
+const USART2_DR: usize = 0x4000_4404;
+const TXE: u32 = 1 << 7;
+
+fn sent(addr: usize) -> String {
+    trace::take().at(addr).writes().accesses().iter().map(|access| access.value as u8 as char).collect()
+}
+
+#[test]
+fn report_prints_location_and_message() {
+    sim::reset();
+    sim::attach(AlwaysSet { addr: UCSR0A, mask: UDRE0 | TXC0 });
+    let location = Location::caller();
+    report::<Atmega328p>(Some(location), &format_args!("Bit rate too low: {}", 42)).unwrap();
+    assert_eq!(
+        sent(UDR0),
+        format!("\r\npanicked at {}:{}:{}:\r\nBit rate too low: 42\r\n", file!(), location.line(), location.column())
+    );
+}
+
+#[test]
+fn report_without_location() {
+    sim::reset();
+    sim::attach(AlwaysSet { addr: USART2_SR, mask: TXE | (1 << 6) }); // TC
+    report::<CortexM3>(None, &"out of memory").unwrap();
+    assert_eq!(sent(USART2_DR), "\r\npanicked:\r\nout of memory\r\n");
+}
+
+#[test]
+fn dead_console_gives_up() {
+    sim::reset();
+    set_timeout(Timeout::Iterations(10));
+    assert_eq!(report::<CortexM3>(None, &"lost"), Err(HalError::Timeout));
+}
@@ -0,0 +1,75 @@
+#![cfg(feature = "host-sim")]
+
+use hal_project::gpio::atmega328p::{PortB, PortD};
+use hal_project::gpio::cortex_m3::GpioC;
+use hal_project::gpio::pin::{Pin, Unconfigured};
+use hal_project::gpio::Port;
+use hal_project::sim;
+use hal_project::sim::trace::{self, Expected};
+
+const PINB: usize = 0x23;
+const DDRB: usize = 0x24;
+const PORTB: usize = 0x25;
+const DDRD: usize = 0x2A;
+const PORTD: usize = 0x2B;
+
+const GPIOC_MODER: usize = 0x4800_0800;
+const GPIOC_PUPDR: usize = 0x4800_080C;
+const GPIOC_ODR: usize = 0x4800_0814;
+
+#[test]
+fn atmega328p_output_pin_is_driven_and_toggled() {
+    sim::reset();
+    let mut led = Pin::<PortB, 5, Unconfigured>::new().into_push_pull_output().unwrap();
+    assert_eq!((led.port(), led.number()), (Port::B, 5));
+    assert_eq!(sim::peek(DDRB), 1 << 5);
+
+    led.set_high().unwrap();
+    assert!(led.is_set_high().unwrap());
+    led.toggle().unwrap();
+    assert_eq!(sim::peek(PORTB), 0);
+    assert!(led.is_set_low().unwrap());
+}
+
+#[test]
+fn atmega328p_toggle_reads_the_output_latch() {
+    sim::reset();
+    let mut pin = Pin::<PortB, 1, Unconfigured>::new().into_push_pull_output().unwrap();
+    sim::poke(PINB, 1 << 1); // The pin is pulled high externally, the latch is still low
+    trace::clear();
+    pin.toggle().unwrap();
+    trace::assert_trace(
+        &Expected::new()
+            .read8(PORTB, 0)
+            .read8(PORTB, 0)
+            .write8(PORTB, 1 << 1),
+    );
+}
+
+#[test]
+fn atmega328p_pull_up_input_sets_portx() {
+    sim::reset();
+    sim::poke(DDRD, 1 << 2);
+    let button = Pin::<PortD, 2, Unconfigured>::new().into_pull_up_input().unwrap();
+    assert_eq!(sim::peek(DDRD), 0);
+    assert_eq!(sim::peek(PORTD), 1 << 2);
+
+    // Switching back to a floating input disables the pull-up
+    let button = button.into_floating_input().unwrap();
+    assert_eq!(sim::peek(PORTD), 0);
+    assert!(button.is_low().unwrap());
+}
+
+#[test]
+fn cortex_m3_pull_up_input_and_output() {
+    sim::reset();
+    sim::poke(GPIOC_MODER, 0b01 << 26);
+    let input = Pin::<GpioC, 13, Unconfigured>::new().into_pull_up_input().unwrap();
+    assert_eq!(sim::peek(GPIOC_PUPDR), 0b01 << 26);
+    assert_eq!(sim::peek(GPIOC_MODER), 0);
+
+    let mut output = input.into_push_pull_output().unwrap();
+    assert_eq!(sim::peek(GPIOC_MODER), 0b01 << 26);
+    output.set_high().unwrap();
+    assert_eq!(sim::peek(GPIOC_ODR), 1 << 13);
+}
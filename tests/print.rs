@@ -0,0 +1,85 @@
+#![cfg(feature = "host-sim")]
+
+use core::fmt::Write;
+
+use hal_project::sim;
+use hal_project::sim::models::AlwaysSet;
+use hal_project::sim::trace;
+use hal_project::timeout::{set_timeout, Timeout};
+use hal_project::usart::atmega328p::Atmega328p;
+use hal_project::usart::cortex_m3::{CortexM3, Usart, USART1};
+use hal_project::usart::print::{hex_dump, HexDump};
+use hal_project::usart::serial::Serial;
+use hal_project::{usart_print, usart_println, HalError};
+
+const UCSR0A: usize = 0xC0;
+const UDR0: usize = 0xC6;
+const UDRE0: u32 = 1 << 5;
+
+const USART1_SR: usize = 0x4001_3800;
+const USART1_DR: usize = 0x4001_3804;
+const USART2_SR: usize = 0x4000_4400;
+const USART2_DR: usize = 0x4000_4404;
+const TXE: u32 = 1 << 7;
+
+// Bytes written to the data register at `addr` since the trace was last taken
+fn sent(addr: usize) -> String {
+    written(&trace::take(), addr)
+}
+
+fn written(trace: &trace::Trace, addr: usize) -> String {
+    trace.at(addr).writes().accesses().iter().map(|access| access.value as u8 as char).collect()
+}
+
+#[test]
+fn serial_implements_fmt_write() {
+    sim::reset();
+    sim::attach(AlwaysSet { addr: UCSR0A, mask: UDRE0 });
+    let mut serial = Serial::<Atmega328p>::new();
+    write!(serial, "x = {}, y = {:#06x}", -12, 0xBEEF_u16).unwrap();
+    assert_eq!(sent(UDR0), "x = -12, y = 0xbeef");
+}
+
+#[test]
+fn macros_print_on_the_named_usart() {
+    sim::reset();
+    sim::attach(AlwaysSet { addr: USART1_SR, mask: TXE });
+    sim::attach(AlwaysSet { addr: USART2_SR, mask: TXE });
+
+    usart_print!(Usart<USART1> => "v{}.{}", 1, 4).unwrap();
+    usart_println!(Usart<USART1> => " ready").unwrap();
+    usart_println!(Usart<USART1> =>).unwrap();
+    usart_println!(CortexM3 => "{:>5}|", "ab").unwrap();
+    let trace = trace::take();
+    assert_eq!(written(&trace, USART1_DR), "v1.4 ready\r\n\r\n");
+    assert_eq!(written(&trace, USART2_DR), "   ab|\r\n");
+}
+
+#[test]
+fn print_reports_the_usart_error() {
+    sim::reset();
+    set_timeout(Timeout::Iterations(3));
+    assert_eq!(usart_print!(CortexM3 => "lost"), Err(HalError::Timeout));
+    assert_eq!(trace::take().at(USART2_DR).writes().accesses().len(), 0);
+
+    let mut serial = Serial::<CortexM3>::new();
+    assert!(write!(serial, "lost").is_err());
+}
+
+#[test]
+fn hex_dump_shows_offset_bytes_and_ascii() {
+    let data: Vec<u8> = (0x3E..0x52).collect();
+    let dump = format!("{}", HexDump::new(&data).at(0x2000_0000));
+    assert_eq!(
+        dump,
+        "20000000: 3e 3f 40 41 42 43 44 45 46 47 48 49 4a 4b 4c 4d  |>?@ABCDEFGHIJKLM|\r\n\
+         20000010: 4e 4f 50 51                                      |NOPQ|\r\n"
+    );
+    assert_eq!(format!("{}", HexDump::new(b"a\0\n ")), format!("00000000: 61 00 0a 20{}  |a.. |\r\n", " ".repeat(36)));
+    assert_eq!(format!("{}", HexDump::new(&[])), "");
+
+    sim::reset();
+    sim::attach(AlwaysSet { addr: UCSR0A, mask: UDRE0 });
+    hex_dump::<Atmega328p>(b"OK").unwrap();
+    assert_eq!(sent(UDR0), format!("00000000: 4f 4b{}  |OK|\r\n", " ".repeat(42)));
+}
@@ -0,0 +1,78 @@
+#![cfg(feature = "host-sim")]
+
+use hal_project::clock::Clocks;
+use hal_project::gpio::{self, PinMode, Port, GPIO};
+use hal_project::i2c::{self, I2C};
+use hal_project::rcc::{self, Peripheral};
+use hal_project::sim;
+use hal_project::sim::models::{AlwaysSet, ClockGate};
+use hal_project::sim::trace::{self, Expected};
+use hal_project::spi::{self, SpiConfig, SPI};
+use hal_project::usart::{self, UsartConfig, USART};
+
+const RCC_APB2RSTR: usize = 0x4002_100C;
+const RCC_AHBENR: usize = 0x4002_1014;
+const RCC_APB2ENR: usize = 0x4002_1018;
+const RCC_APB1ENR: usize = 0x4002_101C;
+
+const CLOCKS: Clocks = Clocks::single(16_000_000);
+
+#[test]
+fn enable_and_disable_touch_only_their_bit() {
+    sim::reset();
+    sim::poke(RCC_APB1ENR, 1 << 21);
+    rcc::enable(Peripheral::Usart2);
+    assert_eq!(sim::peek(RCC_APB1ENR), (1 << 21) | (1 << 17));
+    assert!(rcc::is_enabled(Peripheral::Usart2));
+
+    rcc::disable(Peripheral::I2c1);
+    assert_eq!(sim::peek(RCC_APB1ENR), 1 << 17);
+    assert!(!rcc::is_enabled(Peripheral::I2c1));
+
+    rcc::enable(Peripheral::GpioC);
+    rcc::enable(Peripheral::Usart1);
+    assert_eq!(sim::peek(RCC_AHBENR), 1 << 19);
+    assert_eq!(sim::peek(RCC_APB2ENR), 1 << 14);
+}
+
+#[test]
+fn reset_pulses_the_reset_bit() {
+    sim::reset();
+    rcc::reset(Peripheral::Spi1);
+    trace::assert_trace(
+        &Expected::new()
+            .read32(RCC_APB2RSTR, 0)
+            .write32(RCC_APB2RSTR, 1 << 12)
+            .read32(RCC_APB2RSTR, 1 << 12)
+            .write32(RCC_APB2RSTR, 0),
+    );
+}
+
+#[test]
+fn cortex_m3_drivers_enable_their_clock_first() {
+    sim::reset();
+    sim::attach(ClockGate { enable: RCC_AHBENR, mask: 1 << 17, block: 0x4800_0000..0x4800_0400 });
+    sim::attach(ClockGate { enable: RCC_APB1ENR, mask: 1 << 17, block: 0x4000_4400..0x4000_4800 });
+    sim::attach(ClockGate { enable: RCC_APB2ENR, mask: 1 << 12, block: 0x4001_3000..0x4001_3400 });
+    sim::attach(ClockGate { enable: RCC_APB1ENR, mask: 1 << 21, block: 0x4000_5400..0x4000_5800 });
+
+    gpio::cortex_m3::CortexM3::configure_pin(Port::A, 3, PinMode::Output).unwrap();
+    usart::cortex_m3::CortexM3::usart_init(UsartConfig::new(9600), &CLOCKS).unwrap();
+    spi::cortex_m3::CortexM3::spi_init_master(SpiConfig::default(), &CLOCKS).unwrap();
+    spi::cortex_m3::CortexM3::spi_init_slave(SpiConfig::default()).unwrap();
+    i2c::cortex_m3::CortexM3::i2c_init(100_000, &CLOCKS).unwrap();
+
+    assert!(rcc::is_enabled(Peripheral::GpioA));
+    assert!(rcc::is_enabled(Peripheral::Usart2));
+    assert!(rcc::is_enabled(Peripheral::Spi1));
+    assert!(rcc::is_enabled(Peripheral::I2c1));
+}
+
+#[test]
+#[should_panic(expected = "accessed while its clock is disabled")]
+fn clock_gate_catches_unclocked_accesses() {
+    sim::reset();
+    sim::attach(ClockGate { enable: RCC_APB1ENR, mask: 1 << 17, block: 0x4000_4400..0x4000_4800 });
+    sim::attach(AlwaysSet { addr: 0x4000_4400, mask: 1 << 7 });
+    let _ = usart::cortex_m3::CortexM3::usart_write(b'A'); // USART2 was never initialised
+}
@@ -0,0 +1,144 @@
+#![cfg(feature = "host-sim")]
+
+use hal_project::gpio;
+use hal_project::i2c;
+use hal_project::shell::{commands, parse_number, Args, Command, CommandError, CommandResult, Shell};
+use hal_project::sim;
+use hal_project::sim::models::{AlwaysSet, ClearOnRead, SetOnWrite};
+use hal_project::sim::trace;
+use hal_project::spi;
+use hal_project::usart::cortex_m3::CortexM3;
+use hal_project::HalError;
+
+mod common;
+use common::*;
+
+const USART2_SR: usize = 0x4000_4400;
+const USART2_DR: usize = 0x4000_4404;
+const TXE: u32 = 1 << 7;
+const RXNE: u32 = 1 << 5;
+
+const GPIOA_MODER: usize = 0x4800_0000;
+const GPIOA_IDR: usize = 0x4800_0010;
+const GPIOA_ODR: usize = 0x4800_0014;
+
+const SPSR: usize = 0x4D;
+const SPDR: usize = 0x4E;
+const SPIF: u32 = 1 << 7;
+
+fn add(args: &mut Args<'_>, out: &mut dyn core::fmt::Write) -> CommandResult {
+    let (a, b): (u32, u32) = (args.number()?, args.number()?);
+    args.end()?;
+    write!(out, "{}\r\n", a.checked_add(b).ok_or(HalError::Overrun)?)?;
+    Ok(())
+}
+
+static COMMANDS: [Command; 4] = [
+    commands::gpio::<gpio::cortex_m3::CortexM3>(),
+    commands::i2c_scan::<i2c::atmega328p::Atmega328p>(),
+    commands::spi::<spi::atmega328p::Atmega328p>(),
+    Command { name: "add", usage: "<a> <b>", help: "add two numbers", run: add },
+];
+
+// Console on USART2, the transmitter is always ready
+fn console() -> Shell<CortexM3, 16> {
+    sim::reset();
+    sim::attach(AlwaysSet { addr: USART2_SR, mask: TXE });
+    sim::attach(ClearOnRead { trigger: USART2_DR, target: USART2_SR, mask: RXNE });
+    Shell::new(&COMMANDS, "> ")
+}
+
+// Types `input` on the simulated USART and returns what the shell sent back
+fn type_in(shell: &mut Shell<CortexM3, 16>, input: &str) -> String {
+    trace::clear();
+    for byte in input.bytes() {
+        sim::poke(USART2_DR, byte as u32);
+        sim::poke(USART2_SR, sim::peek(USART2_SR) | RXNE);
+        shell.poll().unwrap();
+    }
+    trace::take().at(USART2_DR).writes().accesses().iter().map(|access| access.value as u8 as char).collect()
+}
+
+#[test]
+fn commands_run_with_their_arguments() {
+    let mut shell = console();
+    shell.start().unwrap();
+    assert_eq!(type_in(&mut shell, "add 2 0x10\r\n"), "add 2 0x10\r\n18\r\n> ");
+    assert_eq!(type_in(&mut shell, "\r"), "\r\n> ");
+    assert_eq!(type_in(&mut shell, "nope\r"), "nope\r\nunknown command: nope, try help\r\n> ");
+    assert_eq!(type_in(&mut shell, "add 1\r"), "add 1\r\nusage: add <a> <b>\r\n> ");
+    assert_eq!(type_in(&mut shell, "add 1 2 3\r"), "add 1 2 3\r\nusage: add <a> <b>\r\n> ");
+    assert_eq!(
+        type_in(&mut shell, "add 4294967295 1\r"),
+        "add 4294967295 1\r\nerror: overrun\r\n> "
+    );
+}
+
+#[test]
+fn line_editing() {
+    let mut shell = console();
+    assert_eq!(type_in(&mut shell, "\x08adx\x7fd 1 1\r"), "adx\x08 \x08d 1 1\r\n2\r\n> ");
+    assert_eq!(type_in(&mut shell, "add\x03"), "add^C\r\n> ");
+    assert_eq!(type_in(&mut shell, "\x1b[A\r"), "[A\r\nunknown command: [A, try help\r\n> ");
+
+    // 16 characters fit in the buffer, the 17th is refused with a bell
+    assert_eq!(type_in(&mut shell, "add 1 00000000002x"), "add 1 0000000000\x07\x07");
+    assert_eq!(type_in(&mut shell, "\r"), "\r\n1\r\n> ");
+}
+
+#[test]
+fn help_lists_the_table() {
+    let mut shell = console();
+    let help = type_in(&mut shell, "help\r");
+    assert!(help.starts_with("help\r\nhelp - list the commands\r\n"));
+    assert!(help.contains("gpio <pin> [high|low|input|pullup|output] - read, drive or configure a pin"));
+    assert!(help.contains("add <a> <b> - add two numbers\r\n"));
+    assert!(help.ends_with("\r\n> "));
+}
+
+#[test]
+fn gpio_command_reads_and_writes_pins() {
+    let mut shell = console();
+    type_in(&mut shell, "gpio a5 output\r");
+    assert_eq!(sim::peek(GPIOA_MODER), 0b01 << 10);
+    type_in(&mut shell, "gpio A5 high\r");
+    assert_eq!(sim::peek(GPIOA_ODR), 1 << 5);
+
+    sim::poke(GPIOA_IDR, 1 << 7);
+    assert_eq!(type_in(&mut shell, "gpio a7\r"), "gpio a7\r\na7: high\r\n> ");
+    assert_eq!(type_in(&mut shell, "gpio a16\r"), "gpio a16\r\nerror: invalid pin\r\n> ");
+    assert!(type_in(&mut shell, "gpio z1\r").contains("usage: gpio"));
+    assert!(type_in(&mut shell, "gpio a1 on\r").contains("usage: gpio"));
+}
+
+#[test]
+fn i2c_scan_lists_acknowledging_addresses() {
+    let mut shell = console();
+    sim::attach(TwiBus::new(0x42));
+    assert_eq!(type_in(&mut shell, "i2cscan\r"), "i2cscan\r\n0x42\r\n1 device(s)\r\n> ");
+}
+
+#[test]
+fn spi_command_prints_received_bytes() {
+    let mut shell = console();
+    sim::attach(SetOnWrite { trigger: SPDR, target: SPSR, mask: SPIF });
+    sim::attach(ConstantSlave { data_register: SPDR, byte: 0xC2 });
+    assert_eq!(type_in(&mut shell, "spi 0x9f 0\r"), "spi 0x9f 0\r\nc2 c2\r\n> ");
+
+    // Nothing is sent when an argument is not a byte
+    sim::poke(SPDR, 0);
+    assert!(type_in(&mut shell, "spi 1 256\r").contains("usage: spi <byte>..."));
+    assert_eq!(sim::peek(SPDR), 0);
+    assert!(type_in(&mut shell, "spi\r").contains("usage: spi"));
+}
+
+#[test]
+fn numbers_are_decimal_or_hexadecimal() {
+    assert_eq!(parse_number::<u8>("255"), Some(255));
+    assert_eq!(parse_number::<u8>("0xFF"), Some(255));
+    assert_eq!(parse_number::<u8>("0x100"), None);
+    assert_eq!(parse_number::<u16>("-1"), None);
+    assert_eq!(parse_number::<u32>("0x"), None);
+    assert_eq!(Args::new("  a   b ").word(), Ok("a"));
+    assert_eq!(Args::new("").word(), Err(CommandError::Usage));
+}
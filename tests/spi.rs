@@ -0,0 +1,416 @@
+#![cfg(feature = "host-sim")]
+
+use hal_project::clock::Clocks;
+use hal_project::sim::{self, Peripheral, RegisterFile};
+use hal_project::sim::trace::{self, Expected, Kind};
+use hal_project::sim::models::{AlwaysSet, SetOnWrite};
+use hal_project::spi::atmega328p::Atmega328p;
+use hal_project::spi::cortex_m3::CortexM3;
+use hal_project::spi::{BitOrder, SpiConfig, SpiMode, SPI};
+use hal_project::HalError;
+
+const CLOCKS: Clocks = Clocks::single(16_000_000);
+
+const SPCR: usize = 0x4C;
+const SPSR: usize = 0x4D;
+const SPDR: usize = 0x4E;
+const SPIF: u32 = 1 << 7;
+
+const RCC_APB2ENR: usize = 0x4002_1018;
+
+const SPI1_CR1: usize = 0x4001_3000;
+const SPI1_SR: usize = 0x4001_3008;
+const SPI1_DR: usize = 0x4001_300C;
+const RXNE: u32 = 1 << 0;
+const TXE: u32 = 1 << 1;
+const OVR: u32 = 1 << 6;
+const BSY: u32 = 1 << 7;
+const SPE: u32 = 1 << 6;
+const DFF: u32 = 1 << 11;
+
+// SPI1 with its transmit buffer and shift register: a frame written to DR starts shifting right away if the shift
+// register is free, otherwise it waits in DR (TXE cleared). Shifting a frame out takes two reads of SR, then the
+// slave's answer lands in the receive buffer (RXNE), setting OVR if the previous answer was not read yet. BSY is set
+// while a frame shifts, OVR is cleared by reading DR then SR.
+// Frames are kept 16 bits wide, the driver truncates them in 8-bit mode.
+struct Stm32Spi {
+    tx: Option<u16>,
+    shifting: Option<u16>,
+    rx: Option<u16>,
+    ticks: u8,
+    respond: fn(u16) -> u16,
+    dr_read: bool,
+    overrun_on: Option<usize>, // Answer that lands with OVR set, as if the driver had been too slow for it
+    answers: usize,
+}
+
+impl Stm32Spi {
+    fn new(respond: fn(u16) -> u16) -> Self {
+        Stm32Spi { tx: None, shifting: None, rx: None, ticks: 0, respond, dr_read: false, overrun_on: None, answers: 0 }
+    }
+}
+
+impl Peripheral for Stm32Spi {
+    fn before_read(&mut self, regs: &mut RegisterFile, addr: usize) {
+        if addr == SPI1_SR {
+            if std::mem::take(&mut self.dr_read) {
+                regs.clear_bits(SPI1_SR, OVR);
+            }
+            if let Some(byte) = self.shifting {
+                self.ticks += 1;
+                if self.ticks == 2 {
+                    if self.rx.is_some() || self.overrun_on == Some(self.answers) {
+                        regs.set_bits(SPI1_SR, OVR);
+                    }
+                    self.rx = Some((self.respond)(byte));
+                    self.answers += 1;
+                    self.shifting = self.tx.take();
+                    self.ticks = 0;
+                }
+            }
+            regs.clear_bits(SPI1_SR, TXE | RXNE | BSY);
+            if self.tx.is_none() {
+                regs.set_bits(SPI1_SR, TXE);
+            }
+            if self.rx.is_some() {
+                regs.set_bits(SPI1_SR, RXNE);
+            }
+            if self.shifting.is_some() {
+                regs.set_bits(SPI1_SR, BSY);
+            }
+        } else if addr == SPI1_DR {
+            regs.set(SPI1_DR, self.rx.take().unwrap_or(0) as u32);
+            regs.clear_bits(SPI1_SR, RXNE);
+            self.dr_read = true;
+        }
+    }
+
+    fn after_write(&mut self, _regs: &mut RegisterFile, addr: usize, value: u32) {
+        if addr == SPI1_DR {
+            if self.shifting.is_none() {
+                self.shifting = Some(value as u16);
+            } else {
+                assert!(self.tx.is_none(), "DR written while TXE was cleared");
+                self.tx = Some(value as u16);
+            }
+        }
+    }
+}
+
+#[test]
+fn atmega328p_init_master_configures_spcr() {
+    sim::reset();
+    Atmega328p::spi_init_master(SpiConfig::default(), &CLOCKS).unwrap();
+    assert_eq!(sim::peek(SPCR), (1 << 6) | (1 << 4) | (0b01 << 0)); // 16 MHz / 16
+    assert_eq!(sim::peek(SPSR), 0);
+}
+
+#[test]
+fn atmega328p_mode_bit_order_and_double_speed() {
+    sim::reset();
+    let config = SpiConfig { mode: SpiMode::Mode3, bit_order: BitOrder::LsbFirst, ..SpiConfig::new(8_000_000) };
+    Atmega328p::spi_init_master(config, &CLOCKS).unwrap();
+    assert_eq!(sim::peek(SPCR), (1 << 6) | (1 << 5) | (1 << 4) | (1 << 3) | (1 << 2)); // DORD, CPOL, CPHA, SPR = 0
+    assert_eq!(sim::peek(SPSR), 1); // SPI2X: 16 MHz / 2
+
+    Atmega328p::spi_init_master(SpiConfig::new(3_000_000), &CLOCKS).unwrap();
+    assert_eq!((sim::peek(SPCR) & 0b11, sim::peek(SPSR)), (0b01, 1)); // 16 MHz / 8 = 2 MHz
+
+    Atmega328p::spi_init_master(SpiConfig::new(5_000_000), &CLOCKS).unwrap();
+    assert_eq!((sim::peek(SPCR) & 0b11, sim::peek(SPSR)), (0b00, 0)); // 16 MHz / 4 = 4 MHz
+}
+
+#[test]
+fn atmega328p_init_slave_sets_the_frame_format() {
+    sim::reset();
+    let config = SpiConfig { mode: SpiMode::Mode1, ..SpiConfig::default() };
+    Atmega328p::spi_init_slave(config).unwrap();
+    assert_eq!(sim::peek(SPCR), (1 << 6) | (1 << 2)); // SPE, CPHA
+}
+
+#[test]
+fn master_refuses_a_frequency_below_the_slowest_sck() {
+    sim::reset();
+    // 16 MHz / 128 = 125 kHz
+    assert_eq!(Atmega328p::spi_init_master(SpiConfig::new(100_000), &CLOCKS), Err(HalError::InvalidClock));
+    // 16 MHz / 256 = 62.5 kHz
+    assert_eq!(CortexM3::spi_init_master(SpiConfig::new(50_000), &CLOCKS), Err(HalError::InvalidClock));
+    assert_eq!(sim::peek(SPCR), 0);
+    assert_eq!(sim::peek(SPI1_CR1), 0);
+}
+
+#[test]
+fn atmega328p_transfer_completes_on_spif() {
+    sim::reset();
+    sim::attach(SetOnWrite { trigger: SPDR, target: SPSR, mask: SPIF });
+    // The simulated data register is a loopback: MOSI is wired to MISO
+    assert_eq!(Atmega328p::spi_transfer(0xA5).unwrap(), 0xA5);
+}
+
+#[test]
+fn cortex_m3_init_master_enables_spi_last() {
+    sim::reset();
+    CortexM3::spi_init_master(SpiConfig::default(), &CLOCKS).unwrap();
+    // MSTR, BR = 16 MHz / 16, SPE, SSI and SSM
+    assert_eq!(sim::peek(SPI1_CR1), (1 << 2) | (0b011 << 3) | (1 << 6) | (1 << 8) | (1 << 9));
+}
+
+#[test]
+fn cortex_m3_init_master_sets_mode_and_bit_order() {
+    sim::reset();
+    let config = SpiConfig { mode: SpiMode::Mode2, bit_order: BitOrder::LsbFirst, ..SpiConfig::new(8_000_000) };
+    CortexM3::spi_init_master(config, &CLOCKS).unwrap();
+    // CPOL, MSTR, BR = 16 MHz / 2, SPE, LSBFIRST, SSI and SSM
+    assert_eq!(sim::peek(SPI1_CR1), (1 << 1) | (1 << 2) | (1 << 6) | (1 << 7) | (1 << 8) | (1 << 9));
+}
+
+#[test]
+fn cortex_m3_transfer_waits_for_txe_and_rxne() {
+    sim::reset();
+    sim::attach(AlwaysSet { addr: SPI1_SR, mask: TXE | RXNE });
+    assert_eq!(CortexM3::spi_transfer(0x3C).unwrap(), 0x3C);
+    assert_eq!(sim::peek(SPI1_DR), 0x3C);
+}
+
+#[test]
+fn atmega328p_transfer_sequence() {
+    sim::reset();
+    sim::attach(SetOnWrite { trigger: SPDR, target: SPSR, mask: SPIF });
+    Atmega328p::spi_transfer(0xA5).unwrap();
+    trace::assert_trace(&Expected::new().write8(SPDR, 0xA5).polls(SPSR).read8(SPDR, 0xA5));
+}
+
+#[test]
+fn cortex_m3_init_slave_sequence() {
+    sim::reset();
+    sim::poke(SPI1_CR1, (1 << 2) | (1 << 9));
+    let config = SpiConfig { mode: SpiMode::Mode3, bit_order: BitOrder::LsbFirst, ..SpiConfig::default() };
+    CortexM3::spi_init_slave(config).unwrap();
+    // MSTR and SSM cleared, the NSS pin selects the slave
+    let format = (1 << 0) | (1 << 1) | (1 << 7);
+    trace::assert_trace(
+        &Expected::new()
+            .read32(RCC_APB2ENR, 0)
+            .write32(RCC_APB2ENR, 1 << 12)
+            .write32(SPI1_CR1, format)
+            .read32(SPI1_CR1, format)
+            .write32(SPI1_CR1, format | (1 << 6)),
+    );
+}
+
+#[test]
+fn master_prescaler_keeps_sck_under_default() {
+    sim::reset();
+    Atmega328p::spi_init_master(SpiConfig::default(), &Clocks::single(4_000_000)).unwrap();
+    assert_eq!(sim::peek(SPCR) & 0b11, 0b00); // 4 MHz / 4
+
+    let clocks = Clocks { sysclk: 72_000_000, hclk: 72_000_000, pclk1: 36_000_000, pclk2: 72_000_000 };
+    CortexM3::spi_init_master(SpiConfig::default(), &clocks).unwrap();
+    assert_eq!((sim::peek(SPI1_CR1) >> 3) & 0b111, 0b110); // 72 MHz / 128
+}
+
+#[test]
+fn cortex_m3_streams_buffers_without_overrun() {
+    sim::reset();
+    sim::attach(Stm32Spi::new(|byte| !byte));
+    let mut received = [0u8; 5];
+    CortexM3::spi_transfer_bytes(&mut received, &[0x01, 0x02, 0x03, 0x04, 0x05]).unwrap();
+    assert_eq!(received, [0xFE, 0xFD, 0xFC, 0xFB, 0xFA]);
+    assert_eq!(sim::peek(SPI1_SR) & OVR, 0);
+
+    // The second byte is loaded into DR before the answer to the first one is read
+    let data = trace::take().at(SPI1_DR);
+    let kinds: Vec<Kind> = data.accesses().iter().map(|access| access.kind).collect();
+    assert_eq!(kinds[..3], [Kind::Write, Kind::Write, Kind::Read]);
+    assert_eq!(data.writes().accesses().len(), 5);
+}
+
+#[test]
+fn cortex_m3_stream_drains_the_frames_in_flight_after_an_overrun() {
+    sim::reset();
+    sim::attach(Stm32Spi { overrun_on: Some(1), ..Stm32Spi::new(|byte| !byte) });
+    let mut received = [0u8; 5];
+    assert_eq!(CortexM3::spi_transfer_bytes(&mut received, &[0x01, 0x02, 0x03, 0x04, 0x05]), Err(HalError::Overrun));
+    assert_eq!(received[0], 0xFE);
+    assert_eq!(sim::peek(SPI1_SR) & (RXNE | OVR | BSY), 0);
+
+    // The next operation gets its own answer, not one left over from the stream
+    assert_eq!(CortexM3::spi_transfer(0x10).unwrap(), 0xEF);
+}
+
+#[test]
+fn cortex_m3_buffer_operations() {
+    sim::reset();
+    sim::attach(Stm32Spi::new(|byte| byte.wrapping_add(1)));
+
+    let mut words = [0x10, 0x20, 0x30];
+    CortexM3::spi_transfer_in_place(&mut words).unwrap();
+    assert_eq!(words, [0x11, 0x21, 0x31]);
+
+    let mut buffer = [0u8; 2];
+    CortexM3::spi_read_bytes(&mut buffer).unwrap();
+    assert_eq!(buffer, [0x01, 0x01]); // 0x00 clocked out
+
+    trace::clear();
+    CortexM3::spi_write_bytes(&[0xAA, 0xBB]).unwrap();
+    let writes = trace::take().at(SPI1_DR).writes();
+    assert_eq!(writes.accesses().iter().map(|access| access.value).collect::<Vec<_>>(), [0xAA, 0xBB]);
+    assert_eq!(sim::peek(SPI1_SR) & (RXNE | OVR), 0); // Every answer was read
+}
+
+#[test]
+fn cortex_m3_transfer_pads_the_shorter_buffer() {
+    sim::reset();
+    sim::attach(Stm32Spi::new(|byte| byte ^ 0x80));
+
+    let mut read = [0u8; 3];
+    CortexM3::spi_transfer_bytes(&mut read, &[0x01]).unwrap();
+    assert_eq!(read, [0x81, 0x80, 0x80]); // 0x00 sent past the end of `write`
+
+    trace::clear();
+    let mut read = [0u8; 1];
+    CortexM3::spi_transfer_bytes(&mut read, &[0x01, 0x02, 0x03]).unwrap();
+    assert_eq!(read, [0x81]);
+    assert_eq!(trace::take().at(SPI1_DR).writes().accesses().len(), 3);
+}
+
+#[test]
+fn atmega328p_buffer_operations_go_byte_by_byte() {
+    sim::reset();
+    sim::attach(SetOnWrite { trigger: SPDR, target: SPSR, mask: SPIF });
+    let mut words = [0x12, 0x34];
+    Atmega328p::spi_transfer_in_place(&mut words).unwrap();
+    assert_eq!(words, [0x12, 0x34]); // Loopback
+    trace::assert_trace(
+        &Expected::new()
+            .write8(SPDR, 0x12)
+            .polls(SPSR)
+            .read8(SPDR, 0x12)
+            .write8(SPDR, 0x34)
+            .polls(SPSR)
+            .read8(SPDR, 0x34),
+    );
+}
+
+#[test]
+fn atmega328p_read_clocks_out_the_fill_byte() {
+    sim::reset();
+    sim::attach(SetOnWrite { trigger: SPDR, target: SPSR, mask: SPIF });
+    Atmega328p::spi_init_master(SpiConfig { fill_byte: 0xFF, ..SpiConfig::default() }, &CLOCKS).unwrap();
+    trace::clear();
+    assert_eq!(Atmega328p::spi_read().unwrap(), 0xFF); // Loopback
+    trace::assert_trace(&Expected::new().write8(SPDR, 0xFF).polls(SPSR).read8(SPDR, 0xFF));
+
+    // A slave loads the fill byte for the master to read back
+    Atmega328p::spi_init_slave(SpiConfig { fill_byte: 0xA5, ..SpiConfig::default() }).unwrap();
+    trace::clear();
+    Atmega328p::spi_read().unwrap();
+    trace::assert_trace(&Expected::new().write8(SPDR, 0xA5).polls(SPSR).read8(SPDR, 0xA5));
+}
+
+#[test]
+fn atmega328p_write_is_a_single_frame() {
+    sim::reset();
+    sim::attach(SetOnWrite { trigger: SPDR, target: SPSR, mask: SPIF });
+    Atmega328p::spi_write(0x42).unwrap();
+    trace::assert_trace(&Expected::new().write8(SPDR, 0x42).polls(SPSR).read8(SPDR, 0x42));
+}
+
+#[test]
+fn cortex_m3_read_clocks_out_the_fill_byte() {
+    sim::reset();
+    sim::attach(AlwaysSet { addr: SPI1_SR, mask: TXE | RXNE });
+    CortexM3::spi_init_master(SpiConfig { fill_byte: 0xFF, ..SpiConfig::default() }, &CLOCKS).unwrap();
+    trace::clear();
+    assert_eq!(CortexM3::spi_read().unwrap(), 0xFF); // Loopback
+    trace::assert_trace(
+        &Expected::new().polls(SPI1_SR).write32(SPI1_DR, 0xFF).polls(SPI1_SR).read32(SPI1_DR, 0xFF),
+    );
+}
+
+#[test]
+fn cortex_m3_read_after_write_returns_the_new_answer() {
+    sim::reset();
+    sim::attach(Stm32Spi::new(|byte| byte.wrapping_add(1)));
+    CortexM3::spi_init_master(SpiConfig { fill_byte: 0xFF, ..SpiConfig::default() }, &CLOCKS).unwrap();
+    CortexM3::spi_write(0x9F).unwrap();
+    assert_eq!(sim::peek(SPI1_SR) & RXNE, 0); // The answer to 0x9F was drained
+    assert_eq!(CortexM3::spi_read().unwrap(), 0x00); // Answer to the fill byte, not the stale 0xA0
+
+    let mut buffer = [0u8; 2];
+    CortexM3::spi_transfer_bytes(&mut buffer, &[0x10]).unwrap();
+    assert_eq!(buffer, [0x11, 0x00]); // Padded with the fill byte
+}
+
+#[test]
+fn cortex_m3_sixteen_bit_frames_set_dff_with_spi_disabled() {
+    sim::reset();
+    sim::attach(Stm32Spi::new(|word| !word));
+    CortexM3::spi_init_master(SpiConfig::default(), &CLOCKS).unwrap();
+    let cr1 = sim::peek(SPI1_CR1);
+    trace::clear();
+
+    assert_eq!(CortexM3::spi_transfer_u16(0x1234).unwrap(), 0xEDCB);
+    let writes: Vec<u32> = trace::take().at(SPI1_CR1).writes().accesses().iter().map(|a| a.value).collect();
+    assert_eq!(writes, [cr1 & !SPE, (cr1 & !SPE) | DFF, cr1 | DFF]);
+
+    // Still 16 bits: CR1 is left alone
+    assert_eq!(CortexM3::spi_transfer_u16(0x00FF).unwrap(), 0xFF00);
+    assert!(trace::take().at(SPI1_CR1).writes().accesses().is_empty());
+
+    // Back to 8 bits for byte operations
+    assert_eq!(CortexM3::spi_transfer(0x01).unwrap(), 0xFE);
+    assert_eq!(sim::peek(SPI1_CR1), cr1);
+}
+
+#[test]
+fn cortex_m3_streams_sixteen_bit_words() {
+    sim::reset();
+    sim::attach(Stm32Spi::new(|word| word.rotate_left(8)));
+    CortexM3::spi_init_master(SpiConfig { fill_byte: 0xFF, ..SpiConfig::default() }, &CLOCKS).unwrap();
+    trace::clear();
+
+    let mut read = [0u16; 3];
+    CortexM3::spi_transfer_words(&mut read, &[0x1234, 0xABCD]).unwrap();
+    assert_eq!(read, [0x3412, 0xCDAB, 0xFFFF]); // Padded with 0xFFFF
+    let data = trace::take().at(SPI1_DR);
+    let kinds: Vec<Kind> = data.accesses().iter().map(|access| access.kind).collect();
+    assert_eq!(kinds[..3], [Kind::Write, Kind::Write, Kind::Read]);
+
+    let mut words = [0x0102, 0x0304];
+    CortexM3::spi_transfer_words_in_place(&mut words).unwrap();
+    assert_eq!(words, [0x0201, 0x0403]);
+}
+
+// The free functions use the SPI of the chip feature
+#[cfg(feature = "cortex_m3")]
+#[test]
+fn free_functions_move_sixteen_bit_words_on_the_active_spi() {
+    sim::reset();
+    sim::attach(Stm32Spi::new(|word| !word));
+    hal_project::spi::spi_init_master(SpiConfig::default(), &CLOCKS).unwrap();
+    let mut words = [0x1234, 0x00FF];
+    hal_project::spi::spi_transfer_words_in_place(&mut words).unwrap();
+    assert_eq!(words, [0xEDCB, 0xFF00]);
+    assert_eq!(sim::peek(SPI1_CR1) & DFF, DFF);
+}
+
+#[test]
+fn atmega328p_sixteen_bit_frames_are_two_bytes_in_bit_order() {
+    sim::reset();
+    sim::attach(SetOnWrite { trigger: SPDR, target: SPSR, mask: SPIF });
+    Atmega328p::spi_init_master(SpiConfig::default(), &CLOCKS).unwrap();
+    trace::clear();
+    assert_eq!(Atmega328p::spi_transfer_u16(0x1234).unwrap(), 0x1234); // Loopback
+    let sent: Vec<u32> = trace::take().at(SPDR).writes().accesses().iter().map(|a| a.value).collect();
+    assert_eq!(sent, [0x12, 0x34]);
+
+    // LSB first on the wire: low byte first, each byte shifted LSB first
+    Atmega328p::spi_init_master(SpiConfig { bit_order: BitOrder::LsbFirst, ..SpiConfig::default() }, &CLOCKS).unwrap();
+    trace::clear();
+    let mut read = [0u16; 2];
+    Atmega328p::spi_transfer_words(&mut read, &[0xABCD]).unwrap();
+    assert_eq!(read, [0xABCD, 0x0000]);
+    let sent: Vec<u32> = trace::take().at(SPDR).writes().accesses().iter().map(|a| a.value).collect();
+    assert_eq!(sent, [0xCD, 0xAB, 0x00, 0x00]);
+}
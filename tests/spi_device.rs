@@ -0,0 +1,115 @@
+#![cfg(feature = "host-sim")]
+
+use hal_project::clock::Clocks;
+use hal_project::delay::Delay;
+use hal_project::gpio::atmega328p::PortB;
+use hal_project::gpio::pin::{Output, Pin, PushPull, Unconfigured};
+use hal_project::sim;
+use hal_project::sim::models::SetOnWrite;
+use hal_project::sim::trace;
+use hal_project::spi::atmega328p::Atmega328p;
+use hal_project::spi::device::{SharedBus, SpiDevice};
+use hal_project::spi::{SpiConfig, SpiMode, SPI};
+use hal_project::HalError;
+
+const CLOCKS: Clocks = Clocks::single(16_000_000);
+
+const PORTB: usize = 0x25;
+const SPCR: usize = 0x4C;
+const SPSR: usize = 0x4D;
+const SPDR: usize = 0x4E;
+const SPIF: u32 = 1 << 7;
+
+const FLASH: SpiConfig = SpiConfig { mode: SpiMode::Mode3, ..SpiConfig::new(8_000_000) };
+const DAC: SpiConfig = SpiConfig::new(1_000_000);
+
+fn chip_select<const N: u8>() -> Pin<PortB, N, Output<PushPull>> {
+    Pin::<PortB, N, Unconfigured>::new().into_push_pull_output().unwrap()
+}
+
+fn setup() -> SharedBus<Atmega328p> {
+    sim::reset();
+    sim::attach(SetOnWrite { trigger: SPDR, target: SPSR, mask: SPIF });
+    SharedBus::new(CLOCKS)
+}
+
+#[test]
+fn each_device_gets_its_own_config() {
+    let bus = setup();
+    let mut flash = SpiDevice::new(&bus, chip_select::<2>(), FLASH, Delay::new(&CLOCKS)).unwrap();
+    let mut dac = SpiDevice::new(&bus, chip_select::<1>(), DAC, Delay::new(&CLOCKS)).unwrap();
+
+    flash.with_selected(|_, _| Atmega328p::spi_write(0x9F)).unwrap();
+    assert_eq!(sim::peek(SPCR), (1 << 6) | (1 << 4) | (1 << 3) | (1 << 2)); // CPOL, CPHA, 16 MHz / 2
+    assert_eq!(sim::peek(SPSR) & 1, 1);
+
+    dac.with_selected(|_, _| Atmega328p::spi_write(0x10)).unwrap();
+    assert_eq!(sim::peek(SPCR), (1 << 6) | (1 << 4) | 0b01); // Mode 0, 16 MHz / 16
+    assert_eq!(sim::peek(SPSR) & 1, 0);
+
+    // Same device again: the peripheral is not initialised a second time
+    trace::clear();
+    dac.with_selected(|_, _| Atmega328p::spi_write(0x20)).unwrap();
+    assert!(trace::take().at(SPCR).writes().accesses().is_empty());
+}
+
+#[test]
+fn only_the_addressed_slave_is_selected() {
+    let bus = setup();
+    let mut flash = SpiDevice::new(&bus, chip_select::<2>(), FLASH, Delay::new(&CLOCKS)).unwrap();
+    let _dac = SpiDevice::new(&bus, chip_select::<1>(), DAC, Delay::new(&CLOCKS)).unwrap();
+    trace::clear();
+
+    flash
+        .with_selected(|_, _| {
+            assert_eq!(sim::peek(PORTB) & 0b110, 0b010); // PB2 low, PB1 still high
+            Atmega328p::spi_write(0x06)
+        })
+        .unwrap();
+    assert_eq!(sim::peek(PORTB) & 0b110, 0b110);
+    let levels: Vec<u32> = trace::take().at(PORTB).writes().accesses().iter().map(|a| a.value & 0b110).collect();
+    assert_eq!(levels, [0b010, 0b110]);
+}
+
+#[test]
+fn a_transaction_inside_another_one_is_refused() {
+    let bus = setup();
+    let mut flash = SpiDevice::new(&bus, chip_select::<2>(), FLASH, Delay::new(&CLOCKS)).unwrap();
+    let mut dac = SpiDevice::new(&bus, chip_select::<1>(), DAC, Delay::new(&CLOCKS)).unwrap();
+
+    // Stands for an interrupt handler preempting the flash transaction
+    let nested = flash.with_selected(|_, _| Ok(dac.with_selected(|_, _| Atmega328p::spi_write(0x10)))).unwrap();
+    assert_eq!(nested, Err(HalError::Busy));
+    assert_eq!(sim::peek(PORTB) & 0b110, 0b110);
+    assert!(dac.with_selected(|_, _| Ok(())).is_ok()); // Released with the flash transaction
+}
+
+#[test]
+fn a_config_change_applies_to_the_next_transaction() {
+    let bus = setup();
+    let mut card = SpiDevice::new(&bus, chip_select::<2>(), SpiConfig::new(50_000), Delay::new(&CLOCKS)).unwrap();
+
+    // 16 MHz / 128 is still too fast, the slave is never selected
+    assert_eq!(card.with_selected(|_, _| Atmega328p::spi_write(0xFF)), Err(HalError::InvalidClock));
+    assert_ne!(sim::peek(PORTB) & (1 << 2), 0);
+
+    card.set_config(SpiConfig { fill_byte: 0xFF, ..SpiConfig::new(200_000) });
+    assert_eq!(card.with_selected(|_, _| Atmega328p::spi_read()), Ok(0xFF)); // Loopback of the fill byte
+    assert_eq!(sim::peek(SPCR) & 0b11, 0b11); // 16 MHz / 128
+}
+
+// Declared like in the documentation of `spi::device`
+static BUS: SharedBus<Atmega328p> = SharedBus::new(Clocks::single(16_000_000));
+
+#[test]
+fn a_static_bus_is_shared_by_devices() {
+    sim::reset();
+    sim::attach(SetOnWrite { trigger: SPDR, target: SPSR, mask: SPIF });
+    let mut flash = SpiDevice::new(&BUS, chip_select::<2>(), FLASH, Delay::new(&CLOCKS)).unwrap();
+    let mut dac = SpiDevice::new(&BUS, chip_select::<1>(), DAC, Delay::new(&CLOCKS)).unwrap();
+
+    let nested = flash.with_selected(|_, _| Ok(dac.with_selected(|_, _| Ok(())))).unwrap();
+    assert_eq!(nested, Err(HalError::Busy));
+    dac.with_selected(|_, _| Atmega328p::spi_write(0x10)).unwrap();
+    assert_eq!(sim::peek(SPCR), (1 << 6) | (1 << 4) | 0b01); // DAC config, 16 MHz / 16
+}
@@ -0,0 +1,85 @@
+#![cfg(feature = "host-sim")]
+
+use std::cell::Cell;
+
+use hal_project::i2c::I2C;
+use hal_project::sim;
+use hal_project::sim::models::ClearOnWrite;
+use hal_project::sim::trace;
+use hal_project::spi::SPI;
+use hal_project::timeout::{self, Timeout};
+use hal_project::usart::USART;
+use hal_project::{i2c, spi, usart, HalError};
+
+const UCSR0A: usize = 0xC0;
+const SPSR: usize = 0x4D;
+const TWCR: usize = 0xBC;
+const TWINT: u32 = 1 << 7;
+const TWSTO: u32 = 1 << 4;
+const TWEN: u32 = 1 << 2;
+
+const USART2_SR: usize = 0x4000_4400;
+const SPI1_SR: usize = 0x4001_3008;
+const I2C_CR1: usize = 0x4000_5400;
+const STOP: u32 = 1 << 9;
+
+std::thread_local! {
+    static TICKS: Cell<u32> = const { Cell::new(0) };
+}
+
+// Time source advancing by one tick every time it is sampled
+fn fake_ticks() -> u32 {
+    TICKS.with(|ticks| {
+        ticks.set(ticks.get().wrapping_add(1));
+        ticks.get()
+    })
+}
+
+#[test]
+fn iteration_timeout_bounds_the_number_of_polls() {
+    sim::reset();
+    timeout::set_timeout(Timeout::Iterations(10));
+    assert_eq!(usart::atmega328p::Atmega328p::usart_write(0x31), Err(HalError::Timeout));
+    assert_eq!(trace::take().at(UCSR0A).accesses().len(), 10);
+}
+
+#[test]
+fn tick_timeout_uses_the_time_source() {
+    sim::reset();
+    TICKS.with(|ticks| ticks.set(u32::MAX - 2)); // The tick counter wraps around during the wait
+    timeout::set_time_source(fake_ticks);
+    timeout::set_timeout(Timeout::Ticks(5));
+    assert_eq!(usart::cortex_m3::CortexM3::usart_read(), Err(HalError::Timeout));
+    assert_eq!(trace::take().at(USART2_SR).accesses().len(), 5);
+}
+
+#[test]
+fn spi_transfers_time_out_on_both_targets() {
+    sim::reset();
+    timeout::set_timeout(Timeout::Iterations(3));
+    assert_eq!(spi::atmega328p::Atmega328p::spi_transfer(0x55), Err(HalError::Timeout));
+    assert_eq!(spi::cortex_m3::CortexM3::spi_write(0x55), Err(HalError::Timeout));
+    let trace = trace::take();
+    assert_eq!(trace.at(SPSR).accesses().len(), 3);
+    assert_eq!(trace.at(SPI1_SR).accesses().len(), 3);
+}
+
+#[test]
+fn atmega328p_i2c_timeout_releases_the_bus() {
+    sim::reset();
+    // Writing TWINT clears the flag, and no slave ever answers
+    sim::attach(ClearOnWrite { trigger: TWCR, target: TWCR, mask: TWINT });
+    timeout::set_timeout(Timeout::Iterations(3));
+    assert_eq!(i2c::atmega328p::Atmega328p::i2c_write(0x42, &[0x01]), Err(HalError::Timeout));
+    let commands = trace::take().at(TWCR).writes();
+    assert_eq!(commands.accesses().last().unwrap().value, TWINT | TWSTO | TWEN);
+}
+
+#[test]
+fn cortex_m3_i2c_timeout_releases_the_bus() {
+    sim::reset();
+    timeout::set_timeout(Timeout::Iterations(3));
+    let mut buffer = [0u8; 2];
+    assert_eq!(i2c::cortex_m3::CortexM3::i2c_read(0x42, &mut buffer), Err(HalError::Timeout));
+    assert_ne!(sim::peek(I2C_CR1) & STOP, 0);
+}
@@ -0,0 +1,309 @@
+#![cfg(feature = "host-sim")]
+
+use hal_project::clock::Clocks;
+use hal_project::sim;
+use hal_project::HalError;
+use hal_project::sim::trace::{self, Expected};
+use hal_project::sim::models::{AlwaysSet, ClearOnRead, ClockGate};
+use hal_project::usart::atmega328p::Atmega328p;
+use hal_project::usart::cortex_m3::{CortexM3, Usart, UART4, UART5, USART1, USART3};
+use hal_project::usart::{DataBits, Parity, StopBits, UsartConfig, UsartErrors, USART};
+
+const CLOCKS: Clocks = Clocks::single(16_000_000);
+
+const UCSR0A: usize = 0xC0;
+const UCSR0B: usize = 0xC1;
+const UCSR0C: usize = 0xC2;
+const UBRR0L: usize = 0xC4;
+const UBRR0H: usize = 0xC5;
+const UDR0: usize = 0xC6;
+const TXC0: u32 = 1 << 6;
+const UDRE0: u32 = 1 << 5;
+const RXC0: u32 = 1 << 7;
+const FE0: u32 = 1 << 4;
+const DOR0: u32 = 1 << 3;
+const UPE0: u32 = 1 << 2;
+const U2X0: u32 = 1 << 1;
+
+const USART2_SR: usize = 0x4000_4400;
+const USART2_DR: usize = 0x4000_4404;
+const USART2_BRR: usize = 0x4000_4408;
+const TXE: u32 = 1 << 7;
+const RXNE: u32 = 1 << 5;
+const ORE: u32 = 1 << 3;
+const NE: u32 = 1 << 2;
+const FE: u32 = 1 << 1;
+const PE: u32 = 1 << 0;
+
+#[test]
+fn atmega328p_init_programs_ubrr() {
+    sim::reset();
+    Atmega328p::usart_init(UsartConfig::new(9600), &CLOCKS).unwrap();
+    assert_eq!(sim::peek(UBRR0H), 0);
+    assert_eq!(sim::peek(UBRR0L), 103);
+}
+
+#[test]
+fn atmega328p_write_waits_for_udre_then_loads_udr() {
+    sim::reset();
+    sim::attach(AlwaysSet { addr: UCSR0A, mask: UDRE0 });
+    Atmega328p::usart_write(0x31).unwrap();
+    assert_eq!(sim::peek(UDR0), 0x31);
+}
+
+#[test]
+fn atmega328p_write_clears_txc0_without_writing_the_error_flags_back() {
+    sim::reset();
+    sim::attach(AlwaysSet { addr: UCSR0A, mask: UDRE0 });
+    sim::poke(UCSR0A, DOR0 | FE0 | U2X0);
+    trace::clear();
+    Atmega328p::usart_write(0x31).unwrap();
+    let status = (UDRE0 | DOR0 | FE0 | U2X0) as u8;
+    trace::assert_trace(
+        &Expected::new()
+            .read8(UCSR0A, status)
+            .read8(UCSR0A, status)
+            .write8(UCSR0A, (U2X0 | TXC0) as u8)
+            .write8(UDR0, 0x31),
+    );
+}
+
+#[test]
+fn atmega328p_read_returns_received_byte() {
+    sim::reset();
+    sim::attach(AlwaysSet { addr: UCSR0A, mask: RXC0 });
+    sim::poke(UDR0, 0x42);
+    assert_eq!(Atmega328p::usart_read().unwrap(), 0x42);
+}
+
+#[test]
+fn cortex_m3_write_and_read_use_data_register() {
+    sim::reset();
+    sim::attach(AlwaysSet { addr: USART2_SR, mask: TXE | RXNE });
+    CortexM3::usart_write(b'A').unwrap();
+    assert_eq!(sim::peek(USART2_DR), b'A' as u32);
+
+    sim::poke(USART2_DR, b'z' as u32);
+    assert_eq!(CortexM3::usart_read().unwrap(), b'z');
+}
+
+#[test]
+fn atmega328p_init_sequence() {
+    sim::reset();
+    Atmega328p::usart_init(UsartConfig::new(9600), &CLOCKS).unwrap();
+    trace::assert_trace(
+        &Expected::new()
+            .write8(UCSR0A, 0)
+            .write8(UBRR0H, 0)
+            .write8(UBRR0L, 103)
+            .write8(UCSR0B, (1 << 4) | (1 << 3))
+            .write8(UCSR0C, (1 << 2) | (1 << 1)),
+    );
+}
+
+#[test]
+fn cortex_m3_write_polls_txe_before_loading_dr() {
+    sim::reset();
+    sim::attach(AlwaysSet { addr: USART2_SR, mask: TXE });
+    CortexM3::usart_write(b'A').unwrap();
+    trace::assert_trace(&Expected::new().polls(USART2_SR).write32(USART2_DR, b'A' as u32));
+}
+
+#[test]
+fn unreachable_baud_rates_are_rejected() {
+    sim::reset();
+    assert_eq!(Atmega328p::usart_init(UsartConfig::new(0), &CLOCKS), Err(HalError::InvalidBaud));
+    assert_eq!(Atmega328p::usart_init(UsartConfig::new(200), &CLOCKS), Err(HalError::InvalidBaud));
+    assert_eq!(CortexM3::usart_init(UsartConfig::new(0), &CLOCKS), Err(HalError::InvalidBaud));
+    assert_eq!(CortexM3::usart_init(UsartConfig::new(2_000_000), &CLOCKS), Err(HalError::InvalidBaud));
+}
+
+#[test]
+fn baud_divider_follows_the_clock_tree() {
+    sim::reset();
+    Atmega328p::usart_init(UsartConfig::new(9600), &Clocks::single(8_000_000)).unwrap();
+    assert_eq!(sim::peek(UBRR0L), 51);
+
+    // USART2 sits on APB1, the core and APB2 frequencies must not matter
+    let clocks = Clocks { sysclk: 72_000_000, hclk: 72_000_000, pclk1: 36_000_000, pclk2: 72_000_000 };
+    CortexM3::usart_init(UsartConfig::new(115_200), &clocks).unwrap();
+    assert_eq!(sim::peek(USART2_BRR), 313); // 312.5 rounded up: mantissa 19, fraction 9
+}
+
+#[test]
+fn atmega328p_frame_formats() {
+    sim::reset();
+    let seven_even = UsartConfig { data_bits: DataBits::Seven, parity: Parity::Even, ..UsartConfig::new(9600) };
+    Atmega328p::usart_init(seven_even, &CLOCKS).unwrap();
+    assert_eq!(sim::peek(UCSR0C), (0b10 << 4) | (0b10 << 1)); // UPM0 even, UCSZ0 7 bits
+
+    let eight_none_two = UsartConfig { stop_bits: StopBits::Two, ..UsartConfig::new(9600) };
+    Atmega328p::usart_init(eight_none_two, &CLOCKS).unwrap();
+    assert_eq!(sim::peek(UCSR0C), (1 << 3) | (0b11 << 1)); // USBS0
+
+    // The 9th bit has no place in the bytes moved by `usart_read`/`usart_write`
+    let nine_odd = UsartConfig { data_bits: DataBits::Nine, parity: Parity::Odd, ..UsartConfig::new(9600) };
+    assert_eq!(Atmega328p::usart_init(nine_odd, &CLOCKS), Err(HalError::InvalidConfig));
+    assert_eq!(sim::peek(UCSR0B), (1 << 4) | (1 << 3)); // Left as the previous init set it
+
+    let one_and_half = UsartConfig { stop_bits: StopBits::OneAndHalf, ..UsartConfig::new(9600) };
+    assert_eq!(Atmega328p::usart_init(one_and_half, &CLOCKS), Err(HalError::InvalidConfig));
+}
+
+#[test]
+fn cortex_m3_frame_formats() {
+    const USART2_CR1: usize = 0x4000_440C;
+    const USART2_CR2: usize = 0x4000_4410;
+    const ENABLE: u32 = (1 << 13) | (1 << 3) | (1 << 2); // UE, TE, RE
+
+    sim::reset();
+    let seven_even = UsartConfig { data_bits: DataBits::Seven, parity: Parity::Even, ..UsartConfig::new(9600) };
+    CortexM3::usart_init(seven_even, &CLOCKS).unwrap();
+    assert_eq!(sim::peek(USART2_CR1), ENABLE | (1 << 10)); // PCE, 8-bit word with the parity bit
+
+    // The parity bit is masked out of the received data
+    sim::attach(AlwaysSet { addr: USART2_SR, mask: RXNE });
+    sim::poke(USART2_DR, 0x80 | b'a' as u32);
+    assert_eq!(CortexM3::usart_read().unwrap(), b'a');
+
+    let eight_odd_two = UsartConfig { parity: Parity::Odd, stop_bits: StopBits::Two, ..UsartConfig::new(9600) };
+    CortexM3::usart_init(eight_odd_two, &CLOCKS).unwrap();
+    assert_eq!(sim::peek(USART2_CR1), ENABLE | (1 << 12) | (1 << 10) | (1 << 9)); // M, PCE, PS
+    assert_eq!(sim::peek(USART2_CR2), 0b10 << 12);
+    sim::poke(USART2_DR, 0xFF);
+    assert_eq!(CortexM3::usart_read().unwrap(), 0xFF);
+
+    for data_bits in [DataBits::Five, DataBits::Six, DataBits::Seven] {
+        let config = UsartConfig { data_bits, ..UsartConfig::new(9600) };
+        assert_eq!(CortexM3::usart_init(config, &CLOCKS), Err(HalError::InvalidConfig));
+    }
+    let nine_even = UsartConfig { data_bits: DataBits::Nine, parity: Parity::Even, ..UsartConfig::new(9600) };
+    assert_eq!(CortexM3::usart_init(nine_even, &CLOCKS), Err(HalError::InvalidConfig));
+    let nine_none = UsartConfig { data_bits: DataBits::Nine, ..UsartConfig::new(9600) };
+    assert_eq!(CortexM3::usart_init(nine_none, &CLOCKS), Err(HalError::InvalidConfig));
+}
+
+#[test]
+fn cortex_m3_one_and_half_stop_bits_only_exist_on_the_usarts() {
+    const USART1_CR2: usize = 0x4001_3810;
+
+    sim::reset();
+    let one_and_half = UsartConfig { stop_bits: StopBits::OneAndHalf, ..UsartConfig::new(9600) };
+    Usart::<USART1>::usart_init(one_and_half, &CLOCKS).unwrap();
+    assert_eq!(sim::peek(USART1_CR2), 0b11 << 12);
+
+    trace::clear();
+    assert_eq!(Usart::<UART4>::usart_init(one_and_half, &CLOCKS), Err(HalError::InvalidConfig));
+    assert_eq!(Usart::<UART5>::usart_init(one_and_half, &CLOCKS), Err(HalError::InvalidConfig));
+    assert!(trace::take().accesses().is_empty());
+}
+
+#[test]
+fn atmega328p_receive_errors_are_read_before_udr0_and_counted() {
+    sim::reset();
+    sim::attach(AlwaysSet { addr: UCSR0A, mask: RXC0 });
+    sim::attach(ClearOnRead { trigger: UDR0, target: UCSR0A, mask: FE0 | DOR0 | UPE0 });
+    sim::poke(UCSR0A, FE0 | DOR0);
+    sim::poke(UDR0, 0x42);
+
+    assert_eq!(Atmega328p::usart_read(), Err(HalError::Framing));
+    trace::assert_trace(
+        &Expected::new()
+            .read8(UCSR0A, (RXC0 | FE0 | DOR0) as u8)
+            .read8(UDR0, 0x42),
+    );
+    assert_eq!(Atmega328p::usart_errors(), UsartErrors { framing: 1, overrun: 1, ..UsartErrors::new() });
+
+    sim::poke(UCSR0A, UPE0);
+    assert_eq!(Atmega328p::usart_read(), Err(HalError::Parity));
+    assert_eq!(Atmega328p::usart_read(), Ok(0x42));
+    assert_eq!(Atmega328p::usart_errors().total(), 3);
+
+    Atmega328p::usart_clear_errors();
+    assert_eq!(Atmega328p::usart_errors(), UsartErrors::new());
+}
+
+#[test]
+fn cortex_m3_receive_errors_are_cleared_by_sr_then_dr() {
+    sim::reset();
+    sim::attach(AlwaysSet { addr: USART2_SR, mask: RXNE });
+    sim::attach(ClearOnRead { trigger: USART2_DR, target: USART2_SR, mask: ORE | NE | FE | PE });
+    sim::poke(USART2_SR, NE | ORE);
+    sim::poke(USART2_DR, b'z' as u32);
+
+    assert_eq!(CortexM3::usart_read(), Err(HalError::Noise));
+    trace::assert_trace(&Expected::new().read32(USART2_SR, RXNE | NE | ORE).read32(USART2_DR, b'z' as u32));
+    assert_eq!(CortexM3::usart_errors(), UsartErrors { noise: 1, overrun: 1, ..UsartErrors::new() });
+
+    assert_eq!(CortexM3::usart_read(), Ok(b'z'));
+    sim::poke(USART2_SR, FE | PE);
+    assert_eq!(CortexM3::usart_read(), Err(HalError::Framing));
+    assert_eq!(CortexM3::usart_errors(), UsartErrors { framing: 1, parity: 1, noise: 1, overrun: 1 });
+}
+
+#[test]
+fn overrun_alone_is_counted_and_keeps_the_received_byte() {
+    sim::reset();
+    sim::attach(AlwaysSet { addr: UCSR0A, mask: RXC0 });
+    sim::attach(ClearOnRead { trigger: UDR0, target: UCSR0A, mask: FE0 | DOR0 | UPE0 });
+    sim::poke(UCSR0A, DOR0);
+    sim::poke(UDR0, 0x42);
+    assert_eq!(Atmega328p::usart_read(), Ok(0x42));
+    trace::assert_trace(&Expected::new().read8(UCSR0A, (RXC0 | DOR0) as u8).read8(UDR0, 0x42));
+    assert_eq!(Atmega328p::usart_errors(), UsartErrors { overrun: 1, ..UsartErrors::new() });
+
+    sim::attach(AlwaysSet { addr: USART2_SR, mask: RXNE });
+    sim::attach(ClearOnRead { trigger: USART2_DR, target: USART2_SR, mask: ORE | NE | FE | PE });
+    sim::poke(USART2_SR, ORE);
+    sim::poke(USART2_DR, b'z' as u32);
+    assert_eq!(CortexM3::usart_read(), Ok(b'z'));
+    assert_eq!(CortexM3::usart_errors(), UsartErrors { overrun: 1, ..UsartErrors::new() });
+}
+
+#[test]
+fn cortex_m3_instances_run_side_by_side() {
+    const USART1_SR: usize = 0x4001_3800;
+    const USART1_DR: usize = 0x4001_3804;
+    const USART1_BRR: usize = 0x4001_3808;
+    const USART3_SR: usize = 0x4000_4800;
+    const USART3_DR: usize = 0x4000_4804;
+    const USART3_BRR: usize = 0x4000_4808;
+    const GPIOA_MODER: usize = 0x4800_0000;
+    const GPIOA_AFRH: usize = 0x4800_0024;
+    const GPIOB_MODER: usize = 0x4800_0400;
+    const GPIOB_AFRH: usize = 0x4800_0424;
+
+    sim::reset();
+    sim::attach(ClockGate { enable: 0x4002_1018, mask: 1 << 14, block: 0x4001_3800..0x4001_3C00 }); // APB2ENR
+    sim::attach(ClockGate { enable: 0x4002_101C, mask: 1 << 18, block: 0x4000_4800..0x4000_4C00 }); // APB1ENR
+    sim::attach(AlwaysSet { addr: USART1_SR, mask: TXE });
+    sim::attach(AlwaysSet { addr: USART3_SR, mask: TXE });
+
+    // USART1 is clocked by APB2, USART3 by APB1
+    let clocks = Clocks { sysclk: 72_000_000, hclk: 72_000_000, pclk1: 36_000_000, pclk2: 72_000_000 };
+    Usart::<USART1>::usart_init(UsartConfig::new(115_200), &clocks).unwrap();
+    Usart::<USART3>::usart_init(UsartConfig::new(9600), &clocks).unwrap();
+    assert_eq!(sim::peek(USART1_BRR), 625);
+    assert_eq!(sim::peek(USART3_BRR), 3750);
+
+    // TX/RX on PA9/PA10 and PB10/PB11, alternate function 7
+    assert_eq!(sim::peek(GPIOA_MODER), (0b10 << 18) | (0b10 << 20));
+    assert_eq!(sim::peek(GPIOA_AFRH), (7 << 4) | (7 << 8));
+    assert_eq!(sim::peek(GPIOB_MODER), (0b10 << 20) | (0b10 << 22));
+    assert_eq!(sim::peek(GPIOB_AFRH), (7 << 8) | (7 << 12));
+
+    Usart::<USART1>::usart_write(b'1').unwrap();
+    Usart::<USART3>::usart_write(b'3').unwrap();
+    assert_eq!(sim::peek(USART1_DR), b'1' as u32);
+    assert_eq!(sim::peek(USART3_DR), b'3' as u32);
+
+    // Frame formats and error counters are kept per instance
+    sim::poke(USART1_SR, RXNE | FE);
+    sim::poke(USART3_SR, RXNE);
+    sim::poke(USART3_DR, b'x' as u32);
+    assert_eq!(Usart::<USART1>::usart_read(), Err(HalError::Framing));
+    assert_eq!(Usart::<USART3>::usart_read(), Ok(b'x'));
+    assert_eq!(Usart::<USART1>::usart_errors().framing, 1);
+    assert_eq!(Usart::<USART3>::usart_errors(), UsartErrors::new());
+    assert_eq!(CortexM3::usart_errors(), UsartErrors::new());
+}